@@ -23,8 +23,15 @@
 //
 // Based on public domain code by Johannes Lundberg
 
+use alloc::boxed::Box;
 use core::alloc::{GlobalAlloc, Layout};
+use core::marker::PhantomData;
+use core::mem::{align_of, size_of};
 
+/// The global allocator, which allocates with `M_WAITOK` and so may sleep
+/// until memory is available. It must not be used from interrupt threads,
+/// use `box_nowait` there instead. Interrupt filters must not allocate at
+/// all.
 pub struct KernelAllocator;
 
 unsafe impl GlobalAlloc for KernelAllocator {
@@ -44,6 +51,82 @@ unsafe impl GlobalAlloc for KernelAllocator {
     }
 }
 
+/// A context in which the current thread may sleep, e.g. to wait for
+/// memory. Interrupt contexts do not implement this, so operations that
+/// require it are rejected at compile time there.
+///
+/// # Safety
+/// Only implement this for contexts in which sleeping is allowed.
+pub unsafe trait Sleepable {}
+
+/// Proof that the current thread may sleep, for code such as module event
+/// handlers, system calls, device driver methods and character device
+/// entry points
+#[derive(Debug)]
+pub struct SleepableContext(PhantomData<*mut ()>);
+
+impl SleepableContext {
+    /// # Safety
+    /// The caller must not be running in an interrupt filter, interrupt
+    /// thread or with a spin lock or non-sleepable lock held.
+    pub unsafe fn new() -> Self {
+        SleepableContext(PhantomData)
+    }
+}
+
+unsafe impl Sleepable for SleepableContext {}
+
+/// Move `value` to the heap, sleeping until memory is available if needed
+/// (`M_WAITOK`)
+pub fn box_waitok<T, C: Sleepable>(_ctx: &C, value: T) -> Box<T> {
+    // `M_WAITOK` allocations do not fail
+    box_with_flags(value, kernel_sys::M_WAITOK).unwrap()
+}
+
+/// Move `value` to the heap without sleeping (`M_NOWAIT`), e.g. in an
+/// interrupt thread. Returns `None` if no memory is available right away.
+/// `malloc(9)` takes mutexes, so this must not be used in interrupt
+/// filters either.
+pub fn box_nowait<T>(value: T) -> Option<Box<T>> {
+    box_with_flags(value, kernel_sys::M_NOWAIT)
+}
+
+/// The alignment `malloc(9)` guarantees for any size
+const MALLOC_ALIGN: usize = size_of::<*mut u8>();
+
+struct Malloc<T>(PhantomData<T>);
+
+impl<T> Malloc<T> {
+    /// The size to allocate for a `T`, which cannot be more aligned than
+    /// `malloc(9)` memory
+    const SIZE: usize = {
+        assert!(
+            align_of::<T>() <= MALLOC_ALIGN,
+            "type is aligned beyond what malloc(9) provides"
+        );
+        size_of::<T>()
+    };
+}
+
+fn box_with_flags<T>(value: T, flags: libc::c_int) -> Option<Box<T>> {
+    if Malloc::<T>::SIZE == 0 {
+        return Some(Box::new(value));
+    }
+    unsafe {
+        let ptr = kernel_sys::malloc(
+            Malloc::<T>::SIZE,
+            &mut kernel_sys::M_DEVBUF[0],
+            flags,
+        ) as *mut T;
+        if ptr.is_null() {
+            return None;
+        }
+        ptr.write(value);
+        // Freed by `KernelAllocator::dealloc`
+        Some(Box::from_raw(ptr))
+    }
+}
+
 /// from `sys/malloc.h`
 /// ```c,ignore
 /// #define    M_NOWAIT    0x0001        /* do not block */
@@ -58,6 +141,8 @@ unsafe impl GlobalAlloc for KernelAllocator {
 /// #define    M_NEXTFIT    0x8000        /* only for vmem, follow cursor */
 /// ```
 
+// Host tests use the handler of std
+#[cfg(not(test))]
 #[alloc_error_handler]
 fn oom(_layout: Layout) -> ! {
     panic!("Out of memory!");
@@ -0,0 +1,409 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Newbus device drivers, see `DRIVER_MODULE(9)` and `DEVICE_PROBE(9)`
+//!
+//! A driver is a type implementing `Driver`. Its `attach` creates the
+//! per-device state, which newbus keeps in the softc of the device until
+//! `detach` succeeds. `driver_module!` builds the method table and
+//! `driver_t` and registers the driver with a parent bus.
+//!
+//! ```rust,ignore
+//! use bsd_kernel::bus::{Device, Driver, ProbePriority};
+//! use bsd_kernel::error::Errno;
+//!
+//! struct Foo;
+//!
+//! impl Driver for Foo {
+//!     fn probe(dev: Device) -> Result<ProbePriority, Errno> {
+//!         dev.set_desc("Foo device");
+//!         Ok(ProbePriority::Default)
+//!     }
+//!
+//!     fn attach(dev: Device) -> Result<Self, Errno> {
+//!         dev.print(format_args!("attached\n"));
+//!         Ok(Foo)
+//!     }
+//! }
+//!
+//! bsd_kernel::driver_module!(foo, nexus, Foo);
+//! ```
+
+use crate::console::{self, Target};
+use crate::error::Errno;
+use crate::linker_set::{Static, StaticMut};
+use crate::{c_str, cstr_ref};
+use alloc::boxed::Box;
+use core::fmt;
+use core::mem::{size_of, transmute};
+use core::ptr::{self, NonNull};
+use kernel_sys::{
+    devclass_t, device_t, driver_module_data, driver_t, kobj_method_t,
+    kobjop_desc,
+};
+use libc::{c_char, c_int};
+
+pub use self::dma::{
+    DmaBuffer, DmaData, DmaLoad, DmaMap, DmaTag, DmaTagParams, Segment, SyncOp,
+    MAXADDR, MAXADDR_32BIT, MAXSIZE,
+};
+pub use self::intr::{
+    FilterContext, FilterResult, Interrupt, InterruptType, ThreadContext,
+};
+pub use self::resource::{
+    Barrier, IoPort, Irq, Memory, Register, RegisterValue, Registers, Resource,
+    ResourceKind, RF_ACTIVE, RF_SHAREABLE,
+};
+
+mod dma;
+mod intr;
+pub mod pci;
+mod resource;
+
+/// Priorities a driver can claim a device with, from `sys/bus.h`. The
+/// driver returning the highest priority attaches to the device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(i32)]
+pub enum ProbePriority {
+    /// Only this driver can support the device
+    Specific = kernel_sys::BUS_PROBE_SPECIFIC,
+    /// A driver for this vendor's devices
+    Vendor = kernel_sys::BUS_PROBE_VENDOR,
+    /// The usual priority for a driver that supports the device
+    Default = kernel_sys::BUS_PROBE_DEFAULT,
+    /// An older driver, to be replaced by a newer one
+    LowPriority = kernel_sys::BUS_PROBE_LOW_PRIORITY,
+    /// A driver for a whole class of devices
+    Generic = kernel_sys::BUS_PROBE_GENERIC,
+    /// Claim any device that nothing else supports
+    Hoover = kernel_sys::BUS_PROBE_HOOVER,
+    /// Only attach to devices added for this driver by name
+    NoWildcard = kernel_sys::BUS_PROBE_NOWILDCARD,
+}
+
+/// A newbus device, i.e. `device_t`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Device(NonNull<kernel_sys::_device>);
+
+impl Device {
+    /// Wrap a raw `device_t`, returning `None` if it is null
+    ///
+    /// # Safety
+    /// `dev` must be a valid device that outlives the `Device`.
+    pub unsafe fn from_ptr(dev: device_t) -> Option<Device> {
+        NonNull::new(dev).map(Device)
+    }
+
+    pub fn as_ptr(self) -> device_t {
+        self.0.as_ptr()
+    }
+
+    /// The driver name of the device, e.g. `"em"`
+    pub fn name(self) -> Option<&'static str> {
+        unsafe { c_str(kernel_sys::device_get_name(self.as_ptr())) }
+    }
+
+    /// The name and unit number of the device, e.g. `"em0"`
+    pub fn nameunit(self) -> Option<&'static str> {
+        unsafe { c_str(kernel_sys::device_get_nameunit(self.as_ptr())) }
+    }
+
+    pub fn unit(self) -> c_int {
+        unsafe { kernel_sys::device_get_unit(self.as_ptr()) }
+    }
+
+    /// The bus device this device is attached to
+    pub fn parent(self) -> Option<Device> {
+        unsafe {
+            Device::from_ptr(kernel_sys::device_get_parent(self.as_ptr()))
+        }
+    }
+
+    /// Set the description shown when the device attaches, usually from
+    /// `Driver::probe`
+    pub fn set_desc(self, desc: &str) {
+        let desc = cstr_ref!(desc);
+        unsafe {
+            kernel_sys::device_set_desc_copy(
+                self.as_ptr(),
+                desc.as_ptr() as *const c_char,
+            )
+        };
+    }
+
+    /// Print to the console prefixed with the device name and unit, i.e.
+    /// `device_printf()`
+    ///
+    /// Like `console::print`, this formats into a stack buffer and does
+    /// not allocate.
+    pub fn print(self, args: fmt::Arguments) {
+        // What `device_printf()` does, with the message printed in pieces
+        // through a constant format string
+        unsafe { kernel_sys::device_print_prettyname(self.as_ptr()) };
+        console::print(Target::Console, args);
+    }
+
+    /// The raw softc of the device, i.e. `device_get_softc()`
+    pub fn softc_ptr(self) -> *mut libc::c_void {
+        unsafe { kernel_sys::device_get_softc(self.as_ptr()) }
+    }
+
+    /// The state of driver `D` for this device, if it is attached
+    ///
+    /// # Safety
+    /// The device must be attached to driver `D` (or be in its `attach` or
+    /// `detach`), and the returned reference must not be used at the same
+    /// time as another one to the same state.
+    pub unsafe fn softc<D: Driver>(self) -> Option<&'static mut D> {
+        (*softc::<D>(self)).as_deref_mut()
+    }
+}
+
+// `device_t` is a handle that newbus functions accept from any thread
+unsafe impl Send for Device {}
+unsafe impl Sync for Device {}
+
+/// A newbus device driver. The implementing type is the state of an
+/// attached device.
+///
+/// The methods are called with the newbus topology lock held, so they are
+/// never called concurrently for one device.
+pub trait Driver: Sized + Send + 'static {
+    /// Check whether the driver supports `dev`, returning `ENXIO` if not
+    fn probe(dev: Device) -> Result<ProbePriority, Errno>;
+    /// Set up `dev` after the driver won the probe
+    fn attach(dev: Device) -> Result<Self, Errno>;
+    /// Prepare to detach from `dev`. Returning an error (e.g. `EBUSY`)
+    /// keeps the driver attached, otherwise the state is dropped.
+    fn detach(&mut self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+    /// The system is suspending
+    fn suspend(&mut self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+    /// The system resumed from suspend
+    fn resume(&mut self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+    /// The system is shutting down
+    fn shutdown(&mut self, _dev: Device) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+// The softc holds a pointer to the driver state, which newbus allocates
+// zeroed, i.e. as `None`
+unsafe fn softc<D: Driver>(dev: Device) -> *mut Option<Box<D>> {
+    dev.softc_ptr() as *mut Option<Box<D>>
+}
+
+unsafe extern "C" fn device_probe<D: Driver>(dev: device_t) -> c_int {
+    match D::probe(Device(NonNull::new_unchecked(dev))) {
+        Ok(priority) => priority as c_int,
+        Err(e) => e.raw(),
+    }
+}
+
+unsafe extern "C" fn device_attach<D: Driver>(dev: device_t) -> c_int {
+    let dev = Device(NonNull::new_unchecked(dev));
+    let result = D::attach(dev).map(|state| {
+        *softc::<D>(dev) = Some(Box::new(state));
+    });
+    Errno::to_ret(result)
+}
+
+unsafe extern "C" fn device_detach<D: Driver>(dev: device_t) -> c_int {
+    let dev = Device(NonNull::new_unchecked(dev));
+    let softc = softc::<D>(dev);
+    let result = (*softc).as_mut().map_or(Ok(()), |d| d.detach(dev));
+    if result.is_ok() {
+        *softc = None;
+    }
+    Errno::to_ret(result)
+}
+
+macro_rules! device_methods {
+    ($($method:ident),*) => {$(
+        unsafe extern "C" fn $method<D: Driver>(dev: device_t) -> c_int {
+            let dev = Device(NonNull::new_unchecked(dev));
+            let result = (*softc::<D>(dev)).as_mut().map_or(Ok(()), |d| {
+                d.$method(dev)
+            });
+            Errno::to_ret(result)
+        }
+    )*};
+}
+
+device_methods!(suspend, resume, shutdown);
+
+// The method descriptors are generated from `device_if.m` by the kernel
+// build, so they are not in the bindings
+extern "C" {
+    static device_probe_desc: kobjop_desc;
+    static device_attach_desc: kobjop_desc;
+    static device_detach_desc: kobjop_desc;
+    static device_suspend_desc: kobjop_desc;
+    static device_resume_desc: kobjop_desc;
+    static device_shutdown_desc: kobjop_desc;
+}
+
+/// The descriptors of the device interface methods, in the order of
+/// `Methods`. Constant functions cannot refer to statics, so this is
+/// passed to `methods()` by the `static` initialiser instead.
+pub struct DeviceInterface([&'static kobjop_desc; 6]);
+
+unsafe impl Sync for DeviceInterface {}
+
+pub static DEVICE_INTERFACE: DeviceInterface = unsafe {
+    DeviceInterface([
+        &device_probe_desc,
+        &device_attach_desc,
+        &device_detach_desc,
+        &device_suspend_desc,
+        &device_resume_desc,
+        &device_shutdown_desc,
+    ])
+};
+
+type DeviceMethod = unsafe extern "C" fn(device_t) -> c_int;
+
+/// `DEVMETHOD()`
+const fn method(desc: &kobjop_desc, func: DeviceMethod) -> kobj_method_t {
+    kobj_method_t {
+        desc: desc as *const kobjop_desc as *mut kobjop_desc,
+        func: Some(unsafe {
+            transmute::<DeviceMethod, unsafe extern "C" fn()>(func)
+        }),
+    }
+}
+
+/// The method table of a `Driver`
+pub type Methods = [kobj_method_t; 7];
+
+/// Build the method table for `D` from `DEVICE_INTERFACE`, ending with
+/// `DEVMETHOD_END`
+pub const fn methods<D: Driver>(interface: &DeviceInterface) -> Methods {
+    let desc = &interface.0;
+    [
+        method(desc[0], device_probe::<D>),
+        method(desc[1], device_attach::<D>),
+        method(desc[2], device_detach::<D>),
+        method(desc[3], suspend::<D>),
+        method(desc[4], resume::<D>),
+        method(desc[5], shutdown::<D>),
+        kobj_method_t {
+            desc: ptr::null_mut(),
+            func: None,
+        },
+    ]
+}
+
+/// `DEFINE_CLASS_0()`: the `driver_t` for `D` named `name`, a
+/// null-terminated string
+pub const fn driver<D: Driver>(
+    name: &'static str,
+    methods: &'static Static<Methods>,
+) -> driver_t {
+    driver_t {
+        name: name.as_ptr() as *const c_char,
+        methods: methods as *const Static<Methods> as *mut kobj_method_t,
+        size: size_of::<Option<Box<D>>>(),
+        baseclasses: ptr::null_mut(),
+        refs: 0,
+        ops: ptr::null_mut(),
+    }
+}
+
+/// The `driver_module_data` registering `driver` with the bus named
+/// `busname`, a null-terminated string
+pub const fn driver_module_data(
+    busname: &'static str,
+    driver: &'static StaticMut<driver_t>,
+    devclass: &'static StaticMut<devclass_t>,
+) -> driver_module_data {
+    driver_module_data {
+        dmd_chainevh: None,
+        dmd_chainarg: ptr::null_mut(),
+        dmd_busname: busname.as_ptr() as *const c_char,
+        dmd_driver: driver.get(),
+        dmd_devclass: devclass.get(),
+        dmd_pass: c_int::MAX, // BUS_PASS_DEFAULT
+    }
+}
+
+/// Register `$driver`, a `Driver`, as `$name` with the parent bus `$bus`
+/// (e.g. `pci`, `acpi` or `nexus`), i.e. `DRIVER_MODULE()`
+///
+/// Like in C, the module is named `$name_$bus`, and drivers for buses that
+/// are modules themselves should also declare a `module_depend!` on them.
+#[macro_export]
+macro_rules! driver_module {
+    ($name:ident, $bus:ident, $driver:ty) => {
+        const _: () = {
+            static METHODS: $crate::linker_set::Static<$crate::bus::Methods> =
+                $crate::linker_set::Static($crate::bus::methods::<$driver>(
+                    &$crate::bus::DEVICE_INTERFACE,
+                ));
+            // The kernel writes to these while registering the driver
+            static DRIVER: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::driver_t,
+            > = $crate::linker_set::StaticMut::new($crate::bus::driver::<
+                $driver,
+            >(
+                $crate::cstr!(stringify!($name)),
+                &METHODS,
+            ));
+            static DEVCLASS: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::devclass_t,
+            > = $crate::linker_set::StaticMut::new(::core::ptr::null_mut());
+
+            static DRIVER_MODULE: $crate::linker_set::Static<
+                $crate::kernel_sys::driver_module_data,
+            > = $crate::linker_set::Static($crate::bus::driver_module_data(
+                $crate::cstr!(stringify!($bus)),
+                &DRIVER,
+                &DEVCLASS,
+            ));
+            static MODULE_DATA: $crate::linker_set::Static<
+                $crate::kernel_sys::moduledata_t,
+            > = $crate::linker_set::Static($crate::kernel_sys::moduledata_t {
+                name: $crate::cstr!(concat!(
+                    stringify!($bus),
+                    "/",
+                    stringify!($name)
+                ))
+                .as_ptr() as *const $crate::libc::c_char,
+                evhand: Some($crate::kernel_sys::driver_module_handler),
+                priv_: &DRIVER_MODULE as *const _ as *mut $crate::libc::c_void,
+            });
+
+            $crate::__declare_module!(
+                concat!(stringify!($name), "_", stringify!($bus)),
+                MODULE_DATA,
+                $crate::sysinit::Subsystem::Drivers,
+                $crate::sysinit::Order::Middle
+            );
+        };
+    };
+}
@@ -0,0 +1,488 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! DMA tags, maps and memory, see `bus_dma(9)`
+//!
+//! A `DmaTag` describes the addressing constraints of a device. Memory
+//! for descriptor rings and the like is allocated as a `DmaBuffer`, and
+//! other buffers, such as packet data, are loaded into a `DmaMap`. Both
+//! give the list of bus address segments to program into the device and
+//! are synced around device accesses with `sync`.
+//!
+//! ```rust,ignore
+//! let tag = DmaTag::new(
+//!     dev,
+//!     &DmaTagParams {
+//!         alignment: 4096,
+//!         lowaddr: MAXADDR_32BIT,
+//!         ..DmaTagParams::new(size_of::<Ring>())
+//!     },
+//! )?;
+//! let ring = DmaBuffer::<Ring>::new(ctx, &tag, true)?;
+//! regs.write(RING_BASE, ring.segments()[0].addr as u32);
+//! ```
+
+use super::Device;
+use crate::allocator::Sleepable;
+use crate::error::Errno;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ops::{BitOr, Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::{fmt, slice};
+use kernel_sys::{bus_dma_segment_t, bus_dma_tag_t, bus_dmamap_t};
+use libc::{c_int, c_void};
+
+/// No address limit, `BUS_SPACE_MAXADDR`
+pub const MAXADDR: u64 = u64::MAX;
+/// Limit for devices that can only address 32 bits,
+/// `BUS_SPACE_MAXADDR_32BIT`
+pub const MAXADDR_32BIT: u64 = 0xffff_ffff;
+/// No size limit, `BUS_SPACE_MAXSIZE`
+pub const MAXSIZE: u64 = u64::MAX;
+
+/// Constraints for the memory a `DmaTag` maps, i.e. the arguments of
+/// `bus_dma_tag_create()`
+#[derive(Copy, Clone, Debug)]
+pub struct DmaTagParams {
+    /// Alignment of segments, a power of two
+    pub alignment: u64,
+    /// Segments must not cross a multiple of this (0 for no boundary)
+    pub boundary: u64,
+    /// Addresses above `lowaddr` and up to `highaddr` cannot be used by
+    /// the device
+    pub lowaddr: u64,
+    pub highaddr: u64,
+    /// The largest mapping in bytes
+    pub maxsize: u64,
+    /// The most segments in a mapping
+    pub nsegments: usize,
+    /// The largest segment in bytes
+    pub maxsegsz: u64,
+}
+
+impl DmaTagParams {
+    /// Parameters for mappings up to `maxsize` bytes in one segment, at
+    /// any address
+    pub const fn new(maxsize: usize) -> Self {
+        DmaTagParams {
+            alignment: 1,
+            boundary: 0,
+            lowaddr: MAXADDR,
+            highaddr: MAXADDR,
+            maxsize: maxsize as u64,
+            nsegments: 1,
+            maxsegsz: maxsize as u64,
+        }
+    }
+}
+
+/// The DMA constraints of a device, destroyed when dropped
+pub struct DmaTag {
+    tag: bus_dma_tag_t,
+    params: DmaTagParams,
+}
+
+impl DmaTag {
+    /// Create a tag for `dev` inheriting the constraints of its parent bus
+    pub fn new(
+        dev: Device,
+        params: &DmaTagParams,
+    ) -> Result<Arc<DmaTag>, Errno> {
+        let mut tag = ptr::null_mut();
+        let ret = unsafe {
+            kernel_sys::bus_dma_tag_create(
+                kernel_sys::bus_get_dma_tag(dev.as_ptr()),
+                params.alignment,
+                params.boundary,
+                params.lowaddr,
+                params.highaddr,
+                None,
+                ptr::null_mut(),
+                params.maxsize,
+                params.nsegments as c_int,
+                params.maxsegsz,
+                0,
+                None,
+                ptr::null_mut(),
+                &mut tag,
+            )
+        };
+        Errno::result(ret)?;
+        Ok(Arc::new(DmaTag {
+            tag,
+            params: *params,
+        }))
+    }
+
+    pub fn params(&self) -> &DmaTagParams {
+        &self.params
+    }
+
+    pub fn as_ptr(&self) -> bus_dma_tag_t {
+        self.tag
+    }
+}
+
+impl fmt::Debug for DmaTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DmaTag")
+            .field("tag", &self.tag)
+            .field("params", &self.params)
+            .finish()
+    }
+}
+
+impl Drop for DmaTag {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::bus_dma_tag_destroy(self.tag) };
+    }
+}
+
+unsafe impl Send for DmaTag {}
+unsafe impl Sync for DmaTag {}
+
+/// A contiguous range of bus addresses of a mapping
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Segment {
+    pub addr: u64,
+    pub len: u64,
+}
+
+/// Which device accesses a `sync` prepares for or completes, i.e.
+/// `BUS_DMASYNC_*`. Operations can be combined with `|`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SyncOp(c_int);
+
+impl SyncOp {
+    /// Before the device writes to memory
+    pub const PREREAD: SyncOp = SyncOp(kernel_sys::BUS_DMASYNC_PREREAD);
+    /// After the device wrote to memory
+    pub const POSTREAD: SyncOp = SyncOp(kernel_sys::BUS_DMASYNC_POSTREAD);
+    /// Before the device reads memory written by the CPU
+    pub const PREWRITE: SyncOp = SyncOp(kernel_sys::BUS_DMASYNC_PREWRITE);
+    /// After the device read memory
+    pub const POSTWRITE: SyncOp = SyncOp(kernel_sys::BUS_DMASYNC_POSTWRITE);
+}
+
+impl BitOr for SyncOp {
+    type Output = SyncOp;
+
+    fn bitor(self, rhs: SyncOp) -> SyncOp {
+        SyncOp(self.0 | rhs.0)
+    }
+}
+
+// The map and memory functions are inline in `x86/include/bus_dma.h`,
+// calling through the implementation of the tag
+unsafe fn dma_impl(tag: bus_dma_tag_t) -> &'static kernel_sys::bus_dma_impl {
+    &*(*(tag as *mut kernel_sys::bus_dma_tag_common)).impl_
+}
+
+unsafe fn map_create(tag: bus_dma_tag_t) -> Result<bus_dmamap_t, Errno> {
+    let mut map = ptr::null_mut();
+    let ret = (dma_impl(tag).map_create.unwrap())(tag, 0, &mut map);
+    Errno::result(ret).map(|_| map)
+}
+
+unsafe fn map_destroy(tag: bus_dma_tag_t, map: bus_dmamap_t) {
+    (dma_impl(tag).map_destroy.unwrap())(tag, map);
+}
+
+unsafe fn map_unload(tag: bus_dma_tag_t, map: bus_dmamap_t) {
+    if !map.is_null() {
+        (dma_impl(tag).map_unload.unwrap())(tag, map);
+    }
+}
+
+unsafe fn map_sync(tag: bus_dma_tag_t, map: bus_dmamap_t, op: SyncOp) {
+    if !map.is_null() {
+        (dma_impl(tag).map_sync.unwrap())(tag, map, op.0);
+    }
+}
+
+/// What `load_callback` got from `bus_dmamap_load()`
+struct LoadResult<'a> {
+    segments: &'a mut Vec<Segment>,
+    error: c_int,
+}
+
+unsafe extern "C" fn load_callback(
+    arg: *mut c_void,
+    segs: *mut bus_dma_segment_t,
+    nseg: c_int,
+    error: c_int,
+) {
+    let result = &mut *(arg as *mut LoadResult);
+    result.error = error;
+    if error != 0 {
+        return;
+    }
+    // Has room for `nsegments` of the tag, so this does not allocate
+    for seg in slice::from_raw_parts(segs, nseg as usize) {
+        result.segments.push(Segment {
+            addr: seg.ds_addr,
+            len: seg.ds_len,
+        });
+    }
+}
+
+/// Load `len` bytes at `buf` into `map`, storing the segments in
+/// `segments`
+unsafe fn load(
+    tag: &DmaTag,
+    map: bus_dmamap_t,
+    buf: *mut c_void,
+    len: usize,
+    segments: &mut Vec<Segment>,
+) -> Result<(), Errno> {
+    segments.clear();
+    let mut result = LoadResult { segments, error: 0 };
+    let ret = kernel_sys::bus_dmamap_load(
+        tag.tag,
+        map,
+        buf,
+        len as u64,
+        Some(load_callback),
+        &mut result as *mut LoadResult as *mut c_void,
+        kernel_sys::BUS_DMA_NOWAIT,
+    );
+    Errno::result(ret)?;
+    // Errors such as `EFBIG` are only passed to the callback
+    Errno::result(result.error)
+}
+
+/// Types that can be shared with a device: plain data that is valid when
+/// all zero
+///
+/// # Safety
+/// Implementors must be valid for any bit pattern, including all zeros.
+pub unsafe trait DmaData: Copy {}
+
+unsafe impl DmaData for u8 {}
+unsafe impl DmaData for u16 {}
+unsafe impl DmaData for u32 {}
+unsafe impl DmaData for u64 {}
+unsafe impl DmaData for i8 {}
+unsafe impl DmaData for i16 {}
+unsafe impl DmaData for i32 {}
+unsafe impl DmaData for i64 {}
+unsafe impl<T: DmaData, const N: usize> DmaData for [T; N] {}
+
+/// DMA memory holding a `T`, allocated with `bus_dmamem_alloc()` and
+/// loaded for the device, freed when dropped
+///
+/// The memory can be accessed through `Deref`, with `sync` before handing
+/// it to the device and after the device accessed it.
+pub struct DmaBuffer<T: DmaData> {
+    tag: Arc<DmaTag>,
+    map: bus_dmamap_t,
+    vaddr: NonNull<T>,
+    segments: Vec<Segment>,
+}
+
+impl<T: DmaData> DmaBuffer<T> {
+    /// Allocate zeroed memory for a `T` meeting the constraints of `tag`,
+    /// which may sleep. Coherent memory, if supported by the platform,
+    /// is mapped uncached so that syncs are cheap.
+    pub fn new<C: Sleepable>(
+        _ctx: &C,
+        tag: &Arc<DmaTag>,
+        coherent: bool,
+    ) -> Result<DmaBuffer<T>, Errno> {
+        if size_of::<T>() as u64 > tag.params.maxsize {
+            return Err(Errno::EINVAL);
+        }
+        let mut flags = kernel_sys::BUS_DMA_WAITOK | kernel_sys::BUS_DMA_ZERO;
+        if coherent {
+            flags |= kernel_sys::BUS_DMA_COHERENT;
+        }
+        let mut vaddr = ptr::null_mut();
+        let mut map = ptr::null_mut();
+        unsafe {
+            let ret = (dma_impl(tag.tag).mem_alloc.unwrap())(
+                tag.tag, &mut vaddr, flags, &mut map,
+            );
+            Errno::result(ret)?;
+            let mut buffer = DmaBuffer {
+                tag: Arc::clone(tag),
+                map,
+                vaddr: NonNull::new_unchecked(vaddr as *mut T),
+                segments: Vec::with_capacity(tag.params.nsegments),
+            };
+            load(tag, map, vaddr, size_of::<T>(), &mut buffer.segments)?;
+            Ok(buffer)
+        }
+    }
+
+    /// The bus address segments of the memory
+    pub fn segments(&self) -> &[Segment] {
+        &self.segments
+    }
+
+    /// `bus_dmamap_sync()` the memory for `op`
+    pub fn sync(&self, op: SyncOp) {
+        unsafe { map_sync(self.tag.tag, self.map, op) };
+    }
+
+    pub fn as_ptr(&self) -> *mut T {
+        self.vaddr.as_ptr()
+    }
+}
+
+impl<T: DmaData> Deref for DmaBuffer<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { self.vaddr.as_ref() }
+    }
+}
+
+impl<T: DmaData> DerefMut for DmaBuffer<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { self.vaddr.as_mut() }
+    }
+}
+
+impl<T: DmaData> fmt::Debug for DmaBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DmaBuffer")
+            .field("vaddr", &self.vaddr)
+            .field("segments", &self.segments)
+            .finish()
+    }
+}
+
+impl<T: DmaData> Drop for DmaBuffer<T> {
+    fn drop(&mut self) {
+        let tag = self.tag.tag;
+        unsafe {
+            if !self.segments.is_empty() {
+                map_unload(tag, self.map);
+            }
+            (dma_impl(tag).mem_free.unwrap())(
+                tag,
+                self.vaddr.as_ptr() as *mut c_void,
+                self.map,
+            );
+        }
+    }
+}
+
+unsafe impl<T: DmaData + Send> Send for DmaBuffer<T> {}
+unsafe impl<T: DmaData + Sync> Sync for DmaBuffer<T> {}
+
+/// A DMA map for loading buffers that were not allocated as DMA memory,
+/// destroyed when dropped
+pub struct DmaMap {
+    tag: Arc<DmaTag>,
+    map: bus_dmamap_t,
+    segments: Vec<Segment>,
+}
+
+impl DmaMap {
+    pub fn new(tag: &Arc<DmaTag>) -> Result<DmaMap, Errno> {
+        let map = unsafe { map_create(tag.tag)? };
+        Ok(DmaMap {
+            tag: Arc::clone(tag),
+            map,
+            segments: Vec::with_capacity(tag.params.nsegments),
+        })
+    }
+
+    /// Map `buf` for the device, i.e. `bus_dmamap_load()`. This does not
+    /// sleep or allocate, so it can be used from interrupt handlers.
+    /// Returns `EFBIG` if `buf` does not fit in the segments of the tag.
+    ///
+    /// The buffer stays mapped until the returned `DmaLoad` is dropped.
+    pub fn load<'a>(
+        &'a mut self,
+        buf: &'a mut [u8],
+    ) -> Result<DmaLoad<'a>, Errno> {
+        unsafe {
+            load(
+                &self.tag,
+                self.map,
+                buf.as_mut_ptr() as *mut c_void,
+                buf.len(),
+                &mut self.segments,
+            )?
+        };
+        Ok(DmaLoad {
+            map: self,
+            _buf: PhantomData,
+        })
+    }
+}
+
+impl fmt::Debug for DmaMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DmaMap")
+            .field("tag", &self.tag)
+            .field("map", &self.map)
+            .finish()
+    }
+}
+
+impl Drop for DmaMap {
+    fn drop(&mut self) {
+        unsafe { map_destroy(self.tag.tag, self.map) };
+    }
+}
+
+unsafe impl Send for DmaMap {}
+unsafe impl Sync for DmaMap {}
+
+/// A buffer loaded into a `DmaMap`, unloaded when dropped
+pub struct DmaLoad<'a> {
+    map: &'a DmaMap,
+    _buf: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> DmaLoad<'a> {
+    /// The bus address segments of the buffer
+    pub fn segments(&self) -> &[Segment] {
+        &self.map.segments
+    }
+
+    /// `bus_dmamap_sync()` the buffer for `op`
+    pub fn sync(&self, op: SyncOp) {
+        unsafe { map_sync(self.map.tag.tag, self.map.map, op) };
+    }
+}
+
+impl<'a> fmt::Debug for DmaLoad<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DmaLoad")
+            .field("segments", &self.segments())
+            .finish()
+    }
+}
+
+impl<'a> Drop for DmaLoad<'a> {
+    fn drop(&mut self) {
+        unsafe { map_unload(self.map.tag.tag, self.map.map) };
+    }
+}
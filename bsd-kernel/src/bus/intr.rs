@@ -0,0 +1,236 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Interrupt handlers, see `bus_setup_intr(9)`
+//!
+//! An interrupt can have a filter, which runs in primary interrupt context
+//! and must not block at all, and an interrupt thread handler, which may
+//! block on mutexes but must not sleep. The filter decides whether the
+//! interrupt thread needs to run. Neither context implements `Sleepable`,
+//! so sleeping operations such as `allocator::box_waitok` do not compile
+//! in the handlers. The global allocator may sleep too, so `Box::new`,
+//! `Vec` and friends must not allocate in either. An interrupt thread can
+//! use `allocator::box_nowait`, but a filter must not allocate at all, as
+//! `malloc(9)` takes mutexes even with `M_NOWAIT`.
+//!
+//! ```rust,ignore
+//! let irq = Resource::<Irq>::alloc_any(dev, 0, RF_ACTIVE | RF_SHAREABLE)?;
+//! let regs = Arc::clone(&self.regs);
+//! let intr = Interrupt::split(
+//!     irq,
+//!     InterruptType::Net,
+//!     move |_ctx| {
+//!         if regs.read(STATUS) == 0 {
+//!             return FilterResult::Stray;
+//!         }
+//!         FilterResult::ScheduleThread
+//!     },
+//!     move |_ctx| { /* process the received packets */ },
+//! )?;
+//! ```
+
+use super::{Irq, Resource};
+use crate::error::Errno;
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::{fmt, ptr};
+use libc::{c_int, c_void};
+
+/// What an interrupt filter did with the interrupt
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(i32)]
+pub enum FilterResult {
+    /// The interrupt was handled completely, `FILTER_HANDLED`
+    Handled = kernel_sys::FILTER_HANDLED,
+    /// The interrupt thread handler needs to run,
+    /// `FILTER_SCHEDULE_THREAD`
+    ScheduleThread = kernel_sys::FILTER_SCHEDULE_THREAD,
+    /// The interrupt was not raised by this device, `FILTER_STRAY`
+    Stray = kernel_sys::FILTER_STRAY,
+}
+
+/// The kind of device handling an interrupt, which sets the priority of
+/// its interrupt thread
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(i32)]
+pub enum InterruptType {
+    Tty = kernel_sys::INTR_TYPE_TTY,
+    Bio = kernel_sys::INTR_TYPE_BIO,
+    Net = kernel_sys::INTR_TYPE_NET,
+    Cam = kernel_sys::INTR_TYPE_CAM,
+    Misc = kernel_sys::INTR_TYPE_MISC,
+    Clk = kernel_sys::INTR_TYPE_CLK,
+    Av = kernel_sys::INTR_TYPE_AV,
+}
+
+/// The context an interrupt filter runs in: interrupts are disabled, so
+/// only spin locks may be taken
+#[derive(Debug)]
+pub struct FilterContext(PhantomData<*mut ()>);
+
+/// The context an interrupt thread handler runs in: it may block on
+/// mutexes, but must not sleep
+#[derive(Debug)]
+pub struct ThreadContext(PhantomData<*mut ()>);
+
+type Filter = dyn Fn(&FilterContext) -> FilterResult + Send + Sync;
+type Handler = dyn Fn(&ThreadContext) + Send + Sync;
+
+struct Handlers {
+    filter: Option<Box<Filter>>,
+    handler: Option<Box<Handler>>,
+}
+
+unsafe extern "C" fn filter(arg: *mut c_void) -> c_int {
+    let handlers = &*(arg as *const Handlers);
+    match &handlers.filter {
+        Some(filter) => filter(&FilterContext(PhantomData)) as c_int,
+        None => FilterResult::Stray as c_int,
+    }
+}
+
+unsafe extern "C" fn handler(arg: *mut c_void) {
+    let handlers = &*(arg as *const Handlers);
+    if let Some(handler) = &handlers.handler {
+        handler(&ThreadContext(PhantomData));
+    }
+}
+
+/// Handlers set up for an interrupt resource, torn down when dropped
+pub struct Interrupt {
+    // Dropped after the handlers are torn down
+    irq: Resource<Irq>,
+    cookie: *mut c_void,
+    handlers: Box<Handlers>,
+}
+
+impl Interrupt {
+    /// Handle interrupts of `irq` in the filter `f` only
+    pub fn filter<F>(
+        irq: Resource<Irq>,
+        ty: InterruptType,
+        f: F,
+    ) -> Result<Interrupt, Errno>
+    where
+        F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+    {
+        let handlers = Handlers {
+            filter: Some(Box::new(f)),
+            handler: None,
+        };
+        Interrupt::setup(irq, ty, handlers)
+    }
+
+    /// Handle interrupts of `irq` in the interrupt thread handler `h` only
+    pub fn ithread<H>(
+        irq: Resource<Irq>,
+        ty: InterruptType,
+        h: H,
+    ) -> Result<Interrupt, Errno>
+    where
+        H: Fn(&ThreadContext) + Send + Sync + 'static,
+    {
+        let handlers = Handlers {
+            filter: None,
+            handler: Some(Box::new(h)),
+        };
+        Interrupt::setup(irq, ty, handlers)
+    }
+
+    /// Handle interrupts of `irq` in the filter `f`, which returns
+    /// `FilterResult::ScheduleThread` to run the interrupt thread handler
+    /// `h`
+    pub fn split<F, H>(
+        irq: Resource<Irq>,
+        ty: InterruptType,
+        f: F,
+        h: H,
+    ) -> Result<Interrupt, Errno>
+    where
+        F: Fn(&FilterContext) -> FilterResult + Send + Sync + 'static,
+        H: Fn(&ThreadContext) + Send + Sync + 'static,
+    {
+        let handlers = Handlers {
+            filter: Some(Box::new(f)),
+            handler: Some(Box::new(h)),
+        };
+        Interrupt::setup(irq, ty, handlers)
+    }
+
+    fn setup(
+        irq: Resource<Irq>,
+        ty: InterruptType,
+        handlers: Handlers,
+    ) -> Result<Interrupt, Errno> {
+        let handlers = Box::new(handlers);
+        let mut cookie = ptr::null_mut();
+        let ret = unsafe {
+            kernel_sys::bus_setup_intr(
+                irq.device().as_ptr(),
+                irq.as_ptr(),
+                ty as c_int | kernel_sys::INTR_MPSAFE,
+                handlers.filter.as_ref().map(|_| filter as _),
+                handlers.handler.as_ref().map(|_| handler as _),
+                &*handlers as *const Handlers as *mut c_void,
+                &mut cookie,
+            )
+        };
+        Errno::result(ret)?;
+        Ok(Interrupt {
+            irq,
+            cookie,
+            handlers,
+        })
+    }
+
+    /// The interrupt resource
+    pub fn irq(&self) -> &Resource<Irq> {
+        &self.irq
+    }
+}
+
+impl fmt::Debug for Interrupt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Interrupt")
+            .field("irq", &self.irq)
+            .field("filter", &self.handlers.filter.is_some())
+            .field("ithread", &self.handlers.handler.is_some())
+            .finish()
+    }
+}
+
+impl Drop for Interrupt {
+    fn drop(&mut self) {
+        // Waits for running handlers to finish
+        unsafe {
+            kernel_sys::bus_teardown_intr(
+                self.irq.device().as_ptr(),
+                self.irq.as_ptr(),
+                self.cookie,
+            )
+        };
+    }
+}
+
+unsafe impl Send for Interrupt {}
+unsafe impl Sync for Interrupt {}
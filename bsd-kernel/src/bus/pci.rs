@@ -0,0 +1,404 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! PCI devices, see `pci(9)`
+//!
+//! ```rust,ignore
+//! bsd_kernel::pci_device_table!(foo, FOO_IDS, [
+//!     (0x8086, 0x100e, "Intel PRO/1000 MT"),
+//!     (0x8086, 0x100f, "Intel PRO/1000 MT Dual Port"),
+//! ]);
+//!
+//! impl Driver for Foo {
+//!     fn probe(dev: Device) -> Result<ProbePriority, Errno> {
+//!         let pci = PciDevice::new(dev).ok_or(Errno::ENXIO)?;
+//!         let id = pci.lookup(&FOO_IDS).ok_or(Errno::ENXIO)?;
+//!         dev.set_desc(id.desc());
+//!         Ok(ProbePriority::Default)
+//!     }
+//!
+//!     fn attach(dev: Device) -> Result<Self, Errno> {
+//!         let pci = PciDevice::new(dev).ok_or(Errno::ENXIO)?;
+//!         pci.enable_busmaster()?;
+//!         let regs = pci.map_bar::<Memory>(0)?;
+//!         let mut vectors = pci.alloc_msix(1)?;
+//!         vectors.setup(0, |irq| {
+//!             Interrupt::ithread(irq, InterruptType::Net, |_ctx| {})
+//!         })?;
+//!         Ok(Foo { regs, vectors })
+//!     }
+//! }
+//! ```
+
+use super::{
+    Device, Interrupt, Irq, Register, RegisterValue, Registers, Resource,
+    RF_ACTIVE,
+};
+use crate::c_str;
+use crate::error::Errno;
+use alloc::vec::Vec;
+use core::fmt;
+use core::mem::{size_of, transmute};
+use core::ptr;
+use kernel_sys::{device_t, kobjop_desc};
+use libc::{c_char, c_int};
+
+/// Vendor ID
+pub const PCIR_VENDOR: Register<u16> = Register::new(0x00);
+/// Device ID
+pub const PCIR_DEVICE: Register<u16> = Register::new(0x02);
+/// Command register
+pub const PCIR_COMMAND: Register<u16> = Register::new(0x04);
+/// Status register
+pub const PCIR_STATUS: Register<u16> = Register::new(0x06);
+/// Revision ID
+pub const PCIR_REVID: Register<u8> = Register::new(0x08);
+/// Programming interface
+pub const PCIR_PROGIF: Register<u8> = Register::new(0x09);
+/// Subclass
+pub const PCIR_SUBCLASS: Register<u8> = Register::new(0x0a);
+/// Class
+pub const PCIR_CLASS: Register<u8> = Register::new(0x0b);
+/// Subsystem vendor ID
+pub const PCIR_SUBVEND_0: Register<u16> = Register::new(0x2c);
+/// Subsystem ID
+pub const PCIR_SUBDEV_0: Register<u16> = Register::new(0x2e);
+
+/// The resource id of base address register `bar`, i.e. `PCIR_BAR()`
+pub const fn pcir_bar(bar: c_int) -> c_int {
+    0x10 + bar * 4
+}
+
+// The PCI methods of the parent bus are called through inline functions
+// generated from `pci_if.m`, so the descriptors are not in the bindings
+extern "C" {
+    static pci_read_config_desc: kobjop_desc;
+    static pci_write_config_desc: kobjop_desc;
+    static pci_enable_busmaster_desc: kobjop_desc;
+    static pci_msi_count_desc: kobjop_desc;
+    static pci_msix_count_desc: kobjop_desc;
+    static pci_alloc_msi_desc: kobjop_desc;
+    static pci_alloc_msix_desc: kobjop_desc;
+    static pci_release_msi_desc: kobjop_desc;
+}
+
+type ReadConfig = unsafe extern "C" fn(device_t, device_t, c_int, c_int) -> u32;
+type WriteConfig = unsafe extern "C" fn(device_t, device_t, c_int, u32, c_int);
+type Method = unsafe extern "C" fn(device_t, device_t) -> c_int;
+type AllocMsi = unsafe extern "C" fn(device_t, device_t, *mut c_int) -> c_int;
+
+/// Look up method `desc` of the kobj class of `dev`, i.e.
+/// `KOBJOPLOOKUP()` without the method cache
+unsafe fn lookup(dev: device_t, desc: &kobjop_desc) -> unsafe extern "C" fn() {
+    let ops = (*(dev as *mut kernel_sys::kobj)).ops;
+    let method = kernel_sys::kobj_lookup_method(
+        (*ops).cls,
+        ptr::null_mut(),
+        desc as *const kobjop_desc as *mut kobjop_desc,
+    );
+    // Falls back to the default method of the descriptor
+    (*method).func.unwrap()
+}
+
+/// An entry of a PCI device ID table, laid out for `MODULE_PNP_INFO()`
+/// as described by `PNP_INFO`
+#[repr(C)]
+#[derive(Debug)]
+pub struct PciId {
+    pub vendor: u16,
+    pub device: u16,
+    desc: *const c_char,
+}
+
+impl PciId {
+    /// `desc` must be null-terminated, as `pci_device_table!` does
+    pub const fn new(vendor: u16, device: u16, desc: &'static str) -> PciId {
+        PciId {
+            vendor,
+            device,
+            desc: desc.as_ptr() as *const c_char,
+        }
+    }
+
+    /// The description of the device, e.g. for `Device::set_desc`
+    pub fn desc(&self) -> &'static str {
+        unsafe { c_str(self.desc) }.unwrap_or("")
+    }
+}
+
+unsafe impl Sync for PciId {}
+
+/// The `MODULE_PNP_INFO()` description of a `PciId`, used by
+/// `devmatch(8)` to find the driver of a device
+pub const PNP_INFO: &str = "U16:vendor;U16:device;D:#\0";
+
+/// Declare `$table`, a `static` table of PCI IDs, and export it as PNP
+/// info of the driver named `$driver` so that `devmatch(8)` loads the
+/// module for matching devices, i.e. `MODULE_PNP_INFO()`. Entries are
+/// `(vendor, device, description)`.
+#[macro_export]
+macro_rules! pci_device_table {
+    (
+        $driver:ident,
+        $table:ident,
+        [$(($vendor:expr, $device:expr, $desc:expr)),* $(,)?]
+    ) => {
+        static $table: [$crate::bus::pci::PciId;
+            [$($vendor),*].len()] = [$(
+            $crate::bus::pci::PciId::new($vendor, $device, $crate::cstr!($desc))
+        ),*];
+
+        const _: () = {
+            static PNP_INFO: $crate::linker_set::Static<
+                $crate::kernel_sys::mod_pnp_match_info,
+            > = $crate::linker_set::Static(
+                $crate::kernel_sys::mod_pnp_match_info {
+                    descr: $crate::bus::pci::PNP_INFO.as_ptr()
+                        as *const $crate::libc::c_char,
+                    bus: "pci\0".as_ptr() as *const $crate::libc::c_char,
+                    table: &$table as *const _ as *const $crate::libc::c_void,
+                    entry_len: ::core::mem::size_of::<$crate::bus::pci::PciId>()
+                        as _,
+                    num_entry: $table.len() as _,
+                },
+            );
+            $crate::__module_metadata!(
+                $crate::kernel_sys::MDT_PNP_INFO,
+                &PNP_INFO,
+                // The bus, while `$driver` only names the symbols in C
+                $crate::cstr!("pci")
+            );
+        };
+    };
+}
+
+/// A device on a PCI bus
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PciDevice(Device);
+
+impl PciDevice {
+    /// Returns `None` if `dev` is not attached to a PCI bus
+    pub fn new(dev: Device) -> Option<PciDevice> {
+        let parent = dev.parent()?;
+        if parent.name() == Some("pci") {
+            Some(PciDevice(dev))
+        } else {
+            None
+        }
+    }
+
+    pub fn device(self) -> Device {
+        self.0
+    }
+
+    fn parent(self) -> device_t {
+        unsafe { kernel_sys::device_get_parent(self.0.as_ptr()) }
+    }
+
+    unsafe fn call(self, desc: &kobjop_desc) -> c_int {
+        let method = transmute::<_, Method>(lookup(self.parent(), desc));
+        method(self.parent(), self.0.as_ptr())
+    }
+
+    /// Read `reg` from config space, i.e. `pci_read_config()`
+    pub fn read_config<T: RegisterValue>(self, reg: Register<T>) -> T {
+        let value = unsafe {
+            let read = transmute::<_, ReadConfig>(lookup(
+                self.parent(),
+                &pci_read_config_desc,
+            ));
+            read(
+                self.parent(),
+                self.0.as_ptr(),
+                reg.offset() as c_int,
+                size_of::<T>() as c_int,
+            )
+        };
+        // The bus only returns `size_of::<T>()` bytes
+        T::truncate(value)
+    }
+
+    /// Write `value` to `reg` in config space, i.e. `pci_write_config()`
+    pub fn write_config<T: RegisterValue + Into<u32>>(
+        self,
+        reg: Register<T>,
+        value: T,
+    ) {
+        unsafe {
+            let write = transmute::<_, WriteConfig>(lookup(
+                self.parent(),
+                &pci_write_config_desc,
+            ));
+            write(
+                self.parent(),
+                self.0.as_ptr(),
+                reg.offset() as c_int,
+                value.into(),
+                size_of::<T>() as c_int,
+            )
+        };
+    }
+
+    pub fn vendor(self) -> u16 {
+        self.read_config(PCIR_VENDOR)
+    }
+
+    pub fn device_id(self) -> u16 {
+        self.read_config(PCIR_DEVICE)
+    }
+
+    pub fn subvendor(self) -> u16 {
+        self.read_config(PCIR_SUBVEND_0)
+    }
+
+    pub fn subdevice(self) -> u16 {
+        self.read_config(PCIR_SUBDEV_0)
+    }
+
+    pub fn revision(self) -> u8 {
+        self.read_config(PCIR_REVID)
+    }
+
+    pub fn class(self) -> u8 {
+        self.read_config(PCIR_CLASS)
+    }
+
+    pub fn subclass(self) -> u8 {
+        self.read_config(PCIR_SUBCLASS)
+    }
+
+    /// The entry of `table` matching the vendor and device ID
+    pub fn lookup(self, table: &'static [PciId]) -> Option<&'static PciId> {
+        let (vendor, device) = (self.vendor(), self.device_id());
+        table
+            .iter()
+            .find(|id| id.vendor == vendor && id.device == device)
+    }
+
+    /// Let the device master the bus for DMA, i.e.
+    /// `pci_enable_busmaster()`
+    pub fn enable_busmaster(self) -> Result<(), Errno> {
+        Errno::result(unsafe { self.call(&pci_enable_busmaster_desc) })
+    }
+
+    /// Allocate and map base address register `bar` (0 to 5)
+    pub fn map_bar<K: Registers>(
+        self,
+        bar: c_int,
+    ) -> Result<Resource<K>, Errno> {
+        Resource::alloc_any(self.0, pcir_bar(bar), RF_ACTIVE)
+    }
+
+    /// The number of MSI messages the device supports
+    pub fn msi_count(self) -> c_int {
+        unsafe { self.call(&pci_msi_count_desc) }
+    }
+
+    /// The number of MSI-X messages the device supports
+    pub fn msix_count(self) -> c_int {
+        unsafe { self.call(&pci_msix_count_desc) }
+    }
+
+    /// Allocate up to `count` MSI messages, a power of two, i.e.
+    /// `pci_alloc_msi()`
+    pub fn alloc_msi(self, count: c_int) -> Result<MsiVectors, Errno> {
+        self.alloc(unsafe { &pci_alloc_msi_desc }, count)
+    }
+
+    /// Allocate up to `count` MSI-X messages, i.e. `pci_alloc_msix()`
+    pub fn alloc_msix(self, count: c_int) -> Result<MsiVectors, Errno> {
+        self.alloc(unsafe { &pci_alloc_msix_desc }, count)
+    }
+
+    fn alloc(
+        self,
+        desc: &kobjop_desc,
+        count: c_int,
+    ) -> Result<MsiVectors, Errno> {
+        let mut count = count;
+        let ret = unsafe {
+            let alloc = transmute::<_, AllocMsi>(lookup(self.parent(), desc));
+            alloc(self.parent(), self.0.as_ptr(), &mut count)
+        };
+        Errno::result(ret)?;
+        Ok(MsiVectors {
+            dev: self,
+            count,
+            interrupts: Vec::new(),
+        })
+    }
+}
+
+/// MSI or MSI-X messages allocated for a device, with the interrupt
+/// handlers set up for them. Dropping this tears down the handlers and
+/// releases the messages (`pci_release_msi()`).
+pub struct MsiVectors {
+    dev: PciDevice,
+    count: c_int,
+    interrupts: Vec<Interrupt>,
+}
+
+impl MsiVectors {
+    /// The number of messages allocated, which may be fewer than requested
+    pub fn count(&self) -> c_int {
+        self.count
+    }
+
+    /// Allocate the interrupt resource of message `index` and set up
+    /// handlers for it with `setup`, e.g. `Interrupt::filter`
+    pub fn setup<F>(&mut self, index: c_int, setup: F) -> Result<(), Errno>
+    where
+        F: FnOnce(Resource<Irq>) -> Result<Interrupt, Errno>,
+    {
+        if index < 0 || index >= self.count {
+            return Err(Errno::EINVAL);
+        }
+        // Message interrupts use resource ids from 1
+        let irq = Resource::alloc_any(self.dev.0, index + 1, RF_ACTIVE)?;
+        self.interrupts.push(setup(irq)?);
+        Ok(())
+    }
+
+    /// The interrupts set up so far
+    pub fn interrupts(&self) -> &[Interrupt] {
+        &self.interrupts
+    }
+}
+
+impl fmt::Debug for MsiVectors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MsiVectors")
+            .field("dev", &self.dev)
+            .field("count", &self.count)
+            .field("interrupts", &self.interrupts)
+            .finish()
+    }
+}
+
+impl Drop for MsiVectors {
+    fn drop(&mut self) {
+        // The interrupt resources must be released first
+        self.interrupts.clear();
+        unsafe { self.dev.call(&pci_release_msi_desc) };
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Bus resources and register access, see `bus_alloc_resource(9)` and
+//! `bus_space(9)`
+
+use super::Device;
+use crate::error::Errno;
+use core::arch::asm;
+use core::fmt;
+use core::marker::PhantomData;
+use core::mem::size_of;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{compiler_fence, Ordering};
+use kernel_sys::{bus_space_handle_t, bus_space_tag_t};
+use libc::{c_int, c_uint};
+
+/// Activate the resource when allocating it, which maps memory resources
+pub const RF_ACTIVE: c_uint = kernel_sys::RF_ACTIVE as c_uint;
+/// Allow other drivers to allocate the resource too, e.g. a shared
+/// interrupt line
+pub const RF_SHAREABLE: c_uint = kernel_sys::RF_SHAREABLE as c_uint;
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A type of bus resource
+pub trait ResourceKind: private::Sealed {
+    /// The `SYS_RES_*` type
+    const TYPE: c_int;
+    /// Whether the resource has registers, which are only mapped while it
+    /// is active
+    const REGISTERS: bool = false;
+}
+
+/// A range of device memory, `SYS_RES_MEMORY`
+#[derive(Debug)]
+pub enum Memory {}
+/// A range of I/O ports, `SYS_RES_IOPORT`
+#[derive(Debug)]
+pub enum IoPort {}
+/// An interrupt line, `SYS_RES_IRQ`
+#[derive(Debug)]
+pub enum Irq {}
+
+impl private::Sealed for Memory {}
+impl private::Sealed for IoPort {}
+impl private::Sealed for Irq {}
+
+impl ResourceKind for Memory {
+    const TYPE: c_int = kernel_sys::SYS_RES_MEMORY;
+    const REGISTERS: bool = true;
+}
+impl ResourceKind for IoPort {
+    const TYPE: c_int = kernel_sys::SYS_RES_IOPORT;
+    const REGISTERS: bool = true;
+}
+impl ResourceKind for Irq {
+    const TYPE: c_int = kernel_sys::SYS_RES_IRQ;
+}
+
+/// Resources with registers that can be read and written
+pub trait Registers: ResourceKind {}
+
+impl Registers for Memory {}
+impl Registers for IoPort {}
+
+/// A resource allocated from the parent bus of a device, released when
+/// dropped
+pub struct Resource<K: ResourceKind> {
+    dev: Device,
+    rid: c_int,
+    res: NonNull<kernel_sys::resource>,
+    tag: bus_space_tag_t,
+    handle: bus_space_handle_t,
+    size: usize,
+    _kind: PhantomData<K>,
+}
+
+impl<K: ResourceKind> Resource<K> {
+    /// Allocate resource `rid` of `dev` with its default range, i.e.
+    /// `bus_alloc_resource_any()`. `flags` is usually `RF_ACTIVE`, and
+    /// `rid` is e.g. the config space offset of a PCI BAR. Returns `ENXIO`
+    /// if the resource is not available.
+    ///
+    /// Memory and I/O port resources must be allocated with `RF_ACTIVE`, as
+    /// their registers cannot be accessed otherwise. Returns `EINVAL` if it
+    /// is missing.
+    pub fn alloc_any(
+        dev: Device,
+        rid: c_int,
+        flags: c_uint,
+    ) -> Result<Resource<K>, Errno> {
+        if K::REGISTERS && flags & RF_ACTIVE == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let mut rid = rid;
+        let res = unsafe {
+            kernel_sys::bus_alloc_resource(
+                dev.as_ptr(),
+                K::TYPE,
+                &mut rid,
+                0,
+                !0,
+                1,
+                flags,
+            )
+        };
+        let res = NonNull::new(res).ok_or(Errno::ENXIO)?;
+        unsafe {
+            Ok(Resource {
+                dev,
+                rid,
+                res,
+                tag: kernel_sys::rman_get_bustag(res.as_ptr()),
+                handle: kernel_sys::rman_get_bushandle(res.as_ptr()),
+                size: kernel_sys::rman_get_size(res.as_ptr()) as usize,
+                _kind: PhantomData,
+            })
+        }
+    }
+
+    /// The device the resource was allocated for
+    pub fn device(&self) -> Device {
+        self.dev
+    }
+
+    /// The resource id, which the bus may have changed on allocation
+    pub fn rid(&self) -> c_int {
+        self.rid
+    }
+
+    /// The first bus address or interrupt number of the resource
+    pub fn start(&self) -> u64 {
+        unsafe { kernel_sys::rman_get_start(self.res.as_ptr()) as u64 }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::resource {
+        self.res.as_ptr()
+    }
+}
+
+impl<K: Registers> Resource<K> {
+    fn address<T: RegisterValue>(&self, reg: Register<T>) -> usize {
+        assert!(
+            reg.offset + size_of::<T>() <= self.size,
+            "register offset {:#x} out of range",
+            reg.offset
+        );
+        self.handle as usize + reg.offset
+    }
+
+    /// Read `reg`, i.e. `bus_space_read_{1,2,4}()`
+    pub fn read<T: RegisterValue>(&self, reg: Register<T>) -> T {
+        let address = self.address(reg);
+        unsafe {
+            if self.tag == kernel_sys::X86_BUS_SPACE_IO as bus_space_tag_t {
+                T::port_read(address as u16)
+            } else {
+                ptr::read_volatile(address as *const T)
+            }
+        }
+    }
+
+    /// Write `value` to `reg`, i.e. `bus_space_write_{1,2,4}()`
+    pub fn write<T: RegisterValue>(&self, reg: Register<T>, value: T) {
+        let address = self.address(reg);
+        unsafe {
+            if self.tag == kernel_sys::X86_BUS_SPACE_IO as bus_space_tag_t {
+                T::port_write(address as u16, value)
+            } else {
+                ptr::write_volatile(address as *mut T, value)
+            }
+        }
+    }
+
+    /// Set the bits in `mask` in `reg`
+    pub fn set<T: RegisterValue>(&self, reg: Register<T>, mask: T) {
+        self.write(reg, self.read(reg) | mask);
+    }
+
+    /// Clear the bits in `mask` in `reg`
+    pub fn clear<T: RegisterValue>(&self, reg: Register<T>, mask: T) {
+        self.write(reg, self.read(reg) & !mask);
+    }
+
+    /// Order the register accesses before the barrier with those after it,
+    /// i.e. `bus_space_barrier()`. As on amd64 in C, read barriers are a
+    /// full fence through a locked instruction, while write barriers only
+    /// stop the compiler from reordering accesses.
+    pub fn barrier(&self, barrier: Barrier) {
+        match barrier {
+            Barrier::Read | Barrier::ReadWrite => unsafe {
+                asm!("lock; addl $0, 0(%rsp)", options(att_syntax));
+            },
+            Barrier::Write => compiler_fence(Ordering::SeqCst),
+        }
+    }
+}
+
+impl<K: ResourceKind> fmt::Debug for Resource<K> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Resource")
+            .field("dev", &self.dev)
+            .field("type", &K::TYPE)
+            .field("rid", &self.rid)
+            .field("size", &self.size)
+            .finish()
+    }
+}
+
+impl<K: ResourceKind> Drop for Resource<K> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::bus_release_resource(
+                self.dev.as_ptr(),
+                K::TYPE,
+                self.rid,
+                self.res.as_ptr(),
+            )
+        };
+    }
+}
+
+unsafe impl<K: ResourceKind> Send for Resource<K> {}
+unsafe impl<K: ResourceKind> Sync for Resource<K> {}
+
+/// Which accesses a `Resource::barrier` orders
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Barrier {
+    /// Reads, `BUS_SPACE_BARRIER_READ`
+    Read,
+    /// Writes, `BUS_SPACE_BARRIER_WRITE`
+    Write,
+    /// Both reads and writes
+    ReadWrite,
+}
+
+/// A register of type `T` at an offset into a resource
+///
+/// ```rust,ignore
+/// const STATUS: Register<u32> = Register::new(0x10);
+///
+/// let status = self.regs.read(STATUS);
+/// ```
+#[derive(Debug)]
+pub struct Register<T> {
+    offset: usize,
+    _value: PhantomData<T>,
+}
+
+impl<T> Register<T> {
+    pub const fn new(offset: usize) -> Self {
+        Register {
+            offset,
+            _value: PhantomData,
+        }
+    }
+
+    pub const fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl<T> Clone for Register<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Register<T> {}
+
+/// The register sizes that can be accessed, i.e. the `_1`, `_2` and `_4`
+/// variants of `bus_space_read`/`bus_space_write`
+pub trait RegisterValue:
+    Copy
+    + core::ops::BitOr<Output = Self>
+    + core::ops::BitAnd<Output = Self>
+    + core::ops::Not<Output = Self>
+    + private::Sealed
+{
+    #[doc(hidden)]
+    unsafe fn port_read(port: u16) -> Self;
+    #[doc(hidden)]
+    unsafe fn port_write(port: u16, value: Self);
+    #[doc(hidden)]
+    fn truncate(value: u32) -> Self;
+}
+
+macro_rules! register_values {
+    ($($ty:ty, $reg:tt, $in:literal, $out:literal;)*) => {$(
+        impl private::Sealed for $ty {}
+
+        impl RegisterValue for $ty {
+            unsafe fn port_read(port: u16) -> Self {
+                let value: $ty;
+                asm!(
+                    $in,
+                    out($reg) value,
+                    in("dx") port,
+                    options(nostack, preserves_flags)
+                );
+                value
+            }
+
+            unsafe fn port_write(port: u16, value: Self) {
+                asm!(
+                    $out,
+                    in("dx") port,
+                    in($reg) value,
+                    options(nostack, preserves_flags)
+                );
+            }
+
+            fn truncate(value: u32) -> Self {
+                value as $ty
+            }
+        }
+    )*};
+}
+
+register_values! {
+    u8, "al", "in al, dx", "out dx, al";
+    u16, "ax", "in ax, dx", "out dx, ax";
+    u32, "eax", "in eax, dx", "out dx, eax";
+}
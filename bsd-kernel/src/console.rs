@@ -0,0 +1,295 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Allocation-free formatted output to the kernel console, the system log
+//! and the controlling terminal of the current process.
+//!
+//! Formatted output is rendered into a small fixed-size buffer on the
+//! stack and handed to the kernel in fragments whenever the buffer fills
+//! up, so printing never calls `malloc(9)`. This makes it usable from
+//! contexts that must not sleep and when the allocator has failed. The
+//! kernel is only ever given a constant `"%.*s"` format string, so the
+//! text being printed is never interpreted as a format.
+//!
+//! ```rust,ignore
+//! use bsd_kernel::console::{self, Priority, Target};
+//! console::print(Target::Log(Priority::Warning), format_args!("{}\n", x));
+//! ```
+
+use crate::cstr;
+use core::{cmp, fmt};
+
+/// Size of the stack buffer used by `print`. Output longer than this is
+/// passed to the kernel in several pieces.
+pub const DEFAULT_BUFFER_SIZE: usize = 128;
+
+/// `syslog` priorities accepted by `log(9)`, from `sys/syslog.h`
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(i32)]
+pub enum Priority {
+    /// System is unusable
+    Emerg = kernel_sys::LOG_EMERG,
+    /// Action must be taken immediately
+    Alert = kernel_sys::LOG_ALERT,
+    /// Critical conditions
+    Crit = kernel_sys::LOG_CRIT,
+    /// Error conditions
+    Err = kernel_sys::LOG_ERR,
+    /// Warning conditions
+    Warning = kernel_sys::LOG_WARNING,
+    /// Normal but significant condition
+    Notice = kernel_sys::LOG_NOTICE,
+    /// Informational
+    Info = kernel_sys::LOG_INFO,
+    /// Debug-level messages
+    Debug = kernel_sys::LOG_DEBUG,
+}
+
+/// Where formatted output is sent
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Target {
+    /// The console and kernel message buffer (`dmesg`), via `printf(9)`
+    Console,
+    /// The kernel message buffer and `/dev/klog` with the given priority,
+    /// via `log(9)`. `syslogd` picks these messages up.
+    Log(Priority),
+    /// The controlling terminal of the current process, via `uprintf(9)`.
+    /// Nothing is printed if the process has no terminal, and the output
+    /// does not end up in the message buffer.
+    Terminal,
+}
+
+impl Target {
+    fn emit(self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        sink(self, cstr!("%.*s"), bytes);
+    }
+}
+
+/// Pass `bytes` to the kernel printing function of `target` with the
+/// format string `fmt`. Tests replace this to capture the output.
+#[cfg(not(test))]
+fn sink(target: Target, fmt: &'static str, bytes: &[u8]) {
+    use libc::{c_char, c_int};
+
+    let fmt = fmt.as_ptr() as *const c_char;
+    let len = bytes.len() as c_int;
+    let ptr = bytes.as_ptr() as *const c_char;
+    unsafe {
+        match target {
+            Target::Console => {
+                kernel_sys::printf(fmt, len, ptr);
+            }
+            Target::Log(priority) => {
+                kernel_sys::log(priority as c_int, fmt, len, ptr);
+            }
+            Target::Terminal => {
+                kernel_sys::uprintf(fmt, len, ptr);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+use self::tests::sink;
+
+/// `fmt::Write` implementation that collects output in a fixed-size
+/// buffer of `N` bytes and passes it to the kernel when the buffer is
+/// full, on `flush` and when dropped.
+pub struct KernelWriter<const N: usize = DEFAULT_BUFFER_SIZE> {
+    target: Target,
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> KernelWriter<N> {
+    // `N`, failing the build if it is 0, as `write_str` could then never
+    // make progress
+    const CAPACITY: usize = {
+        assert!(N > 0, "KernelWriter needs a buffer");
+        N
+    };
+
+    pub fn new(target: Target) -> Self {
+        KernelWriter {
+            target,
+            buf: [0; N],
+            len: 0,
+        }
+    }
+
+    pub fn target(&self) -> Target {
+        self.target
+    }
+
+    /// Pass any buffered output to the kernel
+    pub fn flush(&mut self) {
+        self.target.emit(&self.buf[..self.len]);
+        self.len = 0;
+    }
+}
+
+impl<const N: usize> fmt::Write for KernelWriter<N> {
+    fn write_str(&mut self, message: &str) -> fmt::Result {
+        let mut bytes = message.as_bytes();
+        while !bytes.is_empty() {
+            if self.len == Self::CAPACITY {
+                self.flush();
+            }
+            let amount = cmp::min(Self::CAPACITY - self.len, bytes.len());
+            self.buf[self.len..self.len + amount]
+                .copy_from_slice(&bytes[..amount]);
+            self.len += amount;
+            bytes = &bytes[amount..];
+        }
+        Ok(())
+    }
+}
+
+impl<const N: usize> Drop for KernelWriter<N> {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl<const N: usize> fmt::Debug for KernelWriter<N> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "KernelWriter {{ target: {:?}, buffered: {}/{} }}",
+            self.target, self.len, N
+        )
+    }
+}
+
+/// Format `args` and send the result to `target` without allocating
+pub fn print(target: Target, args: fmt::Arguments) {
+    use core::fmt::Write;
+    // Messages without arguments, e.g. from `println!("...")`, need no
+    // formatting and can be handed to the kernel without copying
+    if let Some(message) = args.as_str() {
+        target.emit(message.as_bytes());
+        return;
+    }
+    let mut writer: KernelWriter = KernelWriter::new(target);
+    // `KernelWriter::write_str` never fails, so an error here can only
+    // come from a `Display` implementation and there is nowhere to
+    // report it.
+    let _ = writer.write_fmt(args);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{print, KernelWriter, Priority, Target, DEFAULT_BUFFER_SIZE};
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use core::fmt::Write;
+    use std::cell::RefCell;
+
+    type Call = (Target, &'static str, Vec<u8>);
+
+    std::thread_local! {
+        /// The output of the current test
+        static CALLS: RefCell<Vec<Call>> = RefCell::new(Vec::new());
+    }
+
+    pub(super) fn sink(target: Target, fmt: &'static str, bytes: &[u8]) {
+        CALLS.with(|calls| {
+            calls.borrow_mut().push((target, fmt, bytes.to_vec()))
+        });
+    }
+
+    /// The pieces printed since the last call, checking that each was
+    /// printed with the constant format string
+    fn printed(target: Target) -> Vec<Vec<u8>> {
+        CALLS
+            .with(|calls| calls.take())
+            .into_iter()
+            .map(|(t, fmt, bytes)| {
+                assert_eq!(t, target);
+                assert_eq!(fmt, "%.*s\0");
+                bytes
+            })
+            .collect()
+    }
+
+    #[test]
+    fn percent_is_literal() {
+        crate::print!("100%s");
+        assert_eq!(printed(Target::Console), [b"100%s"]);
+
+        crate::println!("%n");
+        assert_eq!(printed(Target::Console), [b"%n\n"]);
+
+        crate::uprint!("{}%d{}", 1, "%s");
+        assert_eq!(printed(Target::Terminal), [b"1%d%s"]);
+
+        let target = Target::Log(Priority::Err);
+        print(target, format_args!("%n%n%n {:?}", "%p"));
+        assert_eq!(printed(target), [&b"%n%n%n \"%p\""[..]]);
+    }
+
+    #[test]
+    fn literal_fast_path() {
+        // Printed in one piece without copying, however long it is
+        let message = format_args!(
+            "a message without arguments that is longer than the buffer \
+             used to format messages with arguments, so it would be split \
+             if it was copied"
+        );
+        let text = message.as_str().unwrap();
+        assert!(text.len() > DEFAULT_BUFFER_SIZE);
+        print(Target::Console, message);
+        let calls = CALLS.with(|calls| calls.take());
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].2, text.as_bytes());
+
+        // Nothing to print
+        crate::print!("");
+        assert!(printed(Target::Console).is_empty());
+    }
+
+    #[test]
+    fn long_messages_are_split() {
+        let long: String = "0123456789".repeat(30);
+        print(Target::Console, format_args!("{}%", long));
+        let pieces = printed(Target::Console);
+        let sizes: Vec<_> = pieces.iter().map(|p| p.len()).collect();
+        assert_eq!(sizes, [DEFAULT_BUFFER_SIZE, DEFAULT_BUFFER_SIZE, 45]);
+        assert_eq!(pieces.concat(), alloc::format!("{}%", long).as_bytes());
+
+        {
+            let mut writer: KernelWriter<4> =
+                KernelWriter::new(Target::Console);
+            writer.write_str("ab").unwrap();
+            writer.write_str("cdefghij").unwrap();
+            assert_eq!(printed(Target::Console), [b"abcd", b"efgh"]);
+        }
+        // The rest is printed when the writer is dropped
+        assert_eq!(printed(Target::Console), [b"ij"]);
+    }
+}
@@ -23,6 +23,11 @@
 //
 // Based on public domain code by Johannes Lundberg
 
+use crate::io;
+use alloc::format;
+use core::fmt;
+use libc::c_int;
+
 #[derive(Debug)]
 pub enum Error {
     ConversionError(&'static str),
@@ -33,3 +38,112 @@ impl From<core::num::TryFromIntError> for Error {
         Error::ConversionError("Invalid integer type")
     }
 }
+
+/// An error number from `sys/errno.h`, as returned to the kernel by
+/// module and device entry points
+#[derive(Copy, Clone, Eq, PartialEq, Hash)]
+pub struct Errno(c_int);
+
+impl Errno {
+    pub const EPERM: Errno = Errno(libc::EPERM);
+    pub const ENOENT: Errno = Errno(libc::ENOENT);
+    pub const EINTR: Errno = Errno(libc::EINTR);
+    pub const EIO: Errno = Errno(libc::EIO);
+    pub const ENXIO: Errno = Errno(libc::ENXIO);
+    pub const EBADF: Errno = Errno(libc::EBADF);
+    pub const ENOMEM: Errno = Errno(libc::ENOMEM);
+    pub const EACCES: Errno = Errno(libc::EACCES);
+    pub const EFAULT: Errno = Errno(libc::EFAULT);
+    pub const EBUSY: Errno = Errno(libc::EBUSY);
+    pub const EEXIST: Errno = Errno(libc::EEXIST);
+    pub const ENODEV: Errno = Errno(libc::ENODEV);
+    pub const ENOTDIR: Errno = Errno(libc::ENOTDIR);
+    pub const EISDIR: Errno = Errno(libc::EISDIR);
+    pub const EINVAL: Errno = Errno(libc::EINVAL);
+    pub const ENOSPC: Errno = Errno(libc::ENOSPC);
+    pub const EROFS: Errno = Errno(libc::EROFS);
+    pub const EPIPE: Errno = Errno(libc::EPIPE);
+    pub const ENOBUFS: Errno = Errno(libc::ENOBUFS);
+    pub const EAGAIN: Errno = Errno(libc::EAGAIN);
+    pub const EALREADY: Errno = Errno(libc::EALREADY);
+    pub const EOPNOTSUPP: Errno = Errno(libc::EOPNOTSUPP);
+    pub const EAFNOSUPPORT: Errno = Errno(libc::EAFNOSUPPORT);
+    pub const EADDRINUSE: Errno = Errno(libc::EADDRINUSE);
+    pub const EADDRNOTAVAIL: Errno = Errno(libc::EADDRNOTAVAIL);
+    pub const ECONNABORTED: Errno = Errno(libc::ECONNABORTED);
+    pub const ECONNRESET: Errno = Errno(libc::ECONNRESET);
+    pub const ENOTCONN: Errno = Errno(libc::ENOTCONN);
+    pub const ETIMEDOUT: Errno = Errno(libc::ETIMEDOUT);
+    pub const ECONNREFUSED: Errno = Errno(libc::ECONNREFUSED);
+    pub const ENOSYS: Errno = Errno(libc::ENOSYS);
+
+    /// Wrap a raw error number. `n` should be non-zero, as 0 means success
+    /// to the kernel.
+    pub const fn from_raw(n: c_int) -> Errno {
+        Errno(n)
+    }
+
+    pub const fn raw(self) -> c_int {
+        self.0
+    }
+
+    /// Convert the return value of a kernel function that returns 0 on
+    /// success or an error number into a `Result`
+    pub fn result(ret: c_int) -> Result<(), Errno> {
+        match ret {
+            0 => Ok(()),
+            n => Err(Errno(n)),
+        }
+    }
+
+    /// Convert a `Result` back into the return value expected by the
+    /// kernel
+    pub fn to_ret(result: Result<(), Errno>) -> c_int {
+        match result {
+            Ok(()) => 0,
+            Err(e) => e.0,
+        }
+    }
+}
+
+impl From<Errno> for c_int {
+    fn from(e: Errno) -> c_int {
+        e.0
+    }
+}
+
+impl From<Errno> for io::Error {
+    fn from(e: Errno) -> io::Error {
+        let kind = match e {
+            Errno::ENOENT => io::ErrorKind::NotFound,
+            Errno::EPERM | Errno::EACCES => io::ErrorKind::PermissionDenied,
+            Errno::ECONNREFUSED => io::ErrorKind::ConnectionRefused,
+            Errno::ECONNRESET => io::ErrorKind::ConnectionReset,
+            Errno::ECONNABORTED => io::ErrorKind::ConnectionAborted,
+            Errno::ENOTCONN => io::ErrorKind::NotConnected,
+            Errno::EADDRINUSE => io::ErrorKind::AddrInUse,
+            Errno::EADDRNOTAVAIL => io::ErrorKind::AddrNotAvailable,
+            Errno::EPIPE => io::ErrorKind::BrokenPipe,
+            Errno::EEXIST => io::ErrorKind::AlreadyExists,
+            Errno::EAGAIN => io::ErrorKind::WouldBlock,
+            Errno::EINVAL => io::ErrorKind::InvalidInput,
+            Errno::ETIMEDOUT => io::ErrorKind::TimedOut,
+            // Not `Interrupted`, which the io adaptors retry: a pending
+            // signal keeps interrupting the sleep, so they would spin
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, format!("{}", e))
+    }
+}
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Errno({})", self.0)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "error {}", self.0)
+    }
+}
@@ -0,0 +1,194 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Registration of Rust closures for kernel events, see `EVENTHANDLER(9)`
+//!
+//! Each event is described by a type implementing `Event`, which knows the
+//! name of the event and the signature of its C handlers. An
+//! `EventHandler` keeps the closure registered until it is dropped.
+//!
+//! ```rust,ignore
+//! use bsd_kernel::eventhandler::{self, EventHandler, ProcessExit};
+//!
+//! let handler = EventHandler::<ProcessExit>::register(
+//!     Box::new(|p| log::debug!("process {:?} exited", p)),
+//!     eventhandler::PRI_ANY,
+//! );
+//! ```
+
+use crate::cstr;
+use alloc::boxed::Box;
+use core::marker::PhantomData;
+use core::{fmt, ptr};
+use libc::{c_char, c_int, c_void};
+
+/// Run before other handlers of the event
+pub const PRI_FIRST: c_int = kernel_sys::EVENTHANDLER_PRI_FIRST as c_int;
+/// No particular ordering
+pub const PRI_ANY: c_int = kernel_sys::EVENTHANDLER_PRI_ANY as c_int;
+/// Run after other handlers of the event
+pub const PRI_LAST: c_int = kernel_sys::EVENTHANDLER_PRI_LAST as c_int;
+
+/// A kernel event that handlers can be registered for
+///
+/// # Safety
+/// `function` must return an `extern "C"` function with the signature the
+/// kernel uses for the event, which calls the `Box<Self::Handler>` that
+/// its first (`void *`) argument points to.
+pub unsafe trait Event {
+    /// The event name, null-terminated
+    const NAME: &'static str;
+    /// The closure type called for the event
+    type Handler: ?Sized + Send + Sync + 'static;
+    /// The C handler to register
+    fn function() -> *mut c_void;
+}
+
+macro_rules! events {
+    ($(
+        $(#[$attr:meta])*
+        $event:ident, $name:literal, fn($($arg:ident: $ty:ty),*);
+    )*) => {$(
+        $(#[$attr])*
+        #[derive(Debug)]
+        pub enum $event {}
+
+        unsafe impl Event for $event {
+            const NAME: &'static str = cstr!($name);
+            type Handler = dyn Fn($($ty),*) + Send + Sync;
+
+            fn function() -> *mut c_void {
+                unsafe extern "C" fn handler(
+                    arg: *mut c_void,
+                    $($arg: $ty),*
+                ) {
+                    let handler = &*(arg as *const Box<
+                        <$event as Event>::Handler,
+                    >);
+                    handler($($arg),*)
+                }
+                handler as *mut c_void
+            }
+        }
+    )*};
+}
+
+events! {
+    /// A process is exiting, called with the process
+    ProcessExit, "process_exit", fn(p: *mut kernel_sys::proc);
+    /// A process forked, called with the parent, the child and the
+    /// `RF*` fork flags
+    ProcessFork, "process_fork", fn(
+        parent: *mut kernel_sys::proc,
+        child: *mut kernel_sys::proc,
+        flags: c_int
+    );
+    /// The system is shutting down, before file systems are synced.
+    /// Called with the `RB_*` reboot flags.
+    ShutdownPreSync, "shutdown_pre_sync", fn(howto: c_int);
+    /// The system is shutting down, after file systems are synced
+    ShutdownPostSync, "shutdown_post_sync", fn(howto: c_int);
+    /// The system is about to halt, reboot or power off
+    ShutdownFinal, "shutdown_final", fn(howto: c_int);
+    /// The system is low on memory, called with `VM_LOW_*` flags
+    VmLowmem, "vm_lowmem", fn(flags: c_int);
+    /// A network interface was attached
+    IfnetArrival, "ifnet_arrival_event", fn(ifp: *mut kernel_sys::ifnet);
+    /// A network interface is being detached
+    IfnetDeparture, "ifnet_departure_event", fn(
+        ifp: *mut kernel_sys::ifnet
+    );
+    /// A device that does not exist yet was looked up in devfs. Called
+    /// with the credentials of the caller, the device name and its length
+    /// and a location to store a newly created device in.
+    DevClone, "dev_clone", fn(
+        cred: *mut kernel_sys::ucred,
+        name: *mut c_char,
+        namelen: c_int,
+        dev: *mut *mut kernel_sys::cdev
+    );
+}
+
+/// A closure registered for event `E`, deregistered when dropped
+pub struct EventHandler<E: Event> {
+    tag: kernel_sys::eventhandler_tag,
+    // Boxed twice so that the kernel can be given a thin pointer
+    _handler: Box<Box<E::Handler>>,
+    _event: PhantomData<E>,
+}
+
+impl<E: Event> EventHandler<E> {
+    /// Register `handler` to be called whenever `E` occurs. Returns `None`
+    /// if the kernel could not register the handler.
+    pub fn register(handler: Box<E::Handler>, priority: c_int) -> Option<Self> {
+        let handler = Box::new(handler);
+        let name = E::NAME.as_ptr() as *const c_char;
+        let tag = unsafe {
+            kernel_sys::eventhandler_register(
+                ptr::null_mut(),
+                name,
+                E::function(),
+                &*handler as *const Box<E::Handler> as *mut c_void,
+                priority,
+            )
+        };
+        if tag.is_null() {
+            return None;
+        }
+        Some(EventHandler {
+            tag,
+            _handler: handler,
+            _event: PhantomData,
+        })
+    }
+}
+
+impl<E: Event> fmt::Debug for EventHandler<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "EventHandler {{ event: {}, tag: {:?} }}",
+            E::NAME.trim_end_matches('\0'),
+            self.tag
+        )
+    }
+}
+
+impl<E: Event> Drop for EventHandler<E> {
+    fn drop(&mut self) {
+        // EVENTHANDLER_DEREGISTER(): looking up the list locks it, and
+        // deregistering unlocks it again. This waits for running
+        // invocations of the handler to finish, so the closure can be
+        // freed afterwards.
+        let name = E::NAME.as_ptr() as *const c_char;
+        unsafe {
+            let list = kernel_sys::eventhandler_find_list(name);
+            if !list.is_null() {
+                kernel_sys::eventhandler_deregister(list, self.tag);
+            }
+        }
+    }
+}
+
+unsafe impl<E: Event> Send for EventHandler<E> {}
+unsafe impl<E: Event> Sync for EventHandler<E> {}
@@ -0,0 +1,897 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! GEOM classes, see `geom(4)`, and `disk(9)` block devices
+//!
+//! A geom takes I/O requests on the providers it creates and usually
+//! passes them on through consumers attached to the providers of other
+//! geoms. The type implementing `GeomClass` is the state of each geom of
+//! the class, which is registered with `geom_class!`.
+//!
+//! Most methods are called on the GEOM event thread with the topology lock
+//! held, which they are given as a `Topology`. I/O requests arrive on
+//! `start` without it, on the GEOM down thread.
+//!
+//! ```rust,ignore
+//! /// Pass requests through unchanged, like `gnop(8)`
+//! struct Nop;
+//!
+//! impl GeomClass for Nop {
+//!     fn create(topo: &mut Topology, req: &Request, class: Class<Self>) {
+//!         let pp = match req.get_str("arg0").and_then(|name| {
+//!             Provider::by_name(topo, name)
+//!         }) {
+//!             Some(pp) => pp,
+//!             None => return req.error("Invalid provider."),
+//!         };
+//!         let name = format!("{}.nop", pp.name());
+//!         let gp = class.new_geom(topo, &name, Nop);
+//!         let cp = gp.new_consumer(topo);
+//!         if let Err(e) = cp.attach(topo, pp) {
+//!             gp.wither(topo, e);
+//!             return req.error("Cannot attach to provider.");
+//!         }
+//!         let new = gp.new_provider(topo, &name);
+//!         new.set_mediasize(pp.mediasize());
+//!         new.set_sectorsize(pp.sectorsize());
+//!         new.set_error(topo, None);
+//!     }
+//!
+//!     fn start(&self, geom: Geom<Self>, bio: Bio) {
+//!         match geom.consumer() {
+//!             Some(cp) => cp.forward(bio),
+//!             None => bio.deliver(Err(Errno::ENXIO)),
+//!         }
+//!     }
+//! }
+//!
+//! bsd_kernel::geom_class!(nop, "NOP", Nop);
+//! ```
+//!
+//! A block device that does not sit on other providers is simpler to
+//! write as a `Disk`, whose driver only handles requests.
+
+use crate::allocator::{Sleepable, SleepableContext};
+use crate::error::Errno;
+use crate::{c_str, cstr_ref};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::iter;
+use core::marker::PhantomData;
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::slice;
+use kernel_sys::{g_class, g_consumer, g_geom, g_provider, gctl_req};
+use libc::{c_char, c_int, c_void};
+
+pub use self::bio::{Bio, BioCmd};
+pub use self::disk::{
+    CreatedDisk, Disk, DiskDriver, DISKFLAG_CANDELETE, DISKFLAG_CANFLUSHCACHE,
+};
+
+mod bio;
+mod disk;
+
+/// `g_topology_lock()`
+unsafe fn topology_lock() {
+    let file = concat!(file!(), "\0").as_ptr() as *const c_char;
+    kernel_sys::_sx_xlock(
+        &mut kernel_sys::topology_lock,
+        0,
+        file,
+        line!() as _,
+    );
+}
+
+/// `g_topology_unlock()`
+unsafe fn topology_unlock() {
+    let file = concat!(file!(), "\0").as_ptr() as *const c_char;
+    kernel_sys::_sx_xunlock(&mut kernel_sys::topology_lock, file, line!() as _);
+}
+
+/// Proof that the GEOM topology lock is held, which is needed to change
+/// the topology. GEOM methods are given one, elsewhere it is taken with
+/// `Topology::lock`.
+pub struct Topology(PhantomData<*mut ()>);
+
+impl Topology {
+    /// Take the topology lock, i.e. `g_topology_lock()`
+    pub fn lock<C: Sleepable>(_ctx: &C) -> TopologyGuard {
+        unsafe { topology_lock() };
+        TopologyGuard(Topology(PhantomData))
+    }
+
+    /// Release the lock while running `f`, e.g. to read metadata from a
+    /// consumer while tasting, as I/O cannot be done with it held
+    ///
+    /// # Safety
+    /// Only `GeomClass::taste` and `GeomClass::ctlreq` may drop the lock.
+    /// Other methods rely on the topology not changing while they run.
+    /// Geoms, providers and consumers may be withered in the meantime,
+    /// so only use those the caller keeps open.
+    pub unsafe fn unlocked<R, F: FnOnce(&SleepableContext) -> R>(
+        &mut self,
+        f: F,
+    ) -> R {
+        topology_unlock();
+        let ret = f(&SleepableContext::new());
+        topology_lock();
+        ret
+    }
+}
+
+/// The topology lock, released when dropped
+pub struct TopologyGuard(Topology);
+
+impl Deref for TopologyGuard {
+    type Target = Topology;
+
+    fn deref(&self) -> &Topology {
+        &self.0
+    }
+}
+
+impl DerefMut for TopologyGuard {
+    fn deref_mut(&mut self) -> &mut Topology {
+        &mut self.0
+    }
+}
+
+impl Drop for TopologyGuard {
+    fn drop(&mut self) {
+        unsafe { topology_unlock() };
+    }
+}
+
+/// A GEOM class, i.e. `struct g_class`
+pub struct Class<G>(NonNull<g_class>, PhantomData<G>);
+
+impl<G> Clone for Class<G> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<G> Copy for Class<G> {}
+
+impl<G: GeomClass> Class<G> {
+    pub fn as_ptr(self) -> *mut g_class {
+        self.0.as_ptr()
+    }
+
+    pub fn name(&self) -> &str {
+        unsafe { c_str((*self.as_ptr()).name) }.unwrap_or("")
+    }
+
+    /// Create a geom named `name` with the state `state`, i.e.
+    /// `g_new_geomf()`
+    pub fn new_geom(self, _topo: &Topology, name: &str, state: G) -> Geom<G> {
+        let fmt = "%s\0".as_ptr() as *const c_char;
+        let name = cstr_ref!(name);
+        unsafe {
+            let gp = kernel_sys::g_new_geomf(self.as_ptr(), fmt, name.as_ptr());
+            (*gp).softc = Box::into_raw(Box::new(state)) as *mut c_void;
+            Geom::from_ptr(gp)
+        }
+    }
+
+    /// The geom of this class named `name` that is not being withered
+    pub fn find_geom(self, _topo: &Topology, name: &str) -> Option<Geom<G>> {
+        let first = unsafe { (*self.as_ptr()).geom.lh_first };
+        iter::successors(NonNull::new(first), |gp| {
+            NonNull::new(unsafe { (*gp.as_ptr()).geom.le_next })
+        })
+        .map(|gp| unsafe { Geom::from_ptr(gp.as_ptr()) })
+        .find(|gp| !gp.is_withering() && gp.name() == name)
+    }
+}
+
+/// A geom of the class `G`, i.e. `struct g_geom`
+pub struct Geom<G>(NonNull<g_geom>, PhantomData<G>);
+
+impl<G> Clone for Geom<G> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<G> Copy for Geom<G> {}
+
+unsafe impl<G: Sync> Send for Geom<G> {}
+unsafe impl<G: Sync> Sync for Geom<G> {}
+
+impl<G: GeomClass> Geom<G> {
+    unsafe fn from_ptr(gp: *mut g_geom) -> Geom<G> {
+        Geom(NonNull::new_unchecked(gp), PhantomData)
+    }
+
+    pub fn as_ptr(self) -> *mut g_geom {
+        self.0.as_ptr()
+    }
+
+    pub fn name(&self) -> &str {
+        unsafe { c_str((*self.as_ptr()).name) }.unwrap_or("")
+    }
+
+    pub fn class(self) -> Class<G> {
+        let mp = unsafe { (*self.as_ptr()).class };
+        Class(unsafe { NonNull::new_unchecked(mp) }, PhantomData)
+    }
+
+    /// The state of the geom, `None` once it has been withered
+    pub fn state<'a>(&self, _topo: &'a Topology) -> Option<&'a G> {
+        unsafe { ((*self.as_ptr()).softc as *const G).as_ref() }
+    }
+
+    /// Whether the geom is being destroyed
+    pub fn is_withering(&self) -> bool {
+        let flags = unsafe { (*self.as_ptr()).flags };
+        flags & kernel_sys::G_GEOM_WITHER as c_int != 0
+    }
+
+    /// Create a provider named `name`, e.g. the name of the geom, i.e.
+    /// `g_new_providerf()`. It is announced by `Provider::set_error` once
+    /// its sizes are set.
+    pub fn new_provider(self, _topo: &Topology, name: &str) -> Provider {
+        let fmt = "%s\0".as_ptr() as *const c_char;
+        let name = cstr_ref!(name);
+        unsafe {
+            let pp =
+                kernel_sys::g_new_providerf(self.as_ptr(), fmt, name.as_ptr());
+            Provider(NonNull::new_unchecked(pp))
+        }
+    }
+
+    /// Create a consumer, to be attached to a provider, i.e.
+    /// `g_new_consumer()`
+    pub fn new_consumer(self, _topo: &Topology) -> Consumer<G> {
+        unsafe {
+            let cp = kernel_sys::g_new_consumer(self.as_ptr());
+            Consumer::from_ptr(cp)
+        }
+    }
+
+    /// The first consumer of the geom, the only one of a simple transform
+    pub fn consumer(self) -> Option<Consumer<G>> {
+        let cp = unsafe { (*self.as_ptr()).consumer.lh_first };
+        NonNull::new(cp).map(|cp| Consumer(cp, PhantomData))
+    }
+
+    pub fn providers(self) -> impl Iterator<Item = Provider> {
+        let first = unsafe { (*self.as_ptr()).provider.lh_first };
+        iter::successors(NonNull::new(first).map(Provider), |pp| {
+            NonNull::new(unsafe { (*pp.as_ptr()).provider.le_next })
+                .map(Provider)
+        })
+    }
+
+    /// Destroy the geom once its providers are closed, orphaning them
+    /// with `error`, i.e. `g_wither_geom()`. The state is dropped after
+    /// the last provider is gone.
+    pub fn wither(self, _topo: &Topology, error: Errno) {
+        unsafe {
+            kernel_sys::g_wither_geom(self.as_ptr(), error.raw());
+            if (*self.as_ptr()).provider.lh_first.is_null() {
+                release_state::<G>(self.as_ptr());
+            }
+        }
+    }
+}
+
+/// A provider, i.e. `struct g_provider`, which is either one of our geoms
+/// or one consumers can be attached to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Provider(NonNull<g_provider>);
+
+unsafe impl Send for Provider {}
+unsafe impl Sync for Provider {}
+
+impl Provider {
+    /// The provider named `name`, with or without `/dev/`, i.e.
+    /// `g_provider_by_name()`
+    pub fn by_name(_topo: &Topology, name: &str) -> Option<Provider> {
+        let name = cstr_ref!(name);
+        NonNull::new(unsafe {
+            kernel_sys::g_provider_by_name(name.as_ptr() as _)
+        })
+        .map(Provider)
+    }
+
+    pub fn as_ptr(self) -> *mut g_provider {
+        self.0.as_ptr()
+    }
+
+    pub fn name(&self) -> &str {
+        unsafe { c_str((*self.as_ptr()).name) }.unwrap_or("")
+    }
+
+    /// The size in bytes
+    pub fn mediasize(self) -> i64 {
+        unsafe { (*self.as_ptr()).mediasize }
+    }
+
+    pub fn set_mediasize(self, mediasize: i64) {
+        unsafe { (*self.as_ptr()).mediasize = mediasize };
+    }
+
+    /// The size of a sector in bytes, which requests are a multiple of
+    pub fn sectorsize(self) -> u32 {
+        unsafe { (*self.as_ptr()).sectorsize }
+    }
+
+    pub fn set_sectorsize(self, sectorsize: u32) {
+        unsafe { (*self.as_ptr()).sectorsize = sectorsize };
+    }
+
+    pub fn stripesize(self) -> i64 {
+        unsafe { (*self.as_ptr()).stripesize }
+    }
+
+    pub fn stripeoffset(self) -> i64 {
+        unsafe { (*self.as_ptr()).stripeoffset }
+    }
+
+    /// Set the preferred alignment of requests, e.g. the physical sector
+    /// size
+    pub fn set_stripe(self, size: i64, offset: i64) {
+        unsafe {
+            (*self.as_ptr()).stripesize = size;
+            (*self.as_ptr()).stripeoffset = offset;
+        }
+    }
+
+    /// Whether the provider is open for reading, writing or exclusively
+    pub fn is_open(self) -> bool {
+        let pp = unsafe { &*self.as_ptr() };
+        pp.acr != 0 || pp.acw != 0 || pp.ace != 0
+    }
+
+    /// Fail new requests with `error`, or with `None` make the provider
+    /// available, i.e. `g_error_provider()`
+    pub fn set_error(self, _topo: &Topology, error: Option<Errno>) {
+        let error = error.map_or(0, Errno::raw);
+        unsafe { kernel_sys::g_error_provider(self.as_ptr(), error) };
+    }
+}
+
+/// A consumer of a geom of the class `G`, i.e. `struct g_consumer`
+pub struct Consumer<G>(NonNull<g_consumer>, PhantomData<G>);
+
+impl<G> Clone for Consumer<G> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<G> Copy for Consumer<G> {}
+
+unsafe impl<G: Sync> Send for Consumer<G> {}
+unsafe impl<G: Sync> Sync for Consumer<G> {}
+
+impl<G: GeomClass> Consumer<G> {
+    unsafe fn from_ptr(cp: *mut g_consumer) -> Consumer<G> {
+        Consumer(NonNull::new_unchecked(cp), PhantomData)
+    }
+
+    pub fn as_ptr(self) -> *mut g_consumer {
+        self.0.as_ptr()
+    }
+
+    pub fn geom(self) -> Geom<G> {
+        unsafe { Geom::from_ptr((*self.as_ptr()).geom) }
+    }
+
+    /// The provider the consumer is attached to
+    pub fn provider(self) -> Option<Provider> {
+        NonNull::new(unsafe { (*self.as_ptr()).provider }).map(Provider)
+    }
+
+    /// i.e. `g_attach()`
+    pub fn attach(self, _topo: &Topology, pp: Provider) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::g_attach(self.as_ptr(), pp.as_ptr())
+        })
+    }
+
+    /// i.e. `g_detach()`, after closing the consumer
+    pub fn detach(self, _topo: &Topology) {
+        unsafe { kernel_sys::g_detach(self.as_ptr()) };
+    }
+
+    /// Change the read, write and exclusive access counts by the given
+    /// deltas, opening or closing the provider, i.e. `g_access()`
+    pub fn access(
+        self,
+        _topo: &Topology,
+        dr: c_int,
+        dw: c_int,
+        de: c_int,
+    ) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::g_access(self.as_ptr(), dr, dw, de)
+        })
+    }
+
+    /// Send `bio` to the provider, usually a clone of a request to this
+    /// geom, i.e. `g_io_request()`. `GeomClass::done` is called when it
+    /// is complete.
+    pub fn request(self, bio: Bio) {
+        unsafe {
+            let bp = bio.into_raw();
+            (*bp).bio_done = Some(g_done::<G>);
+            kernel_sys::g_io_request(bp, self.as_ptr());
+        }
+    }
+
+    /// Pass a clone of `bio` on to the provider, failing it if there is no
+    /// memory for one
+    pub fn forward(self, bio: Bio) {
+        match bio.clone_bio() {
+            Some(clone) => self.request(clone),
+            None => bio.deliver(Err(Errno::ENOMEM)),
+        }
+    }
+
+    /// Check that `length` bytes at `offset` make a valid request: not
+    /// empty, at most `maxphys` bytes, and aligned to the sector size
+    fn check_request(self, offset: i64, length: usize) -> Result<(), Errno> {
+        let pp = self.provider().ok_or(Errno::ENXIO)?;
+        let sectorsize = pp.sectorsize() as usize;
+        let maxphys = unsafe { kernel_sys::maxphys } as usize;
+        if sectorsize == 0
+            || length == 0
+            || length > maxphys
+            || length % sectorsize != 0
+            || offset < 0
+            || offset as u64 % sectorsize as u64 != 0
+        {
+            return Err(Errno::EINVAL);
+        }
+        Ok(())
+    }
+
+    /// Read `length` bytes at `offset`, both multiples of the sector size,
+    /// e.g. metadata while tasting, i.e. `g_read_data()`. The consumer
+    /// must be open for reading. Returns `EINVAL` if the request is empty,
+    /// unaligned or longer than `maxphys`.
+    pub fn read_data<C: Sleepable>(
+        self,
+        _ctx: &C,
+        offset: i64,
+        length: usize,
+    ) -> Result<Vec<u8>, Errno> {
+        self.check_request(offset, length)?;
+        let mut error = 0;
+        unsafe {
+            let buf = kernel_sys::g_read_data(
+                self.as_ptr(),
+                offset,
+                length as i64,
+                &mut error,
+            );
+            if buf.is_null() {
+                return Err(match error {
+                    0 => Errno::EIO,
+                    n => Errno::from_raw(n),
+                });
+            }
+            let data = slice::from_raw_parts(buf as *const u8, length).to_vec();
+            // g_free()
+            kernel_sys::free(buf, &mut kernel_sys::M_GEOM[0]);
+            Ok(data)
+        }
+    }
+
+    /// Write `data` at `offset`, both multiples of the sector size, i.e.
+    /// `g_write_data()`. The consumer must be open for writing. Returns
+    /// `EINVAL` like `read_data`.
+    pub fn write_data<C: Sleepable>(
+        self,
+        _ctx: &C,
+        offset: i64,
+        data: &[u8],
+    ) -> Result<(), Errno> {
+        self.check_request(offset, data.len())?;
+        Errno::result(unsafe {
+            kernel_sys::g_write_data(
+                self.as_ptr(),
+                offset,
+                data.as_ptr() as *mut c_void,
+                data.len() as i64,
+            )
+        })
+    }
+}
+
+/// A request from `geom(8)` to a class, i.e. `struct gctl_req`
+pub struct Request(NonNull<gctl_req>);
+
+impl Request {
+    pub fn as_ptr(&self) -> *mut gctl_req {
+        self.0.as_ptr()
+    }
+
+    /// The string argument `name`, e.g. `arg0` for the first name given
+    pub fn get_str(&self, name: &str) -> Option<&str> {
+        let name = cstr_ref!(name);
+        unsafe {
+            c_str(kernel_sys::gctl_get_asciiparam(
+                self.as_ptr(),
+                name.as_ptr() as _,
+            ))
+        }
+    }
+
+    fn get<T: Copy>(&self, name: &str) -> Option<T> {
+        let name = cstr_ref!(name);
+        let size = core::mem::size_of::<T>() as c_int;
+        unsafe {
+            // Reports missing arguments as an error of the request
+            let p = kernel_sys::gctl_get_paraml(
+                self.as_ptr(),
+                name.as_ptr() as _,
+                size,
+            );
+            (p as *const T).as_ref().map(|p| ptr::read_unaligned(p))
+        }
+    }
+
+    /// The integer argument `name`, e.g. `nargs` for the number of names
+    /// given
+    pub fn get_int(&self, name: &str) -> Option<c_int> {
+        self.get(name)
+    }
+
+    /// The `intmax_t` argument `name`, e.g. a size or offset
+    pub fn get_intmax(&self, name: &str) -> Option<i64> {
+        self.get(name)
+    }
+
+    /// Fail the request with `msg`, which `geom(8)` prints, i.e.
+    /// `gctl_error()`. Only the first error is kept.
+    pub fn error(&self, msg: &str) {
+        let fmt = "%s\0".as_ptr() as *const c_char;
+        let msg = cstr_ref!(msg);
+        unsafe { kernel_sys::gctl_error(self.as_ptr(), fmt, msg.as_ptr()) };
+    }
+}
+
+/// A GEOM class, whose geoms each own a value of the implementing type
+///
+/// A transform usually creates a geom with one consumer, attached to the
+/// provider it sits on, and one provider with the same size, whose
+/// requests it passes on through the consumer.
+pub trait GeomClass: Sized + Send + Sync + 'static {
+    /// Look at a new or changed provider, and create a geom on it if it
+    /// belongs to this class, e.g. because of its metadata, i.e.
+    /// `g_taste_t`. By default geoms are only created by `create`.
+    fn taste(
+        _topo: &mut Topology,
+        _class: Class<Self>,
+        _pp: Provider,
+    ) -> Option<Geom<Self>> {
+        None
+    }
+
+    /// Handle the `create` verb of `geom(8)`, reporting failure with
+    /// `Request::error`
+    fn create(_topo: &mut Topology, req: &Request, _class: Class<Self>) {
+        req.error("Unknown verb.");
+    }
+
+    /// Handle a request from `geom(8)`, i.e. `g_ctl_req_t`. By default
+    /// `create` is passed to `create`, and `destroy` destroys the geoms
+    /// named by the arguments.
+    fn ctlreq(
+        topo: &mut Topology,
+        req: &Request,
+        class: Class<Self>,
+        verb: &str,
+    ) {
+        match verb {
+            "create" => Self::create(topo, req, class),
+            "destroy" => {
+                let nargs = match req.get_int("nargs") {
+                    Some(nargs) => nargs,
+                    None => return,
+                };
+                for i in 0..nargs {
+                    let name = match req.get_str(&alloc::format!("arg{}", i)) {
+                        Some(name) => name,
+                        None => return req.error("Missing device name."),
+                    };
+                    let gp = match class.find_geom(topo, name) {
+                        Some(gp) => gp,
+                        None => return req.error("Device is invalid."),
+                    };
+                    // Not tied to the borrow of `topo`, like in the methods
+                    let result = match unsafe { state::<Self>(gp.as_ptr()) } {
+                        Some(state) => state.destroy(topo, gp, Some(req)),
+                        None => Ok(()),
+                    };
+                    if result.is_err() {
+                        return req.error("Cannot destroy device.");
+                    }
+                }
+            }
+            _ => req.error("Unknown verb."),
+        }
+    }
+
+    /// Destroy the geom, i.e. `g_ctl_destroy_geom_t`, without a request
+    /// when the class is unloaded. By default this fails with `EBUSY` if
+    /// a provider is open, and withers the geom otherwise.
+    fn destroy(
+        &self,
+        topo: &mut Topology,
+        geom: Geom<Self>,
+        _req: Option<&Request>,
+    ) -> Result<(), Errno> {
+        if geom.providers().any(Provider::is_open) {
+            return Err(Errno::EBUSY);
+        }
+        geom.wither(topo, Errno::ENXIO);
+        Ok(())
+    }
+
+    /// Handle a request to a provider of the geom, completing it with
+    /// `Bio::deliver` or passing it on with `Consumer::forward`, i.e.
+    /// `g_start_t`. This may be called concurrently and must not sleep.
+    fn start(&self, geom: Geom<Self>, bio: Bio);
+
+    /// A request sent with `Consumer::request` is complete, e.g. to
+    /// decrypt data read. By default the request it was cloned from is
+    /// completed with `Bio::std_done`.
+    fn done(&self, bio: Bio) {
+        bio.std_done();
+    }
+
+    /// The provider `cp` is attached to is going away, or its contents
+    /// changed behind our back, i.e. `g_orphan_t` and `g_spoiled_t`. By
+    /// default the geom is withered.
+    fn orphan(&self, topo: &mut Topology, cp: Consumer<Self>) {
+        cp.geom().wither(topo, Errno::ENXIO);
+    }
+
+    /// Change the access counts of `pp` by the given deltas, i.e.
+    /// `g_access_t`. By default they are passed on to the first consumer.
+    fn access(
+        &self,
+        topo: &mut Topology,
+        geom: Geom<Self>,
+        _pp: Provider,
+        dr: c_int,
+        dw: c_int,
+        de: c_int,
+    ) -> Result<(), Errno> {
+        match geom.consumer() {
+            Some(cp) => cp.access(topo, dr, dw, de),
+            None => Ok(()),
+        }
+    }
+}
+
+unsafe fn state<'a, G: GeomClass>(gp: *mut g_geom) -> Option<&'a G> {
+    ((*gp).softc as *const G).as_ref()
+}
+
+/// Detach the state from a geom that is going away, dropping it once the
+/// methods that may still be running on the event thread are done
+unsafe fn release_state<G: GeomClass>(gp: *mut g_geom) {
+    let state = (*gp).softc;
+    if state.is_null() {
+        return;
+    }
+    (*gp).softc = ptr::null_mut();
+    kernel_sys::g_post_event(
+        Some(drop_state::<G>),
+        state,
+        kernel_sys::M_WAITOK,
+        ptr::null_mut::<c_void>(),
+    );
+}
+
+unsafe extern "C" fn drop_state<G: GeomClass>(
+    state: *mut c_void,
+    _flag: c_int,
+) {
+    drop(Box::from_raw(state as *mut G));
+}
+
+unsafe extern "C" fn g_taste<G: GeomClass>(
+    mp: *mut g_class,
+    pp: *mut g_provider,
+    _flags: c_int,
+) -> *mut g_geom {
+    let mut topo = Topology(PhantomData);
+    let class = Class(NonNull::new_unchecked(mp), PhantomData);
+    let pp = Provider(NonNull::new_unchecked(pp));
+    match G::taste(&mut topo, class, pp) {
+        Some(gp) => gp.as_ptr(),
+        None => ptr::null_mut(),
+    }
+}
+
+unsafe extern "C" fn g_ctlreq<G: GeomClass>(
+    req: *mut gctl_req,
+    mp: *mut g_class,
+    verb: *const c_char,
+) {
+    let mut topo = Topology(PhantomData);
+    let req = Request(NonNull::new_unchecked(req));
+    let class = Class(NonNull::new_unchecked(mp), PhantomData);
+    G::ctlreq(&mut topo, &req, class, c_str(verb).unwrap_or(""));
+}
+
+unsafe extern "C" fn g_destroy_geom<G: GeomClass>(
+    req: *mut gctl_req,
+    _mp: *mut g_class,
+    gp: *mut g_geom,
+) -> c_int {
+    let mut topo = Topology(PhantomData);
+    let req = NonNull::new(req).map(Request);
+    match state::<G>(gp) {
+        Some(state) => Errno::to_ret(state.destroy(
+            &mut topo,
+            Geom::from_ptr(gp),
+            req.as_ref(),
+        )),
+        // Already withering
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn g_start<G: GeomClass>(bp: *mut kernel_sys::bio) {
+    let gp = (*(*bp).bio_to).geom;
+    let bio = match Bio::from_raw(bp) {
+        Some(bio) => bio,
+        None => return,
+    };
+    match state::<G>(gp) {
+        Some(state) => state.start(Geom::from_ptr(gp), bio),
+        None => bio.deliver(Err(Errno::ENXIO)),
+    }
+}
+
+unsafe extern "C" fn g_done<G: GeomClass>(bp: *mut kernel_sys::bio) {
+    let gp = (*(*bp).bio_from).geom;
+    let bio = match Bio::from_raw(bp) {
+        Some(bio) => bio,
+        None => return,
+    };
+    match state::<G>(gp) {
+        Some(state) => state.done(bio),
+        None => bio.std_done(),
+    }
+}
+
+unsafe extern "C" fn g_orphan<G: GeomClass>(cp: *mut g_consumer) {
+    let mut topo = Topology(PhantomData);
+    if let Some(state) = state::<G>((*cp).geom) {
+        state.orphan(&mut topo, Consumer::from_ptr(cp));
+    }
+}
+
+unsafe extern "C" fn g_access<G: GeomClass>(
+    pp: *mut g_provider,
+    dr: c_int,
+    dw: c_int,
+    de: c_int,
+) -> c_int {
+    let mut topo = Topology(PhantomData);
+    let gp = (*pp).geom;
+    let pp = Provider(NonNull::new_unchecked(pp));
+    match state::<G>(gp) {
+        Some(state) => Errno::to_ret(state.access(
+            &mut topo,
+            Geom::from_ptr(gp),
+            pp,
+            dr,
+            dw,
+            de,
+        )),
+        // Only allow closing once withered
+        None if dr <= 0 && dw <= 0 && de <= 0 => 0,
+        None => Errno::ENXIO.raw(),
+    }
+}
+
+unsafe extern "C" fn g_providergone<G: GeomClass>(pp: *mut g_provider) {
+    let gp = Geom::<G>::from_ptr((*pp).geom);
+    // Already removed from the list of the geom
+    if gp.is_withering() && gp.providers().next().is_none() {
+        release_state::<G>(gp.as_ptr());
+    }
+}
+
+/// The `g_class` of `G` named `name`, a null-terminated string
+pub const fn g_class<G: GeomClass>(name: &'static str) -> g_class {
+    g_class {
+        name: name.as_ptr() as *const c_char,
+        version: kernel_sys::G_VERSION as u32,
+        spare0: 0,
+        taste: Some(g_taste::<G>),
+        ctlreq: Some(g_ctlreq::<G>),
+        init: None,
+        fini: None,
+        destroy_geom: Some(g_destroy_geom::<G>),
+        start: Some(g_start::<G>),
+        spoiled: Some(g_orphan::<G>),
+        attrchanged: None,
+        dumpconf: None,
+        access: Some(g_access::<G>),
+        orphan: Some(g_orphan::<G>),
+        ioctl: None,
+        providergone: Some(g_providergone::<G>),
+        resize: None,
+        spare1: ptr::null_mut(),
+        spare2: ptr::null_mut(),
+        class: kernel_sys::g_class__bindgen_ty_1 {
+            le_next: ptr::null_mut(),
+            le_prev: ptr::null_mut(),
+        },
+        geom: kernel_sys::g_class__bindgen_ty_2 {
+            lh_first: ptr::null_mut(),
+        },
+    }
+}
+
+/// Register `$geom`, a `GeomClass`, as the GEOM class named `$class`, e.g.
+/// `"NOP"`, i.e. `DECLARE_GEOM_CLASS()`. Like in C, the module is named
+/// `g_$name`.
+///
+/// ```rust,ignore
+/// bsd_kernel::geom_class!(nop, "NOP", Nop);
+/// bsd_kernel::module_version!(g_nop, 1);
+/// ```
+#[macro_export]
+macro_rules! geom_class {
+    ($name:ident, $class:literal, $geom:ty) => {
+        const _: () = {
+            // The kernel links this into its list of classes
+            static CLASS: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::g_class,
+            > = $crate::linker_set::StaticMut::new($crate::geom::g_class::<
+                $geom,
+            >($crate::cstr!(
+                $class
+            )));
+
+            static MODULE_DATA: $crate::linker_set::Static<
+                $crate::kernel_sys::moduledata_t,
+            > = $crate::linker_set::Static($crate::kernel_sys::moduledata_t {
+                name: $crate::cstr!(concat!("g_", stringify!($name))).as_ptr()
+                    as *const $crate::libc::c_char,
+                evhand: Some($crate::kernel_sys::g_modevent),
+                priv_: CLASS.get() as *mut $crate::libc::c_void,
+            });
+
+            $crate::__declare_module!(
+                concat!("g_", stringify!($name)),
+                MODULE_DATA,
+                $crate::sysinit::Subsystem::Drivers,
+                $crate::sysinit::Order::Second
+            );
+        };
+    };
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use crate::c_str;
+use crate::error::Errno;
+use core::ptr::NonNull;
+use core::slice;
+use kernel_sys::bio;
+use libc::c_int;
+
+/// The command of a `Bio`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BioCmd {
+    Read,
+    Write,
+    /// Free the range, e.g. TRIM
+    Delete,
+    /// Look up the attribute named by `Bio::attribute`
+    GetAttr,
+    /// Make previous writes stable
+    Flush,
+    Other(u16),
+}
+
+impl From<u16> for BioCmd {
+    fn from(cmd: u16) -> BioCmd {
+        match c_int::from(cmd) {
+            kernel_sys::BIO_READ => BioCmd::Read,
+            kernel_sys::BIO_WRITE => BioCmd::Write,
+            kernel_sys::BIO_DELETE => BioCmd::Delete,
+            kernel_sys::BIO_GETATTR => BioCmd::GetAttr,
+            kernel_sys::BIO_FLUSH => BioCmd::Flush,
+            _ => BioCmd::Other(cmd),
+        }
+    }
+}
+
+/// An I/O request, i.e. `struct bio`
+///
+/// A `Bio` passed to a driver must be completed exactly once, by
+/// `deliver` or `std_done` in a GEOM class and by `biodone` in a disk
+/// driver. Dropping it leaves the request hanging.
+#[derive(Debug)]
+#[must_use = "the request must be completed"]
+pub struct Bio(NonNull<bio>);
+
+impl Bio {
+    /// # Safety
+    /// `bp` must be a valid request that the caller is responsible for
+    /// completing, which the `Bio` takes over.
+    pub unsafe fn from_raw(bp: *mut bio) -> Option<Bio> {
+        NonNull::new(bp).map(Bio)
+    }
+
+    pub fn as_ptr(&self) -> *mut bio {
+        self.0.as_ptr()
+    }
+
+    /// Give up the request without completing it
+    pub fn into_raw(self) -> *mut bio {
+        self.0.as_ptr()
+    }
+
+    pub fn cmd(&self) -> BioCmd {
+        BioCmd::from(unsafe { (*self.as_ptr()).bio_cmd })
+    }
+
+    /// The byte offset the request starts at
+    pub fn offset(&self) -> i64 {
+        unsafe { (*self.as_ptr()).bio_offset }
+    }
+
+    /// The number of bytes requested
+    pub fn length(&self) -> usize {
+        unsafe { (*self.as_ptr()).bio_length as usize }
+    }
+
+    /// The name of the attribute for `BioCmd::GetAttr`
+    pub fn attribute(&self) -> Option<&str> {
+        unsafe { c_str((*self.as_ptr()).bio_attribute) }
+    }
+
+    /// Whether the data is only given as pages, which are not mapped into
+    /// the kernel address space, i.e. `BIO_UNMAPPED`
+    pub fn is_unmapped(&self) -> bool {
+        let flags = unsafe { (*self.as_ptr()).bio_flags };
+        c_int::from(flags) & kernel_sys::BIO_UNMAPPED != 0
+    }
+
+    /// The data to write, or buffer to read into, of `length()` bytes.
+    /// `None` if the request has no mapped data.
+    pub fn data(&self) -> Option<&[u8]> {
+        let data = unsafe { (*self.as_ptr()).bio_data };
+        if data.is_null() || self.is_unmapped() {
+            return None;
+        }
+        Some(unsafe { slice::from_raw_parts(data as *const u8, self.length()) })
+    }
+
+    pub fn data_mut(&mut self) -> Option<&mut [u8]> {
+        let data = unsafe { (*self.as_ptr()).bio_data };
+        if data.is_null() || self.is_unmapped() {
+            return None;
+        }
+        Some(unsafe {
+            slice::from_raw_parts_mut(data as *mut u8, self.length())
+        })
+    }
+
+    /// Use `length()` bytes at `data` as the buffer of the request, e.g.
+    /// for a clone whose data is transformed on the way down
+    ///
+    /// # Safety
+    /// `data` must stay valid until the request is completed, after which
+    /// the caller is responsible for freeing it.
+    pub unsafe fn set_data(&mut self, data: *mut u8) {
+        (*self.as_ptr()).bio_data = data as _;
+        (*self.as_ptr()).bio_flags &= !(kernel_sys::BIO_UNMAPPED as u16);
+    }
+
+    /// The number of bytes transferred
+    pub fn completed(&self) -> usize {
+        unsafe { (*self.as_ptr()).bio_completed as usize }
+    }
+
+    pub fn set_completed(&mut self, completed: usize) {
+        unsafe { (*self.as_ptr()).bio_completed = completed as _ };
+    }
+
+    /// The number of bytes not transferred by a disk driver
+    pub fn resid(&self) -> usize {
+        unsafe { (*self.as_ptr()).bio_resid as usize }
+    }
+
+    pub fn set_resid(&mut self, resid: usize) {
+        unsafe { (*self.as_ptr()).bio_resid = resid as _ };
+    }
+
+    pub fn error(&self) -> Option<Errno> {
+        match unsafe { (*self.as_ptr()).bio_error } {
+            0 => None,
+            n => Some(Errno::from_raw(n)),
+        }
+    }
+
+    /// A copy of the request to pass on to a consumer, i.e.
+    /// `g_clone_bio()`. It completes this one when completed by
+    /// `std_done`. Returns `None` if no memory is available.
+    pub fn clone_bio(&self) -> Option<Bio> {
+        unsafe { Bio::from_raw(kernel_sys::g_clone_bio(self.as_ptr())) }
+    }
+
+    /// Complete a request sent to a provider, i.e. `g_io_deliver()`. On
+    /// success, `completed()` should be set to the number of bytes
+    /// transferred first.
+    pub fn deliver(self, result: Result<(), Errno>) {
+        unsafe {
+            kernel_sys::g_io_deliver(self.into_raw(), Errno::to_ret(result))
+        };
+    }
+
+    /// Complete a request passed to a disk driver, i.e. `biodone()`. On
+    /// success, `resid()` should be set first if not all of `length()`
+    /// was transferred. On failure, nothing was.
+    pub fn biodone(self, result: Result<(), Errno>) {
+        let bp = self.into_raw();
+        unsafe {
+            if let Err(e) = result {
+                (*bp).bio_error = e.raw();
+                (*bp).bio_flags |= kernel_sys::BIO_ERROR as u16;
+                (*bp).bio_resid = (*bp).bio_bcount;
+            }
+            kernel_sys::biodone(bp);
+        }
+    }
+
+    /// Complete a clone, passing its result on to the request it was
+    /// cloned from and freeing it, i.e. `g_std_done()`
+    pub fn std_done(self) {
+        unsafe { kernel_sys::g_std_done(self.into_raw()) };
+    }
+}
+
+unsafe impl Send for Bio {}
@@ -0,0 +1,321 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+use super::Bio;
+use crate::allocator::{box_waitok, Sleepable};
+use crate::console::{self, Priority, Target};
+use crate::error::Errno;
+use crate::{cstr_ref, mtx_lock, mtx_unlock};
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::cell::UnsafeCell;
+use core::mem::{self, ManuallyDrop};
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicBool, Ordering};
+use kernel_sys::{disk, mtx};
+use libc::{c_char, c_int, c_void};
+
+/// The disk supports `BioCmd::Delete`
+pub const DISKFLAG_CANDELETE: u32 = kernel_sys::DISKFLAG_CANDELETE as u32;
+/// The disk supports `BioCmd::Flush`
+pub const DISKFLAG_CANFLUSHCACHE: u32 =
+    kernel_sys::DISKFLAG_CANFLUSHCACHE as u32;
+
+/// The driver of a `Disk`
+pub trait DiskDriver: Send + Sync + Sized + 'static {
+    /// Handle a request, completing it with `Bio::biodone`, i.e.
+    /// `disk_strategy_t`. This may be called concurrently and must not
+    /// sleep.
+    fn strategy(&self, bio: Bio);
+
+    /// The disk is being opened, i.e. `disk_open_t`
+    fn open(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// The disk was closed, i.e. `disk_close_t`
+    fn close(&self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// A simple block device, see `disk(9)`, whose settings can be changed
+/// until `create` makes it available as `/dev/<name><unit>`. Requests
+/// are passed to the driver of type `T`.
+pub struct Disk<T: DiskDriver> {
+    dp: NonNull<disk>,
+    shared: NonNull<Shared<T>>,
+}
+
+/// A disk made available by `Disk::create`, which must be removed with
+/// `destroy`
+///
+/// The kernel calls into the driver until then, so dropping it instead is
+/// a bug: the disk is withdrawn without waiting for it to be closed, and
+/// the driver is leaked, but requests that are still running may call
+/// code of a module that has been unloaded.
+pub struct CreatedDisk<T: DiskDriver> {
+    disk: ManuallyDrop<Disk<T>>,
+}
+
+/// The part of a `Disk` used by the kernel, through `d_drv1`
+struct Shared<T> {
+    /// Protects `gone`
+    lock: UnsafeCell<mtx>,
+    gone: AtomicBool,
+    open: AtomicBool,
+    name: String,
+    handler: T,
+}
+
+unsafe impl<T: DiskDriver> Send for Disk<T> {}
+unsafe impl<T: DiskDriver> Sync for Disk<T> {}
+
+impl<T: DiskDriver> Disk<T> {
+    /// A disk of `mediasize` bytes in sectors of `sectorsize` bytes, i.e.
+    /// `disk_alloc()`. Requests are at most `DFLTPHYS` bytes long unless
+    /// changed with `set_maxsize`.
+    pub fn new<C: Sleepable>(
+        ctx: &C,
+        name: &str,
+        unit: u32,
+        sectorsize: u32,
+        mediasize: i64,
+        handler: T,
+    ) -> Disk<T> {
+        let dp = unsafe { NonNull::new_unchecked(kernel_sys::disk_alloc()) };
+        // Freed by `Drop` or `CreatedDisk::destroy`
+        let shared = Box::leak(box_waitok(
+            ctx,
+            Shared {
+                lock: UnsafeCell::new(unsafe { mem::zeroed() }),
+                gone: AtomicBool::new(false),
+                open: AtomicBool::new(false),
+                name: cstr_ref!(name).clone(),
+                handler,
+            },
+        ));
+        unsafe {
+            // mtx_init()
+            kernel_sys::_mtx_init(
+                &mut (*shared.lock.get()).mtx_lock,
+                "disk gone\0".as_ptr() as *const c_char,
+                ptr::null(),
+                kernel_sys::MTX_DEF,
+            );
+            let dp = dp.as_ptr();
+            (*dp).d_name = shared.name.as_ptr() as *const c_char;
+            (*dp).d_unit = unit;
+            (*dp).d_sectorsize = sectorsize;
+            (*dp).d_mediasize = mediasize;
+            (*dp).d_maxsize = kernel_sys::DFLTPHYS as u32;
+            (*dp).d_open = Some(d_open::<T>);
+            (*dp).d_close = Some(d_close::<T>);
+            (*dp).d_strategy = Some(d_strategy::<T>);
+            (*dp).d_gone = Some(d_gone::<T>);
+            (*dp).d_drv1 = shared as *mut Shared<T> as *mut c_void;
+        }
+        Disk {
+            dp,
+            shared: NonNull::from(shared),
+        }
+    }
+
+    pub fn as_ptr(&self) -> *mut disk {
+        self.dp.as_ptr()
+    }
+
+    fn shared(&self) -> &Shared<T> {
+        unsafe { self.shared.as_ref() }
+    }
+
+    pub fn handler(&self) -> &T {
+        &self.shared().handler
+    }
+
+    /// Set the largest request passed to the driver in bytes
+    pub fn set_maxsize(&mut self, maxsize: u32) {
+        unsafe { (*self.as_ptr()).d_maxsize = maxsize };
+    }
+
+    /// Set the `DISKFLAG_*` flags, e.g. `DISKFLAG_CANDELETE`
+    pub fn set_flags(&mut self, flags: u32) {
+        unsafe { (*self.as_ptr()).d_flags = flags };
+    }
+
+    /// Set the preferred alignment of requests, e.g. the physical sector
+    /// size
+    pub fn set_stripe(&mut self, size: u32, offset: u32) {
+        unsafe {
+            (*self.as_ptr()).d_stripesize = size as _;
+            (*self.as_ptr()).d_stripeoffset = offset as _;
+        }
+    }
+
+    /// Make the disk available, i.e. `disk_create()`. The settings cannot
+    /// be changed afterwards, except for the size with
+    /// `CreatedDisk::resize`.
+    pub fn create(self) -> CreatedDisk<T> {
+        let version = kernel_sys::DISK_VERSION as c_int;
+        unsafe { kernel_sys::disk_create(self.as_ptr(), version) };
+        CreatedDisk {
+            disk: ManuallyDrop::new(self),
+        }
+    }
+
+    /// Free the state shared with the kernel
+    unsafe fn free_shared(&mut self) {
+        let shared = Box::from_raw(self.shared.as_ptr());
+        // mtx_destroy()
+        kernel_sys::_mtx_destroy(&mut (*shared.lock.get()).mtx_lock);
+    }
+}
+
+impl<T: DiskDriver> Drop for Disk<T> {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::disk_destroy(self.as_ptr());
+            self.free_shared();
+        }
+    }
+}
+
+impl<T: DiskDriver> CreatedDisk<T> {
+    pub fn as_ptr(&self) -> *mut disk {
+        self.disk.as_ptr()
+    }
+
+    pub fn handler(&self) -> &T {
+        self.disk.handler()
+    }
+
+    /// Change the size of the disk, i.e. `disk_resize()`
+    pub fn resize<C: Sleepable>(
+        &self,
+        _ctx: &C,
+        mediasize: i64,
+    ) -> Result<(), Errno> {
+        unsafe {
+            (*self.as_ptr()).d_mediasize = mediasize;
+            Errno::result(kernel_sys::disk_resize(
+                self.as_ptr(),
+                kernel_sys::M_WAITOK,
+            ))
+        }
+    }
+
+    /// Whether the disk is open, e.g. by a mounted file system. A module
+    /// should refuse to unload with `EBUSY` then, rather than wait for it
+    /// to be closed in `destroy`.
+    pub fn is_open(&self) -> bool {
+        self.disk.shared().open.load(Ordering::Acquire)
+    }
+
+    /// Remove the disk, i.e. `disk_gone()` and `disk_destroy()`. New
+    /// requests fail, and this waits until the disk is closed and the
+    /// last request is done, however long that takes.
+    pub fn destroy<C: Sleepable>(self, _ctx: &C) {
+        let mut this = ManuallyDrop::new(self);
+        let dp = this.as_ptr();
+        let shared = this.disk.shared();
+        let lock = shared.lock.get();
+        unsafe {
+            // d_gone is called once the disk is closed and idle
+            kernel_sys::disk_gone(dp);
+            mtx_lock(lock);
+            while !shared.gone.load(Ordering::Acquire) {
+                kernel_sys::_sleep(
+                    &shared.gone as *const AtomicBool as *mut c_void,
+                    &mut (*lock).lock_object,
+                    kernel_sys::PRIBIO as c_int,
+                    "dgone\0".as_ptr() as *const c_char,
+                    0,
+                    0,
+                    0,
+                );
+            }
+            mtx_unlock(lock);
+            kernel_sys::disk_destroy(dp);
+            this.disk.free_shared();
+        }
+    }
+}
+
+impl<T: DiskDriver> Drop for CreatedDisk<T> {
+    fn drop(&mut self) {
+        console::print(
+            Target::Log(Priority::Crit),
+            format_args!(
+                "{}: disk dropped without destroy, leaking it\n",
+                self.disk.shared().name.trim_end_matches('\0')
+            ),
+        );
+        let dp = self.as_ptr();
+        unsafe {
+            // Waiting for the disk to go away needs `destroy`. Withdraw it
+            // without waiting, and leave the driver to requests that may
+            // still be running.
+            (*dp).d_gone = None;
+            kernel_sys::disk_gone(dp);
+        }
+    }
+}
+
+unsafe impl<T: DiskDriver> Send for CreatedDisk<T> {}
+unsafe impl<T: DiskDriver> Sync for CreatedDisk<T> {}
+
+unsafe fn driver<'a, T: DiskDriver>(dp: *mut disk) -> &'a Shared<T> {
+    &*((*dp).d_drv1 as *const Shared<T>)
+}
+
+unsafe extern "C" fn d_open<T: DiskDriver>(dp: *mut disk) -> c_int {
+    let sc = driver::<T>(dp);
+    let result = sc.handler.open();
+    if result.is_ok() {
+        sc.open.store(true, Ordering::Release);
+    }
+    Errno::to_ret(result)
+}
+
+unsafe extern "C" fn d_close<T: DiskDriver>(dp: *mut disk) -> c_int {
+    let sc = driver::<T>(dp);
+    // The disk is closed even if this fails
+    sc.open.store(false, Ordering::Release);
+    Errno::to_ret(sc.handler.close())
+}
+
+unsafe extern "C" fn d_strategy<T: DiskDriver>(bp: *mut kernel_sys::bio) {
+    let sc = driver::<T>((*bp).bio_disk);
+    if let Some(bio) = Bio::from_raw(bp) {
+        sc.handler.strategy(bio);
+    }
+}
+
+unsafe extern "C" fn d_gone<T: DiskDriver>(dp: *mut disk) {
+    let sc = driver::<T>(dp);
+    let lock = sc.lock.get();
+    mtx_lock(lock);
+    sc.gone.store(true, Ordering::Release);
+    kernel_sys::wakeup(&sc.gone as *const AtomicBool as *const c_void);
+    mtx_unlock(lock);
+}
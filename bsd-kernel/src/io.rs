@@ -23,43 +23,18 @@
 //
 // Based on public domain code by Johannes Lundberg
 
-use crate::cstr;
 use alloc::string::String;
 use alloc::vec::Vec;
-use core::{fmt, ptr};
-use libc::{c_char, c_void};
-
-/// Empty structure that uses libcore's `fmt::Write` trait to provide
-/// support for writing formatted arguments lists (as generated by the
-/// built-in `format_args!()` macro`)
-pub struct KernelDebugWriter {}
-
-impl fmt::Write for KernelDebugWriter {
-    fn write_str(&mut self, message: &str) -> fmt::Result {
-        unsafe {
-            let ptr = kernel_sys::malloc(
-                message.len() + 1,
-                &mut kernel_sys::M_DEVBUF[0],
-                kernel_sys::M_WAITOK,
-            ) as *mut c_char;
-            if ptr.is_null() {
-                let msg =
-                    cstr!("Failed to allocate memory for dynamic printf()\n");
-                let ptr = msg.as_ptr() as *const c_char;
-                kernel_sys::uprintf(ptr);
-            } else {
-                ptr::copy(message.as_ptr(), ptr as *mut u8, message.len());
-                ptr::write(ptr.add(message.len()), 0);
-                kernel_sys::uprintf(ptr);
-                kernel_sys::free(
-                    ptr as *mut c_void,
-                    &mut kernel_sys::M_DEVBUF[0],
-                );
-            }
-        }
-        Ok(())
-    }
-}
+use core::{cmp, fmt};
+
+pub use self::buffered::{BufReader, BufWriter, IntoInnerError};
+pub use self::cursor::Cursor;
+pub use self::util::copy;
+
+mod buffered;
+mod cursor;
+mod impls;
+mod util;
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -237,6 +212,24 @@ pub trait Read {
     {
         self
     }
+    /// Creates an adaptor which reads at most `limit` bytes from this reader
+    fn take(self, limit: u64) -> Take<Self>
+    where
+        Self: Sized,
+    {
+        Take { inner: self, limit }
+    }
+    /// Creates an adaptor which reads all of this reader, then all of `next`
+    fn chain<R: Read>(self, next: R) -> Chain<Self, R>
+    where
+        Self: Sized,
+    {
+        Chain {
+            first: self,
+            second: next,
+            done_first: false,
+        }
+    }
 }
 
 pub trait Write {
@@ -301,3 +294,548 @@ pub trait Write {
         self
     }
 }
+
+fn read_until<R: BufRead + ?Sized>(
+    r: &mut R,
+    delim: u8,
+    buf: &mut Vec<u8>,
+) -> Result<usize> {
+    let mut read = 0;
+    loop {
+        let (done, used) = {
+            let available = match r.fill_buf() {
+                Ok(n) => n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            match available.iter().position(|&b| b == delim) {
+                Some(i) => {
+                    buf.extend_from_slice(&available[..=i]);
+                    (true, i + 1)
+                }
+                None => {
+                    buf.extend_from_slice(available);
+                    (false, available.len())
+                }
+            }
+        };
+        r.consume(used);
+        read += used;
+        if done || used == 0 {
+            return Ok(read);
+        }
+    }
+}
+
+/// A `Read` type with an internal buffer, allowing it to perform extra
+/// ways of reading such as line by line
+pub trait BufRead: Read {
+    /// Returns the contents of the internal buffer, filling it with more
+    /// data from the inner reader if it is empty. An empty slice means EOF.
+    fn fill_buf(&mut self) -> Result<&[u8]>;
+    /// Marks `amt` bytes of the buffer as consumed so they are not
+    /// returned by subsequent reads
+    fn consume(&mut self, amt: usize);
+    /// Reads all bytes into `buf` until `byte` or EOF is reached. The
+    /// delimiter, if found, is appended to `buf`.
+    fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> Result<usize> {
+        read_until(self, byte, buf)
+    }
+    /// Reads all bytes until a newline (`0xA`) is reached and appends them
+    /// to `buf`, including the newline. `buf` is left unchanged if the
+    /// data read is not valid UTF-8.
+    fn read_line(&mut self, buf: &mut String) -> Result<usize> {
+        append_to_string(buf, |b| read_until(self, b'\n', b))
+    }
+    /// Returns an iterator over the contents of this reader split on
+    /// `byte`. The delimiter is not included in the yielded buffers.
+    fn split(self, byte: u8) -> Split<Self>
+    where
+        Self: Sized,
+    {
+        Split {
+            buf: self,
+            delim: byte,
+        }
+    }
+    /// Returns an iterator over the lines of this reader. Lines are
+    /// terminated by `\n` or `\r\n`, which is stripped from the result.
+    fn lines(self) -> Lines<Self>
+    where
+        Self: Sized,
+    {
+        Lines { buf: self }
+    }
+}
+
+/// Possible ways to seek within an I/O object
+#[derive(Copy, PartialEq, Eq, Clone, Debug)]
+pub enum SeekFrom {
+    /// Set the offset to the provided number of bytes
+    Start(u64),
+    /// Set the offset to the size of the object plus the given number of
+    /// bytes
+    End(i64),
+    /// Set the offset to the current position plus the given number of
+    /// bytes
+    Current(i64),
+}
+
+/// A cursor which can be moved within a stream of bytes
+pub trait Seek {
+    /// Seek to an offset, in bytes, returning the new position from the
+    /// start of the stream
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64>;
+    /// Rewind to the beginning of the stream
+    fn rewind(&mut self) -> Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+    /// Returns the current position from the start of the stream
+    fn stream_position(&mut self) -> Result<u64> {
+        self.seek(SeekFrom::Current(0))
+    }
+}
+
+/// Adaptor that limits the bytes read from an underlying reader, created
+/// by `Read::take`
+#[derive(Debug)]
+pub struct Take<T> {
+    inner: T,
+    limit: u64,
+}
+
+impl<T> Take<T> {
+    /// Returns the number of bytes that can be read before this instance
+    /// returns EOF
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+    pub fn set_limit(&mut self, limit: u64) {
+        self.limit = limit;
+    }
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: Read> Read for Take<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // Don't call into inner reader at all at EOF because it may still
+        // block
+        if self.limit == 0 {
+            return Ok(0);
+        }
+
+        let max = cmp::min(buf.len() as u64, self.limit) as usize;
+        let n = self.inner.read(&mut buf[..max])?;
+        self.limit -= n as u64;
+        Ok(n)
+    }
+}
+
+impl<T: BufRead> BufRead for Take<T> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.limit == 0 {
+            return Ok(&[]);
+        }
+
+        let buf = self.inner.fill_buf()?;
+        let cap = cmp::min(buf.len() as u64, self.limit) as usize;
+        Ok(&buf[..cap])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = cmp::min(amt as u64, self.limit) as usize;
+        self.limit -= amt as u64;
+        self.inner.consume(amt);
+    }
+}
+
+/// Adaptor that chains two readers together, created by `Read::chain`
+#[derive(Debug)]
+pub struct Chain<T, U> {
+    first: T,
+    second: U,
+    done_first: bool,
+}
+
+impl<T, U> Chain<T, U> {
+    pub fn into_inner(self) -> (T, U) {
+        (self.first, self.second)
+    }
+    pub fn get_ref(&self) -> (&T, &U) {
+        (&self.first, &self.second)
+    }
+    pub fn get_mut(&mut self) -> (&mut T, &mut U) {
+        (&mut self.first, &mut self.second)
+    }
+}
+
+impl<T: Read, U: Read> Read for Chain<T, U> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.done_first {
+            match self.first.read(buf)? {
+                0 if !buf.is_empty() => self.done_first = true,
+                n => return Ok(n),
+            }
+        }
+        self.second.read(buf)
+    }
+}
+
+impl<T: BufRead, U: BufRead> BufRead for Chain<T, U> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if !self.done_first {
+            match self.first.fill_buf()? {
+                buf if buf.is_empty() => self.done_first = true,
+                buf => return Ok(buf),
+            }
+        }
+        self.second.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if !self.done_first {
+            self.first.consume(amt)
+        } else {
+            self.second.consume(amt)
+        }
+    }
+}
+
+/// Iterator over the contents of a `BufRead` split on a byte, created by
+/// `BufRead::split`
+#[derive(Debug)]
+pub struct Split<B> {
+    buf: B,
+    delim: u8,
+}
+
+impl<B: BufRead> Iterator for Split<B> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Result<Vec<u8>>> {
+        let mut buf = Vec::new();
+        match self.buf.read_until(self.delim, &mut buf) {
+            Ok(0) => None,
+            Ok(_n) => {
+                if buf[buf.len() - 1] == self.delim {
+                    buf.pop();
+                }
+                Some(Ok(buf))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// Iterator over the lines of a `BufRead`, created by `BufRead::lines`
+#[derive(Debug)]
+pub struct Lines<B> {
+    buf: B,
+}
+
+impl<B: BufRead> Iterator for Lines<B> {
+    type Item = Result<String>;
+
+    fn next(&mut self) -> Option<Result<String>> {
+        let mut buf = String::new();
+        match self.buf.read_line(&mut buf) {
+            Ok(0) => None,
+            Ok(_n) => {
+                if buf.ends_with('\n') {
+                    buf.pop();
+                    if buf.ends_with('\r') {
+                        buf.pop();
+                    }
+                }
+                Some(Ok(buf))
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::{BufRead, ErrorKind, Read, Result, Write};
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use std::io as sio;
+
+    /// The std `ErrorKind` of the same name
+    pub(super) fn std_kind(kind: ErrorKind) -> sio::ErrorKind {
+        match kind {
+            ErrorKind::NotFound => sio::ErrorKind::NotFound,
+            ErrorKind::PermissionDenied => sio::ErrorKind::PermissionDenied,
+            ErrorKind::ConnectionRefused => sio::ErrorKind::ConnectionRefused,
+            ErrorKind::ConnectionReset => sio::ErrorKind::ConnectionReset,
+            ErrorKind::ConnectionAborted => sio::ErrorKind::ConnectionAborted,
+            ErrorKind::NotConnected => sio::ErrorKind::NotConnected,
+            ErrorKind::AddrInUse => sio::ErrorKind::AddrInUse,
+            ErrorKind::AddrNotAvailable => sio::ErrorKind::AddrNotAvailable,
+            ErrorKind::BrokenPipe => sio::ErrorKind::BrokenPipe,
+            ErrorKind::AlreadyExists => sio::ErrorKind::AlreadyExists,
+            ErrorKind::WouldBlock => sio::ErrorKind::WouldBlock,
+            ErrorKind::InvalidInput => sio::ErrorKind::InvalidInput,
+            ErrorKind::InvalidData => sio::ErrorKind::InvalidData,
+            ErrorKind::TimedOut => sio::ErrorKind::TimedOut,
+            ErrorKind::WriteZero => sio::ErrorKind::WriteZero,
+            ErrorKind::Interrupted => sio::ErrorKind::Interrupted,
+            ErrorKind::Other => sio::ErrorKind::Other,
+            ErrorKind::UnexpectedEof => sio::ErrorKind::UnexpectedEof,
+        }
+    }
+
+    /// Check that two results are both `Ok` with the same value, or both
+    /// errors of the same kind
+    pub(super) fn assert_same<T, U>(ours: Result<T>, std: sio::Result<U>)
+    where
+        T: PartialEq<U> + core::fmt::Debug,
+        U: core::fmt::Debug,
+    {
+        match (ours, std) {
+            (Ok(a), Ok(b)) => assert_eq!(a, b),
+            (Err(a), Err(b)) => assert_eq!(std_kind(a.kind()), b.kind()),
+            (a, b) => panic!("{:?} != {:?}", a, b),
+        }
+    }
+
+    /// A reader returning at most 3 bytes per read, implementing both our
+    /// and std's `Read`
+    pub(super) struct Trickle<'a>(pub(super) &'a [u8]);
+
+    impl Trickle<'_> {
+        fn next(&mut self, buf: &mut [u8]) -> usize {
+            let n = buf.len().min(self.0.len()).min(3);
+            buf[..n].copy_from_slice(&self.0[..n]);
+            self.0 = &self.0[n..];
+            n
+        }
+    }
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            Ok(self.next(buf))
+        }
+    }
+
+    impl sio::Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> sio::Result<usize> {
+            Ok(self.next(buf))
+        }
+    }
+
+    /// A writer that accepts at most `limit` bytes, then writes 0,
+    /// implementing both our and std's `Write`
+    #[derive(Debug)]
+    pub(super) struct Limited {
+        pub(super) data: Vec<u8>,
+        pub(super) limit: usize,
+    }
+
+    impl Limited {
+        pub(super) fn new(limit: usize) -> Limited {
+            Limited {
+                data: Vec::new(),
+                limit,
+            }
+        }
+
+        fn put(&mut self, buf: &[u8]) -> usize {
+            let n = buf.len().min(self.limit - self.data.len());
+            self.data.extend_from_slice(&buf[..n]);
+            n
+        }
+    }
+
+    impl Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            Ok(self.put(buf))
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl sio::Write for Limited {
+        fn write(&mut self, buf: &[u8]) -> sio::Result<usize> {
+            Ok(self.put(buf))
+        }
+
+        fn flush(&mut self) -> sio::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn read_exact() {
+        for len in 0..8 {
+            let mut ours = [0; 8];
+            let mut theirs = [0; 8];
+            assert_same(
+                Read::read_exact(&mut Trickle(b"abcde"), &mut ours[..len]),
+                sio::Read::read_exact(
+                    &mut Trickle(b"abcde"),
+                    &mut theirs[..len],
+                ),
+            );
+            if len <= 5 {
+                assert_eq!(ours, theirs);
+            }
+        }
+    }
+
+    #[test]
+    fn write_all() {
+        for limit in [0, 3, 5, 8] {
+            let mut ours = Limited::new(limit);
+            let mut theirs = Limited::new(limit);
+            assert_same(
+                Write::write_all(&mut ours, b"abcde"),
+                sio::Write::write_all(&mut theirs, b"abcde"),
+            );
+            assert_eq!(ours.data, theirs.data);
+        }
+    }
+
+    #[test]
+    fn write_fmt() {
+        let mut ours = Limited::new(4);
+        let mut theirs = Limited::new(4);
+        assert_same(
+            Write::write_fmt(&mut ours, format_args!("{}-{}", 12, 34)),
+            sio::Write::write_fmt(&mut theirs, format_args!("{}-{}", 12, 34)),
+        );
+        assert_eq!(ours.data, theirs.data);
+    }
+
+    #[test]
+    fn read_to_string() {
+        for input in [&b"hello"[..], b"caf\xc3\xa9", b"bad \xff utf-8", b""] {
+            let mut ours = String::from("x");
+            let mut theirs = String::from("x");
+            assert_same(
+                Read::read_to_string(&mut Trickle(input), &mut ours),
+                sio::Read::read_to_string(&mut Trickle(input), &mut theirs),
+            );
+            assert_eq!(ours, theirs);
+        }
+    }
+
+    #[test]
+    fn take() {
+        let mut ours = Read::take(Trickle(b"hello world"), 5);
+        let mut theirs = sio::Read::take(Trickle(b"hello world"), 5);
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        assert_same(
+            ours.read_to_end(&mut a),
+            sio::Read::read_to_end(&mut theirs, &mut b),
+        );
+        assert_eq!(a, b);
+        assert_eq!(ours.limit(), theirs.limit());
+
+        // The rest of the inner reader is still there
+        ours.set_limit(100);
+        theirs.set_limit(100);
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        assert_same(
+            ours.read_to_end(&mut a),
+            sio::Read::read_to_end(&mut theirs, &mut b),
+        );
+        assert_eq!(a, b);
+
+        let ours: Vec<_> = Read::take(&b"a\nbc\nd"[..], 4)
+            .lines()
+            .map(|l| l.unwrap())
+            .collect();
+        let theirs: Vec<_> =
+            sio::BufRead::lines(sio::Read::take(&b"a\nbc\nd"[..], 4))
+                .map(|l| l.unwrap())
+                .collect();
+        assert_eq!(ours, theirs);
+    }
+
+    #[test]
+    fn chain() {
+        let mut ours = Read::chain(Trickle(b"ab"), Trickle(b"cde"));
+        let mut theirs = sio::Read::chain(Trickle(b"ab"), Trickle(b"cde"));
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        assert_same(
+            ours.read_to_end(&mut a),
+            sio::Read::read_to_end(&mut theirs, &mut b),
+        );
+        assert_eq!(a, b);
+
+        // Lines continue across the boundary
+        let ours: Vec<_> = Read::chain(&b"ab"[..], &b"c\nd"[..])
+            .lines()
+            .map(|l| l.unwrap())
+            .collect();
+        let theirs: Vec<_> =
+            sio::BufRead::lines(sio::Read::chain(&b"ab"[..], &b"c\nd"[..]))
+                .map(|l| l.unwrap())
+                .collect();
+        assert_eq!(ours, theirs);
+    }
+
+    #[test]
+    fn lines() {
+        let inputs = [
+            &b"a\nb\r\nc"[..],
+            b"\n\n",
+            b"",
+            b"x\r",
+            b"one\n",
+            b"\r\n\r\n",
+        ];
+        for input in inputs {
+            let ours: Vec<_> =
+                BufRead::lines(input).map(|l| l.unwrap()).collect();
+            let theirs: Vec<_> =
+                sio::BufRead::lines(input).map(|l| l.unwrap()).collect();
+            assert_eq!(ours, theirs, "{:?}", input);
+        }
+
+        let mut ours = BufRead::lines(&b"ok\n\xff\n"[..]);
+        let mut theirs = sio::BufRead::lines(&b"ok\n\xff\n"[..]);
+        assert_same(ours.next().unwrap(), theirs.next().unwrap());
+        assert_same(ours.next().unwrap(), theirs.next().unwrap());
+    }
+
+    #[test]
+    fn split() {
+        for input in [&b"a,b,,c,"[..], b",", b"", b"abc", b",,"] {
+            let ours: Vec<_> =
+                BufRead::split(input, b',').map(|s| s.unwrap()).collect();
+            let theirs: Vec<_> = sio::BufRead::split(input, b',')
+                .map(|s| s.unwrap())
+                .collect();
+            assert_eq!(ours, theirs, "{:?}", input);
+        }
+    }
+
+    #[test]
+    fn read_line() {
+        let mut ours = &b"first\nsecond"[..];
+        let mut theirs = &b"first\nsecond"[..];
+        for _ in 0..3 {
+            let (mut a, mut b) = (String::new(), String::new());
+            assert_same(
+                BufRead::read_line(&mut ours, &mut a),
+                sio::BufRead::read_line(&mut theirs, &mut b),
+            );
+            assert_eq!(a, b);
+        }
+    }
+}
@@ -0,0 +1,457 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Buffering wrappers for I/O traits
+
+use super::DEFAULT_BUF_SIZE;
+use super::{BufRead, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::{cmp, fmt};
+
+/// Adds buffering to any reader, so that many small reads do not each
+/// call into the underlying reader
+pub struct BufReader<R> {
+    inner: R,
+    buf: Box<[u8]>,
+    pos: usize,
+    cap: usize,
+}
+
+impl<R: Read> BufReader<R> {
+    /// Creates a new `BufReader` with a default buffer capacity
+    pub fn new(inner: R) -> BufReader<R> {
+        BufReader::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Creates a new `BufReader` with the specified buffer capacity
+    pub fn with_capacity(capacity: usize, inner: R) -> BufReader<R> {
+        BufReader {
+            inner,
+            buf: vec![0; capacity].into_boxed_slice(),
+            pos: 0,
+            cap: 0,
+        }
+    }
+}
+
+impl<R> BufReader<R> {
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// It is inadvisable to directly read from the underlying reader
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the currently buffered data without filling the buffer
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.cap]
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Unwraps this `BufReader`, returning the underlying reader. Any
+    /// buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn discard_buffer(&mut self) {
+        self.pos = 0;
+        self.cap = 0;
+    }
+}
+
+impl<R: Read> Read for BufReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        // If we don't have any buffered data and we're doing a massive read
+        // (larger than our internal buffer), bypass our internal buffer
+        // entirely.
+        if self.pos == self.cap && buf.len() >= self.buf.len() {
+            self.discard_buffer();
+            return self.inner.read(buf);
+        }
+        let nread = {
+            let mut rem = self.fill_buf()?;
+            rem.read(buf)?
+        };
+        self.consume(nread);
+        Ok(nread)
+    }
+}
+
+impl<R: Read> BufRead for BufReader<R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        // If we've reached the end of our internal buffer then we need to
+        // fetch some more data from the underlying reader.
+        if self.pos >= self.cap {
+            self.cap = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.cap])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = cmp::min(self.pos + amt, self.cap);
+    }
+}
+
+impl<R: Seek> Seek for BufReader<R> {
+    /// Seeking always discards the internal buffer. `SeekFrom::Current`
+    /// is relative to the position of the `BufReader`, not the position
+    /// of the underlying reader.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        let result: u64;
+        if let SeekFrom::Current(n) = pos {
+            let remainder = (self.cap - self.pos) as i64;
+            // it should be safe to assume that remainder fits within an
+            // i64 as the alternative means we managed to allocate 8
+            // exbibytes and that's absurd.
+            if let Some(offset) = n.checked_sub(remainder) {
+                result = self.inner.seek(SeekFrom::Current(offset))?;
+            } else {
+                // seek backwards by our remainder, and then by the offset
+                self.inner.seek(SeekFrom::Current(-remainder))?;
+                self.discard_buffer();
+                result = self.inner.seek(SeekFrom::Current(n))?;
+            }
+        } else {
+            // Seeking with Start/End doesn't care about our buffer length.
+            result = self.inner.seek(pos)?;
+        }
+        self.discard_buffer();
+        Ok(result)
+    }
+}
+
+impl<R: fmt::Debug> fmt::Debug for BufReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BufReader {{ reader: {:?}, buffer: {}/{} }}",
+            self.inner,
+            self.cap - self.pos,
+            self.buf.len()
+        )
+    }
+}
+
+/// Buffers the output of a writer so that many small writes are
+/// coalesced into fewer, larger writes to the underlying writer.
+///
+/// The buffer is flushed when the `BufWriter` is dropped, but any error
+/// raised at that point is ignored. Call `flush` before dropping to
+/// observe write errors.
+pub struct BufWriter<W: Write> {
+    // `None` only after `into_inner` has taken the writer out
+    inner: Option<W>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> BufWriter<W> {
+    /// Creates a new `BufWriter` with a default buffer capacity
+    pub fn new(inner: W) -> BufWriter<W> {
+        BufWriter::with_capacity(DEFAULT_BUF_SIZE, inner)
+    }
+
+    /// Creates a new `BufWriter` with the specified buffer capacity
+    pub fn with_capacity(capacity: usize, inner: W) -> BufWriter<W> {
+        BufWriter {
+            inner: Some(inner),
+            buf: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn flush_buf(&mut self) -> Result<()> {
+        let mut written = 0;
+        let len = self.buf.len();
+        let mut ret = Ok(());
+        while written < len {
+            let r = self.inner.as_mut().unwrap().write(&self.buf[written..]);
+
+            match r {
+                Ok(0) => {
+                    ret = Err(Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    ));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    ret = Err(e);
+                    break;
+                }
+            }
+        }
+        if written > 0 {
+            self.buf.drain(..written);
+        }
+        ret
+    }
+
+    pub fn get_ref(&self) -> &W {
+        self.inner.as_ref().unwrap()
+    }
+
+    /// It is inadvisable to directly write to the underlying writer
+    pub fn get_mut(&mut self) -> &mut W {
+        self.inner.as_mut().unwrap()
+    }
+
+    /// Returns the data waiting to be written
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Flushes the buffer and returns the underlying writer. If flushing
+    /// fails the `BufWriter` is returned inside the error.
+    pub fn into_inner(
+        mut self,
+    ) -> core::result::Result<W, IntoInnerError<BufWriter<W>>> {
+        match self.flush_buf() {
+            Err(e) => Err(IntoInnerError(self, e)),
+            Ok(()) => Ok(self.inner.take().unwrap()),
+        }
+    }
+}
+
+impl<W: Write> Write for BufWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.buf.len() + buf.len() > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.buf.capacity() {
+            self.get_mut().write(buf)
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        if self.buf.len() + buf.len() > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.buf.capacity() {
+            self.get_mut().write_all(buf)
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_buf().and_then(|()| self.get_mut().flush())
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriter<W> {
+    /// Seeking always writes out the internal buffer before seeking
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.flush_buf()?;
+        self.get_mut().seek(pos)
+    }
+}
+
+impl<W: Write + fmt::Debug> fmt::Debug for BufWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BufWriter {{ writer: {:?}, buffer: {}/{} }}",
+            self.inner.as_ref().unwrap(),
+            self.buf.len(),
+            self.buf.capacity()
+        )
+    }
+}
+
+impl<W: Write> Drop for BufWriter<W> {
+    fn drop(&mut self) {
+        if self.inner.is_some() {
+            // dtors should not return errors
+            let _r = self.flush_buf();
+        }
+    }
+}
+
+/// Error returned by `BufWriter::into_inner` which combines the error
+/// that happened while flushing the buffer with the buffered writer
+/// itself, so that no data is lost
+#[derive(Debug)]
+pub struct IntoInnerError<W>(W, Error);
+
+impl<W> IntoInnerError<W> {
+    /// Returns the error which caused the call to `into_inner` to fail
+    pub fn error(&self) -> &Error {
+        &self.1
+    }
+
+    /// Returns the buffered writer instance which generated the error
+    pub fn into_inner(self) -> W {
+        self.0
+    }
+}
+
+impl<W> From<IntoInnerError<W>> for Error {
+    fn from(iie: IntoInnerError<W>) -> Error {
+        iie.1
+    }
+}
+
+impl<W> fmt::Display for IntoInnerError<W> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.error().fmt(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::super::tests::{assert_same, std_kind, Limited, Trickle};
+    use super::{BufReader, BufWriter};
+    use crate::io::{BufRead, Cursor, Read, Seek, SeekFrom, Write};
+    use alloc::vec::Vec;
+    use std::io as sio;
+
+    const DATA: &[u8] = b"the quick brown fox jumps over the lazy dog";
+
+    #[test]
+    fn reader_reads() {
+        for size in [1, 2, 3, 5, 8, 64] {
+            let mut ours = BufReader::with_capacity(4, Trickle(DATA));
+            let mut theirs = sio::BufReader::with_capacity(4, Trickle(DATA));
+            loop {
+                let mut a = [0; 64];
+                let mut b = [0; 64];
+                let n = ours.read(&mut a[..size]).unwrap();
+                let m = sio::Read::read(&mut theirs, &mut b[..size]).unwrap();
+                assert_eq!(n, m);
+                assert_eq!(a, b);
+                assert_eq!(ours.buffer(), theirs.buffer());
+                if n == 0 {
+                    break;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn reader_lines() {
+        let input = &b"one\ntwo\r\n\nthree"[..];
+        let ours: Vec<_> = BufReader::with_capacity(2, Trickle(input))
+            .lines()
+            .map(|l| l.unwrap())
+            .collect();
+        let theirs: Vec<_> = sio::BufRead::lines(
+            sio::BufReader::with_capacity(2, Trickle(input)),
+        )
+        .map(|l| l.unwrap())
+        .collect();
+        assert_eq!(ours, theirs);
+    }
+
+    #[test]
+    fn reader_seek() {
+        let mut ours = BufReader::with_capacity(4, Cursor::new(DATA));
+        let mut theirs =
+            sio::BufReader::with_capacity(4, sio::Cursor::new(DATA));
+        let mut a = [0; 1];
+        let mut b = [0; 1];
+        ours.read_exact(&mut a).unwrap();
+        sio::Read::read_exact(&mut theirs, &mut b).unwrap();
+        assert_eq!(ours.buffer(), theirs.buffer());
+
+        // Relative to the position of the reader, not of the inner cursor
+        for pos in [
+            SeekFrom::Current(0),
+            SeekFrom::Current(2),
+            SeekFrom::Current(-3),
+        ] {
+            let std_pos = match pos {
+                SeekFrom::Current(n) => sio::SeekFrom::Current(n),
+                _ => unreachable!(),
+            };
+            assert_same(ours.seek(pos), sio::Seek::seek(&mut theirs, std_pos));
+            assert_eq!(ours.buffer(), theirs.buffer());
+            ours.read_exact(&mut a).unwrap();
+            sio::Read::read_exact(&mut theirs, &mut b).unwrap();
+            assert_eq!(a, b);
+        }
+        assert_same(
+            ours.seek(SeekFrom::Current(-100)),
+            sio::Seek::seek(&mut theirs, sio::SeekFrom::Current(-100)),
+        );
+    }
+
+    #[test]
+    fn writer_buffers() {
+        let mut ours = BufWriter::with_capacity(4, Vec::new());
+        let mut theirs = sio::BufWriter::with_capacity(4, Vec::new());
+        for chunk in [&b"ab"[..], b"cde", b"f", b"ghijk", b"", b"lm"] {
+            assert_same(
+                ours.write(chunk),
+                sio::Write::write(&mut theirs, chunk),
+            );
+            assert_eq!(ours.get_ref(), theirs.get_ref());
+            assert_eq!(ours.buffer(), theirs.buffer());
+        }
+        assert_eq!(ours.into_inner().unwrap(), theirs.into_inner().unwrap());
+    }
+
+    #[test]
+    fn writer_flushes_on_drop() {
+        let mut out = Vec::new();
+        {
+            let mut w = BufWriter::new(&mut out);
+            w.write_all(b"hello").unwrap();
+        }
+        assert_eq!(out, b"hello");
+    }
+
+    #[test]
+    fn writer_into_inner_error() {
+        let mut ours = BufWriter::with_capacity(8, Limited::new(2));
+        let mut theirs = sio::BufWriter::with_capacity(8, Limited::new(2));
+        ours.write_all(b"abcd").unwrap();
+        sio::Write::write_all(&mut theirs, b"abcd").unwrap();
+
+        let ours = ours.into_inner().unwrap_err();
+        let theirs = theirs.into_inner().unwrap_err();
+        assert_eq!(std_kind(ours.error().kind()), theirs.error().kind());
+        let (ours, theirs) = (ours.into_inner(), theirs.into_inner());
+        assert_eq!(ours.buffer(), theirs.buffer());
+        assert_eq!(ours.get_ref().data, theirs.get_ref().data);
+    }
+}
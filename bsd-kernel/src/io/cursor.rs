@@ -0,0 +1,372 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! In-memory buffer wrapper implementing the I/O traits
+
+use super::{BufRead, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cmp;
+use core::convert::TryFrom;
+
+/// Wraps an in-memory buffer and provides it with a `Seek` implementation.
+///
+/// `Cursor` is typically used with `Vec<u8>`, `&[u8]` or `&mut [u8]` to
+/// allow code written against `Read`/`Write` to operate on kernel
+/// memory as well as on real devices.
+#[derive(Debug, Default, Eq, PartialEq, Clone)]
+pub struct Cursor<T> {
+    inner: T,
+    pos: u64,
+}
+
+impl<T> Cursor<T> {
+    /// Creates a new cursor wrapping the provided buffer, starting at
+    /// position 0
+    pub fn new(inner: T) -> Cursor<T> {
+        Cursor { pos: 0, inner }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Care should be taken to avoid modifying the internal I/O state
+    /// of the underlying value, as it may corrupt this cursor's position
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn position(&self) -> u64 {
+        self.pos
+    }
+
+    pub fn set_position(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+}
+
+impl<T> Cursor<T>
+where
+    T: AsRef<[u8]>,
+{
+    /// Returns the part of the buffer after the current position, which
+    /// is empty if the position is at or past the end
+    pub fn remaining_slice(&self) -> &[u8] {
+        let len = self.pos.min(self.inner.as_ref().len() as u64);
+        &self.inner.as_ref()[(len as usize)..]
+    }
+}
+
+impl<T> Seek for Cursor<T>
+where
+    T: AsRef<[u8]>,
+{
+    fn seek(&mut self, style: SeekFrom) -> Result<u64> {
+        let (base_pos, offset) = match style {
+            SeekFrom::Start(n) => {
+                self.pos = n;
+                return Ok(n);
+            }
+            SeekFrom::End(n) => (self.inner.as_ref().len() as u64, n),
+            SeekFrom::Current(n) => (self.pos, n),
+        };
+        let new_pos = if offset >= 0 {
+            base_pos.checked_add(offset as u64)
+        } else {
+            base_pos.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match new_pos {
+            Some(n) => {
+                self.pos = n;
+                Ok(self.pos)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        Ok(self.pos)
+    }
+}
+
+impl<T> Read for Cursor<T>
+where
+    T: AsRef<[u8]>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = Read::read(&mut self.remaining_slice(), buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        let n = buf.len();
+        Read::read_exact(&mut self.remaining_slice(), buf)?;
+        self.pos += n as u64;
+        Ok(())
+    }
+}
+
+impl<T> BufRead for Cursor<T>
+where
+    T: AsRef<[u8]>,
+{
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        Ok(self.remaining_slice())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+    }
+}
+
+// Non-resizing write implementation
+fn slice_write(
+    pos_mut: &mut u64,
+    slice: &mut [u8],
+    buf: &[u8],
+) -> Result<usize> {
+    let pos = cmp::min(*pos_mut, slice.len() as u64);
+    let amt = (&mut slice[(pos as usize)..]).write(buf)?;
+    *pos_mut += amt as u64;
+    Ok(amt)
+}
+
+// Resizing write implementation
+fn vec_write(
+    pos_mut: &mut u64,
+    vec: &mut Vec<u8>,
+    buf: &[u8],
+) -> Result<usize> {
+    let pos = usize::try_from(*pos_mut).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            "cursor position exceeds maximum possible vector length",
+        )
+    })?;
+    // Make sure the internal buffer is as least as big as where we
+    // currently are
+    let len = vec.len();
+    if len < pos {
+        // use `resize` so that the zero filling is as efficient as possible
+        vec.resize(pos, 0);
+    }
+    // Figure out what bytes will be used to overwrite what's currently
+    // there (left), and what will be appended on the end (right)
+    {
+        let space = vec.len() - pos;
+        let (left, right) = buf.split_at(cmp::min(space, buf.len()));
+        vec[pos..pos + left.len()].copy_from_slice(left);
+        vec.extend_from_slice(right);
+    }
+
+    // Bump us forward
+    *pos_mut = (pos + buf.len()) as u64;
+    Ok(buf.len())
+}
+
+impl Write for Cursor<&mut [u8]> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        slice_write(&mut self.pos, self.inner, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Cursor<&mut Vec<u8>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        vec_write(&mut self.pos, self.inner, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Cursor<Vec<u8>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        vec_write(&mut self.pos, &mut self.inner, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl Write for Cursor<Box<[u8]>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        slice_write(&mut self.pos, &mut self.inner, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::super::tests::assert_same;
+    use super::Cursor;
+    use crate::io::{BufRead, Read, Seek, SeekFrom, Write};
+    use alloc::boxed::Box;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use std::io as sio;
+
+    fn std_seek(pos: SeekFrom) -> sio::SeekFrom {
+        match pos {
+            SeekFrom::Start(n) => sio::SeekFrom::Start(n),
+            SeekFrom::End(n) => sio::SeekFrom::End(n),
+            SeekFrom::Current(n) => sio::SeekFrom::Current(n),
+        }
+    }
+
+    #[test]
+    fn seek_and_read() {
+        let data = [1, 2, 3, 4, 5];
+        let mut ours = Cursor::new(&data[..]);
+        let mut theirs = sio::Cursor::new(&data[..]);
+        let seeks = [
+            SeekFrom::Start(2),
+            SeekFrom::Current(-1),
+            SeekFrom::End(-2),
+            SeekFrom::Current(-10),
+            SeekFrom::End(3),
+            SeekFrom::Start(0),
+        ];
+        for pos in seeks {
+            assert_same(
+                ours.seek(pos),
+                sio::Seek::seek(&mut theirs, std_seek(pos)),
+            );
+            assert_eq!(ours.position(), theirs.position());
+            let mut a = [0; 2];
+            let mut b = [0; 2];
+            assert_same(
+                ours.read(&mut a),
+                sio::Read::read(&mut theirs, &mut b),
+            );
+            assert_eq!(a, b);
+        }
+    }
+
+    #[test]
+    fn read_exact_past_end() {
+        let mut ours = Cursor::new(vec![1, 2, 3]);
+        let mut theirs = sio::Cursor::new(vec![1, 2, 3]);
+        ours.set_position(1);
+        theirs.set_position(1);
+        let mut a = [0; 4];
+        let mut b = [0; 4];
+        assert_same(
+            ours.read_exact(&mut a),
+            sio::Read::read_exact(&mut theirs, &mut b),
+        );
+        assert_eq!(ours.position(), theirs.position());
+        assert_same(
+            ours.read_exact(&mut a[..2]),
+            sio::Read::read_exact(&mut theirs, &mut b[..2]),
+        );
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn buf_read() {
+        let mut ours = Cursor::new(&b"ab\ncd"[..]);
+        let mut theirs = sio::Cursor::new(&b"ab\ncd"[..]);
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        assert_same(
+            ours.read_until(b'\n', &mut a),
+            sio::BufRead::read_until(&mut theirs, b'\n', &mut b),
+        );
+        assert_eq!(a, b);
+        assert_eq!(
+            ours.fill_buf().unwrap(),
+            sio::BufRead::fill_buf(&mut theirs).unwrap()
+        );
+        assert_eq!(ours.position(), theirs.position());
+    }
+
+    #[test]
+    fn write_vec_past_end() {
+        let mut ours = Cursor::new(Vec::new());
+        let mut theirs = sio::Cursor::new(Vec::new());
+        ours.set_position(3);
+        theirs.set_position(3);
+        assert_same(ours.write(b"ab"), sio::Write::write(&mut theirs, b"ab"));
+        ours.set_position(1);
+        theirs.set_position(1);
+        assert_same(
+            ours.write(b"xyzw"),
+            sio::Write::write(&mut theirs, b"xyzw"),
+        );
+        assert_eq!(ours.position(), theirs.position());
+        assert_eq!(ours.get_ref(), theirs.get_ref());
+
+        let mut out = Vec::new();
+        let mut ours = Cursor::new(&mut out);
+        ours.write_all(b"hi").unwrap();
+        assert_eq!(out, b"hi");
+    }
+
+    #[test]
+    fn write_slice_full() {
+        let mut a = [0; 4];
+        let mut b = [0; 4];
+        let mut ours = Cursor::new(&mut a[..]);
+        let mut theirs = sio::Cursor::new(&mut b[..]);
+        assert_same(
+            ours.write(b"abcdef"),
+            sio::Write::write(&mut theirs, b"abcdef"),
+        );
+        assert_same(ours.write(b"g"), sio::Write::write(&mut theirs, b"g"));
+        ours.set_position(2);
+        theirs.set_position(2);
+        assert_same(
+            ours.write_all(b"xyz"),
+            sio::Write::write_all(&mut theirs, b"xyz"),
+        );
+        assert_eq!(a, b);
+
+        let mut ours = Cursor::new(vec![0u8; 3].into_boxed_slice());
+        let mut theirs = sio::Cursor::new(vec![0u8; 3].into_boxed_slice());
+        assert_same(
+            ours.write(b"abcd"),
+            sio::Write::write(&mut theirs, b"abcd"),
+        );
+        let ours: Box<[u8]> = ours.into_inner();
+        assert_eq!(ours, theirs.into_inner());
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Free-standing I/O helper functions
+
+use super::{ErrorKind, Read, Result, Write, DEFAULT_BUF_SIZE};
+
+/// Copies the entire contents of a reader into a writer, returning the
+/// number of bytes copied.
+///
+/// The copy goes through a `DEFAULT_BUF_SIZE` buffer on the stack, so
+/// keep in mind the (small) kernel stack when calling this from deep
+/// call chains.
+pub fn copy<R: ?Sized, W: ?Sized>(reader: &mut R, writer: &mut W) -> Result<u64>
+where
+    R: Read,
+    W: Write,
+{
+    let mut buf = [0; DEFAULT_BUF_SIZE];
+    let mut written = 0;
+    loop {
+        let len = match reader.read(&mut buf) {
+            Ok(0) => return Ok(written),
+            Ok(len) => len,
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..len])?;
+        written += len as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use super::super::tests::{assert_same, Limited, Trickle};
+    use super::copy;
+    use alloc::vec::Vec;
+    use std::io as sio;
+
+    #[test]
+    fn copy_all() {
+        let data: Vec<u8> = (0..=255).cycle().take(3000).collect();
+        let (mut a, mut b) = (Vec::new(), Vec::new());
+        assert_same(
+            copy(&mut Trickle(&data), &mut a),
+            sio::copy(&mut Trickle(&data), &mut b),
+        );
+        assert_eq!(a, b);
+        assert_eq!(a, data);
+    }
+
+    #[test]
+    fn copy_write_zero() {
+        let mut ours = Limited::new(4);
+        let mut theirs = Limited::new(4);
+        assert_same(
+            copy(&mut Trickle(b"abcdefgh"), &mut ours),
+            sio::copy(&mut Trickle(b"abcdefgh"), &mut theirs),
+        );
+        assert_eq!(ours.data, theirs.data);
+    }
+}
@@ -25,21 +25,76 @@
 
 #![no_std]
 #![feature(alloc_error_handler)]
+#![feature(const_ptr_offset_from)]
+#![cfg_attr(feature = "panic-handler", feature(ffi_returns_twice))]
 
 // Re-export libc and kernel_sys so that the printing macros work
 pub use kernel_sys;
 pub use libc;
+// Re-export log so that modules use the same facade as `logger`
+pub use log;
 
 pub use kernel_sys::module_t as Module;
 
 extern crate alloc;
 
 pub mod allocator;
+pub mod bus;
 pub mod character_device;
+pub mod console;
 pub mod error;
+pub mod eventhandler;
+pub mod geom;
 pub mod io;
+pub mod linker_set;
+pub mod logger;
 pub mod module;
+pub mod net;
+#[cfg(feature = "panic-handler")]
+pub mod panic;
+pub mod syscall;
+pub mod sysctl;
+pub mod sysinit;
 pub mod uio;
+pub mod vfs;
+
+/// The current kernel thread, `curthread` in C
+pub(crate) fn curthread() -> *mut kernel_sys::thread {
+    let td: *mut kernel_sys::thread;
+    // pc_curthread is the first member of struct pcpu, which %gs points
+    // to in the kernel
+    unsafe {
+        core::arch::asm!(
+            "mov {}, qword ptr gs:[0]",
+            out(reg) td,
+            options(nostack, preserves_flags, readonly)
+        );
+    }
+    td
+}
+
+/// A null-terminated C string as a `&str`, or `None` if it is null or
+/// not UTF-8
+pub(crate) unsafe fn c_str<'a>(s: *const libc::c_char) -> Option<&'a str> {
+    if s.is_null() {
+        return None;
+    }
+    let bytes =
+        core::slice::from_raw_parts(s as *const u8, kernel_sys::strlen(s));
+    core::str::from_utf8(bytes).ok()
+}
+
+/// `mtx_lock()`
+pub(crate) unsafe fn mtx_lock(m: *mut kernel_sys::mtx) {
+    let file = concat!(file!(), "\0").as_ptr() as *const libc::c_char;
+    kernel_sys::__mtx_lock_flags(&mut (*m).mtx_lock, 0, file, line!() as _);
+}
+
+/// `mtx_unlock()`
+pub(crate) unsafe fn mtx_unlock(m: *mut kernel_sys::mtx) {
+    let file = concat!(file!(), "\0").as_ptr() as *const libc::c_char;
+    kernel_sys::__mtx_unlock_flags(&mut (*m).mtx_lock, 0, file, line!() as _);
+}
 
 /// Create a null-terminated constant string at compile time
 #[macro_export]
@@ -57,42 +112,46 @@ macro_rules! cstr_ref {
     };
 }
 
-/// Print kernel debug messages without a trailing newline
+/// Print to the console and kernel message buffer without a trailing
+/// newline
+///
+/// The arguments are checked at compile time like `core::format_args!()`,
+/// so `{{` and `}}` are the only escapes and a stray `{}` is an error. A
+/// `%` is always printed as is: the kernel `printf(9)` only ever sees a
+/// constant format string, with the message passed as an argument.
 #[macro_export]
 macro_rules! print {
-	// Static (zero-allocation) implementation that uses compile-time `concat!()` only
-	($fmt:expr) => ({
-		let msg = $crate::cstr!($fmt);
-		let ptr = msg.as_ptr() as *const $crate::libc::c_char;
-		unsafe {
-			$crate::kernel_sys::uprintf(ptr);
-		}
-	});
-
-	// Dynamic implementation that processes format arguments
-	($fmt:expr, $($arg:tt)*) => ({
-		use ::core::fmt::Write;
-		use $crate::io::KernelDebugWriter;
-		let mut writer = KernelDebugWriter {};
-        writer.write_fmt(format_args!($fmt, $($arg)*)).unwrap();
+	($($arg:tt)*) => ({
+		$crate::console::print(
+			$crate::console::Target::Console,
+			format_args!($($arg)*),
+		);
 	});
 }
 
-/// Print kernel debug messages with a trailing newline
+/// Print to the console and kernel message buffer with a trailing newline
 #[macro_export]
 macro_rules! println {
-	($fmt:expr)              => ($crate::print!(concat!($fmt, "\n")));
-	($fmt:expr, $($arg:tt)+) => ($crate::print!(concat!($fmt, "\n"), $($arg)*));
+	()            => ($crate::print!("\n"));
+	($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-/// Print kernel debug messages without a trailing newline in debug builds
+/// Print to the controlling terminal of the current process without a
+/// trailing newline. Output is lost if the process has no terminal.
 #[macro_export]
-macro_rules! debug {
-    ($($arg:tt)*) => (if cfg!(debug_assertions) { $crate::print!($($arg)*) })
+macro_rules! uprint {
+	($($arg:tt)*) => ({
+		$crate::console::print(
+			$crate::console::Target::Terminal,
+			format_args!($($arg)*),
+		);
+	});
 }
 
-/// Print kernel debug messages with a trailing newline in debug builds
+/// Print to the controlling terminal of the current process with a
+/// trailing newline
 #[macro_export]
-macro_rules! debugln {
-    ($($arg:tt)*) => (if cfg!(debug_assertions) { $crate::println!($($arg)*) })
+macro_rules! uprintln {
+	()            => ($crate::uprint!("\n"));
+	($($arg:tt)*) => ($crate::uprint!("{}\n", format_args!($($arg)*)));
 }
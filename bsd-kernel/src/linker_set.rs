@@ -0,0 +1,80 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+//
+// Based on public domain code by Johannes Lundberg
+
+//! Support for placing entries in kernel linker sets from Rust
+//!
+//! A linker set is an ELF section named `set_<name>` holding pointers to
+//! objects of one type. The kernel linker looks these sections up by name
+//! when a module is loaded, which is how `DECLARE_MODULE`, `SYSINIT` and
+//! friends from `sys/linker_set.h` work in C.
+
+use core::cell::UnsafeCell;
+
+/// Wrapper that allows kernel structures containing raw pointers to be
+/// stored in a `static`. The structures are only read by the kernel, so
+/// sharing them between threads is fine.
+#[repr(transparent)]
+pub struct Static<T>(pub T);
+
+unsafe impl<T> Sync for Static<T> {}
+
+/// Like `Static`, for kernel structures that the kernel also writes to.
+/// The cell keeps the `static` out of read-only memory.
+#[repr(transparent)]
+pub struct StaticMut<T>(UnsafeCell<T>);
+
+impl<T> StaticMut<T> {
+    pub const fn new(value: T) -> Self {
+        StaticMut(UnsafeCell::new(value))
+    }
+
+    pub const fn get(&self) -> *mut T {
+        self.0.get()
+    }
+}
+
+unsafe impl<T> Sync for StaticMut<T> {}
+
+/// A pointer to an object in a linker set, i.e. what `DATA_SET()` places
+/// in the `set_*` section
+#[repr(transparent)]
+pub struct SetEntry<T: 'static>(pub &'static Static<T>);
+
+unsafe impl<T> Sync for SetEntry<T> {}
+
+/// Add `$item`, a `static` of type `Static<$ty>`, to the linker set
+/// named `$set`. `$section` must be `"set_"` followed by the set name.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __linker_set_entry {
+    ($section:literal, $ty:ty, $item:expr) => {
+        const _: () = {
+            #[link_section = $section]
+            #[used]
+            static ENTRY: $crate::linker_set::SetEntry<$ty> =
+                $crate::linker_set::SetEntry(&$item);
+        };
+    };
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Backend for the `log` crate that writes records to the kernel message
+//! buffer through `log(9)`
+//!
+//! ```rust,ignore
+//! static LOGGER: KernelLogger = KernelLogger::new("hello");
+//!
+//! LOGGER.init(LevelFilter::Info).unwrap();
+//! log::info!("loaded");   // "hello: loaded" at LOG_INFO
+//! ```
+//!
+//! The maximum level can be changed at runtime by adding a sysctl for it
+//! with `KernelLogger::add_level_sysctl`.
+
+use crate::console::{self, Priority, Target};
+use crate::sysctl::{SysctlContext, SysctlOid};
+use core::ptr;
+use libc::{c_int, c_void};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/// `log::Log` implementation that prefixes every record with the name of
+/// the module that emitted it
+#[derive(Debug)]
+pub struct KernelLogger {
+    prefix: &'static str,
+}
+
+impl KernelLogger {
+    pub const fn new(prefix: &'static str) -> Self {
+        KernelLogger { prefix }
+    }
+
+    pub fn prefix(&self) -> &'static str {
+        self.prefix
+    }
+
+    /// Install this logger as the `log` backend and set the maximum level.
+    /// Fails if a logger has already been installed.
+    pub fn init(
+        &'static self,
+        level: LevelFilter,
+    ) -> Result<(), SetLoggerError> {
+        log::set_logger(self)?;
+        log::set_max_level(level);
+        Ok(())
+    }
+
+    /// Add a read/write integer sysctl named `log_level` beneath `parent`
+    /// that reflects `log::max_level()`, from 0 (`Off`) to 5 (`Trace`)
+    pub fn add_level_sysctl(
+        &self,
+        ctx: &mut SysctlContext,
+        parent: SysctlOid,
+    ) -> Option<SysctlOid> {
+        unsafe {
+            ctx.add_int_proc(
+                parent,
+                "log_level",
+                kernel_sys::CTLFLAG_RW as c_int,
+                ptr::null_mut(),
+                log_level_handler,
+                "Log level (0=off, 1=error, 2=warn, 3=info, 4=debug, 5=trace)",
+            )
+        }
+    }
+}
+
+impl Log for KernelLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        console::print(
+            Target::Log(priority(record.level())),
+            format_args!("{}: {}\n", self.prefix, record.args()),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Map a `log` level to the `syslog` priority passed to `log(9)`
+pub fn priority(level: Level) -> Priority {
+    match level {
+        Level::Error => Priority::Err,
+        Level::Warn => Priority::Warning,
+        Level::Info => Priority::Info,
+        Level::Debug | Level::Trace => Priority::Debug,
+    }
+}
+
+fn level_filter(n: c_int) -> Option<LevelFilter> {
+    match n {
+        0 => Some(LevelFilter::Off),
+        1 => Some(LevelFilter::Error),
+        2 => Some(LevelFilter::Warn),
+        3 => Some(LevelFilter::Info),
+        4 => Some(LevelFilter::Debug),
+        5 => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+unsafe extern "C" fn log_level_handler(
+    oidp: *mut kernel_sys::sysctl_oid,
+    _arg1: *mut c_void,
+    _arg2: kernel_sys::intmax_t,
+    req: *mut kernel_sys::sysctl_req,
+) -> c_int {
+    let mut level = log::max_level() as c_int;
+    let error = kernel_sys::sysctl_handle_int(
+        oidp,
+        &mut level as *mut c_int as *mut c_void,
+        0,
+        req,
+    );
+    if error != 0 || (*req).newptr.is_null() {
+        return error;
+    }
+    match level_filter(level) {
+        Some(filter) => {
+            log::set_max_level(filter);
+            0
+        }
+        None => libc::EINVAL,
+    }
+}
@@ -25,7 +25,7 @@
 
 //! Traits and interfaces for modules
 
-use crate::error::Error;
+use crate::error::{Errno, Error};
 use alloc::sync::Arc;
 use core::convert::{TryFrom, TryInto};
 use core::ops::{Deref, DerefMut};
@@ -35,8 +35,14 @@ use kernel_sys::{
     modeventtype_MOD_LOAD, modeventtype_MOD_QUIESCE, modeventtype_MOD_SHUTDOWN,
     modeventtype_MOD_UNLOAD,
 };
+use libc::c_int;
 use spin::{Mutex, MutexGuard};
 
+/// `MODULE_KERNEL_MAXVER` from `sys/module.h`: the newest kernel a module
+/// built against these headers declares itself compatible with
+pub const MODULE_KERNEL_MAXVER: c_int =
+    (kernel_sys::__FreeBSD_version + 99999) / 100000 * 100000 - 1;
+
 /// The module event types
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 #[repr(u32)]
@@ -79,12 +85,233 @@ impl ModuleEventType {
 
 /// Functions to handle each type of module event
 ///
-/// TODO: functions for SHUTDOWN and QUIESCE with default implementations
+/// Each function returns an error number to report failure to the kernel.
 pub trait ModuleEvents {
-    /// Function called when the module is loaded
-    fn load(&mut self);
-    /// Function called when the module is unloaded
-    fn unload(&mut self);
+    /// Function called when the module is loaded. If this fails the module
+    /// is not loaded and its state is dropped, so anything acquired before
+    /// the failure should be owned by the module state to be released.
+    fn load(&mut self) -> Result<(), Errno>;
+    /// Function called when the module is unloaded. Returning an error
+    /// keeps the module loaded, even for `kldunload -f`.
+    fn unload(&mut self) -> Result<(), Errno>;
+    /// Function called before the module is unloaded. Returning an error
+    /// (e.g. `EBUSY` while devices are open) cancels the unload, unless it
+    /// is forced with `kldunload -f`.
+    fn quiesce(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+    /// Function called when the system is shutting down. The module stays
+    /// loaded and any error is ignored by the kernel.
+    fn shutdown(&mut self) -> Result<(), Errno> {
+        Ok(())
+    }
+}
+
+/// Pass a raw module event on to the `ModuleEvents` implementation in
+/// `module`. This is the body of the event handler generated by
+/// `declare_module!`.
+pub fn dispatch_event<T>(module: &SharedModule<T>, event: c_int) -> c_int
+where
+    T: ModuleEvents,
+{
+    use ModuleEventType::*;
+    let event = match ModuleEventType::from_i32(event) {
+        Some(event) => event,
+        None => return libc::EOPNOTSUPP,
+    };
+    let result = match event {
+        Load => {
+            let result = module.lock().map_or(Ok(()), |mut m| m.load());
+            if result.is_err() {
+                // Roll back by dropping the module state. The kernel sends
+                // MOD_UNLOAD next, which finds nothing left to unload.
+                let _ = module.inner().lock().take();
+            }
+            result
+        }
+        Unload => {
+            let result = module.lock().map_or(Ok(()), |mut m| m.unload());
+            if result.is_ok() {
+                module.cleanup();
+            }
+            result
+        }
+        Quiesce => module.lock().map_or(Ok(()), |mut m| m.quiesce()),
+        Shutdown => module.lock().map_or(Ok(()), |mut m| m.shutdown()),
+    };
+    Errno::to_ret(result)
+}
+
+/// Declare a kernel module, replacing `DECLARE_MODULE()` and the
+/// `moduledata_t` of a C shim.
+///
+/// `$name` is the module name shown by `kldstat -v` and `$module` is a
+/// `SharedModule<T>` (or something that dereferences to one, such as a
+/// `lazy_static!`) whose `ModuleEvents` receive the module events. Like
+/// `DECLARE_MODULE()`, this also declares a dependency on the running
+/// kernel version. The module is registered at `Subsystem::Drivers` and
+/// `Order::Middle` unless another `sysinit::Subsystem` and
+/// `sysinit::Order` are given.
+///
+/// ```rust,ignore
+/// lazy_static! {
+///     pub static ref MODULE: SharedModule<Hello> =
+///         SharedModule::new(Hello::new());
+/// }
+///
+/// bsd_kernel::declare_module!(hello, MODULE);
+/// ```
+#[macro_export]
+macro_rules! declare_module {
+    ($name:ident, $module:expr) => {
+        $crate::declare_module!(
+            $name,
+            $module,
+            $crate::sysinit::Subsystem::Drivers,
+            $crate::sysinit::Order::Middle
+        );
+    };
+    ($name:ident, $module:expr, $subsystem:expr, $order:expr) => {
+        const _: () = {
+            unsafe extern "C" fn module_event(
+                _module: $crate::kernel_sys::module_t,
+                event: $crate::libc::c_int,
+                _arg: *mut $crate::libc::c_void,
+            ) -> $crate::libc::c_int {
+                $crate::module::dispatch_event(&$module, event)
+            }
+
+            static MODULE_DATA: $crate::linker_set::Static<
+                $crate::kernel_sys::moduledata_t,
+            > = $crate::linker_set::Static($crate::kernel_sys::moduledata_t {
+                name: $crate::cstr!(stringify!($name)).as_ptr()
+                    as *const $crate::libc::c_char,
+                evhand: Some(module_event as _),
+                priv_: ::core::ptr::null_mut(),
+            });
+
+            $crate::__declare_module!(
+                stringify!($name),
+                MODULE_DATA,
+                $subsystem,
+                $order
+            );
+        };
+    };
+}
+
+/// `DECLARE_MODULE()` for the `moduledata_t` in `$data`, a
+/// `Static<moduledata_t>`: depend on the kernel, add the module metadata
+/// for the module named `$name` (a string) and register the module at
+/// `$subsystem` and `$order`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __declare_module {
+    ($name:expr, $data:expr, $subsystem:expr, $order:expr) => {
+        $crate::module_depend!(
+            kernel,
+            $crate::kernel_sys::__FreeBSD_version,
+            $crate::kernel_sys::__FreeBSD_version,
+            $crate::module::MODULE_KERNEL_MAXVER
+        );
+        $crate::__module_metadata!(
+            $crate::kernel_sys::MDT_MODULE,
+            &$data,
+            $crate::cstr!($name)
+        );
+        $crate::__sysinit!(
+            "set_sysinit_set",
+            $subsystem,
+            $order,
+            $crate::kernel_sys::module_register_init,
+            &$data
+        );
+    };
+}
+
+/// `MODULE_METADATA()`: add a `mod_metadata` record of type `$type`
+/// pointing at `$data` to the `modmetadata_set` linker set
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __module_metadata {
+    ($type:expr, $data:expr, $cval:expr) => {
+        const _: () = {
+            static METADATA: $crate::linker_set::Static<
+                $crate::kernel_sys::mod_metadata,
+            > = $crate::linker_set::Static($crate::kernel_sys::mod_metadata {
+                md_version: $crate::kernel_sys::MDT_STRUCT_VERSION as _,
+                md_type: $type as _,
+                md_data: $data as *const _ as *const $crate::libc::c_void,
+                md_cval: $cval.as_ptr() as *const $crate::libc::c_char,
+            });
+            $crate::__linker_set_entry!(
+                "set_modmetadata_set",
+                $crate::kernel_sys::mod_metadata,
+                METADATA
+            );
+        };
+    };
+}
+
+/// Declare the version of a module, i.e. `MODULE_VERSION()`
+///
+/// The version is shown by `kldstat -v` and is what `module_depend!`
+/// in other modules is checked against. `$module` must match the name
+/// given to `declare_module!`.
+///
+/// ```rust,ignore
+/// bsd_kernel::declare_module!(hello, MODULE);
+/// bsd_kernel::module_version!(hello, 1);
+/// ```
+#[macro_export]
+macro_rules! module_version {
+    ($module:ident, $version:expr) => {
+        const _: () = {
+            static VERSION: $crate::kernel_sys::mod_version =
+                $crate::kernel_sys::mod_version {
+                    mv_version: $version,
+                };
+            $crate::__module_metadata!(
+                $crate::kernel_sys::MDT_VERSION,
+                &VERSION,
+                $crate::cstr!(stringify!($module))
+            );
+        };
+    };
+}
+
+/// Declare that the modules in this file depend on module `$depend`,
+/// i.e. `MODULE_DEPEND()`
+///
+/// `kldload` loads `$depend` first if it is not loaded yet, and refuses
+/// to load this file unless the version `$depend` declares with
+/// `MODULE_VERSION()` is between `$min` and `$max` inclusive. If several
+/// files provide `$depend`, the one closest to `$preferred` is used.
+///
+/// Unlike `MODULE_DEPEND()`, this does not take the name of the depending
+/// module: the C macro only uses it to name the metadata symbols, and the
+/// kernel linker applies dependencies to the whole file anyway.
+///
+/// ```rust,ignore
+/// bsd_kernel::module_depend!(crypto, 1, 1, 1);
+/// ```
+#[macro_export]
+macro_rules! module_depend {
+    ($depend:ident, $min:expr, $preferred:expr, $max:expr) => {
+        const _: () = {
+            static DEPEND: $crate::kernel_sys::mod_depend =
+                $crate::kernel_sys::mod_depend {
+                    md_ver_minimum: $min,
+                    md_ver_preferred: $preferred,
+                    md_ver_maximum: $max,
+                };
+            $crate::__module_metadata!(
+                $crate::kernel_sys::MDT_DEPEND,
+                &DEPEND,
+                $crate::cstr!(stringify!($depend))
+            );
+        };
+    };
 }
 
 pub struct LockedModule<'a, T: Sized + 'a> {
@@ -20,24 +20,25 @@
 // CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
 // OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
 // OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
-//
-// Based on public domain code by Johannes Lundberg
-
-#include <sys/param.h>
-#include <sys/module.h>
-#include <sys/kernel.h>
-#include <sys/systm.h>
-#include <sys/types.h>
-#include <sys/conf.h>
-#include <sys/uio.h>
-#include <sys/malloc.h>
 
-extern int module_event(struct module *, int, void *);
+//! Networking: packets, interfaces and hooks into the network stack
 
-static moduledata_t module_data = {
-    "hello",        /* module name */
-     module_event,  /* event handler */
-     NULL           /* extra data */
+pub use self::header::{EtherHeader, Header, Ip4Header, Ip6Header};
+pub use self::ifnet::{
+    IfType, Ifnet, Interface, IoctlData, NetInterface, DLT_EN10MB, DLT_NULL,
+    DLT_RAW, IFF_BROADCAST, IFF_DRV_OACTIVE, IFF_DRV_RUNNING, IFF_MULTICAST,
+    IFF_POINTOPOINT, IFF_SIMPLEX, IFF_UP, SIOCADDMULTI, SIOCDELMULTI,
+    SIOCSIFADDR, SIOCSIFFLAGS, SIOCSIFMTU,
+};
+pub use self::mbuf::{MTag, MTagRef, Mbuf, MbufChain, Segments};
+pub use self::socket::{
+    Domain, KernelSocket, SocketAddr, SocketStream, SocketType,
 };
 
-DECLARE_MODULE(hello, module_data, SI_SUB_DRIVERS, SI_ORDER_MIDDLE);
+pub mod header;
+mod ifnet;
+mod mbuf;
+pub mod netgraph;
+pub mod pfil;
+pub mod protocol;
+mod socket;
@@ -0,0 +1,146 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Protocol headers as found in packet data
+//!
+//! The headers only contain byte arrays, so they can be read at any
+//! offset into a packet whatever its alignment. Multi-byte fields are in
+//! network byte order and have accessors converting them.
+
+/// Types that can be viewed in packet data
+///
+/// # Safety
+/// Implementors must have an alignment of 1 and be valid for any bytes.
+pub unsafe trait Header: Sized {}
+
+/// `ETHERTYPE_IP`
+pub const ETHERTYPE_IP: u16 = 0x0800;
+/// `ETHERTYPE_ARP`
+pub const ETHERTYPE_ARP: u16 = 0x0806;
+/// `ETHERTYPE_VLAN`
+pub const ETHERTYPE_VLAN: u16 = 0x8100;
+/// `ETHERTYPE_IPV6`
+pub const ETHERTYPE_IPV6: u16 = 0x86dd;
+
+/// `IPPROTO_ICMP`
+pub const IPPROTO_ICMP: u8 = 1;
+/// `IPPROTO_TCP`
+pub const IPPROTO_TCP: u8 = 6;
+/// `IPPROTO_UDP`
+pub const IPPROTO_UDP: u8 = 17;
+/// `IPPROTO_ICMPV6`
+pub const IPPROTO_ICMPV6: u8 = 58;
+
+/// An Ethernet header, `struct ether_header`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct EtherHeader {
+    pub dhost: [u8; 6],
+    pub shost: [u8; 6],
+    pub ether_type: [u8; 2],
+}
+
+unsafe impl Header for EtherHeader {}
+
+impl EtherHeader {
+    pub fn ether_type(&self) -> u16 {
+        u16::from_be_bytes(self.ether_type)
+    }
+
+    pub fn set_ether_type(&mut self, ether_type: u16) {
+        self.ether_type = ether_type.to_be_bytes();
+    }
+}
+
+/// An IPv4 header without options, `struct ip`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Ip4Header {
+    pub version_ihl: u8,
+    pub tos: u8,
+    pub len: [u8; 2],
+    pub id: [u8; 2],
+    pub off: [u8; 2],
+    pub ttl: u8,
+    pub protocol: u8,
+    pub sum: [u8; 2],
+    pub src: [u8; 4],
+    pub dst: [u8; 4],
+}
+
+unsafe impl Header for Ip4Header {}
+
+impl Ip4Header {
+    pub fn version(&self) -> u8 {
+        self.version_ihl >> 4
+    }
+
+    /// The length of the header including options, in bytes
+    pub fn header_len(&self) -> usize {
+        ((self.version_ihl & 0xf) as usize) * 4
+    }
+
+    /// The length of the packet including the header, in bytes
+    pub fn total_len(&self) -> u16 {
+        u16::from_be_bytes(self.len)
+    }
+
+    pub fn id(&self) -> u16 {
+        u16::from_be_bytes(self.id)
+    }
+
+    /// The fragment offset and flags
+    pub fn off(&self) -> u16 {
+        u16::from_be_bytes(self.off)
+    }
+
+    pub fn checksum(&self) -> u16 {
+        u16::from_be_bytes(self.sum)
+    }
+}
+
+/// An IPv6 header, `struct ip6_hdr`
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Ip6Header {
+    /// Version, traffic class and flow label
+    pub flow: [u8; 4],
+    pub payload_len: [u8; 2],
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src: [u8; 16],
+    pub dst: [u8; 16],
+}
+
+unsafe impl Header for Ip6Header {}
+
+impl Ip6Header {
+    pub fn version(&self) -> u8 {
+        self.flow[0] >> 4
+    }
+
+    /// The length of the packet after this header, in bytes
+    pub fn payload_len(&self) -> u16 {
+        u16::from_be_bytes(self.payload_len)
+    }
+}
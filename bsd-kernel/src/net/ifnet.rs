@@ -0,0 +1,416 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Network interfaces, see `ifnet(9)`
+//!
+//! A pseudo-interface implements `Interface` and is created as a
+//! `NetInterface`, which is detached and freed when dropped:
+//!
+//! ```rust,ignore
+//! struct Tunnel;
+//!
+//! impl Interface for Tunnel {
+//!     fn transmit(&self, ifp: Ifnet, m: Mbuf) -> Result<(), Errno> {
+//!         ifp.bpf_tap(&m);
+//!         // Send m through the tunnel
+//!         Ok(())
+//!     }
+//! }
+//!
+//! let mut tun = NetInterface::new(ctx, IfType::Tunnel, "rtun", 0, Tunnel)?;
+//! tun.set_mtu(1400);
+//! tun.set_flags(IFF_POINTOPOINT | IFF_MULTICAST, 0);
+//! tun.attach(DLT_NULL, 4)?;
+//! ```
+
+use super::mbuf::{Mbuf, MbufChain};
+use crate::allocator::Sleepable;
+use crate::cstr_ref;
+use crate::error::Errno;
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::mem::size_of;
+use core::ops::Deref;
+use core::ptr::NonNull;
+use kernel_sys::{caddr_t, if_t, ifnet, mbuf};
+use libc::{c_char, c_int, c_uint, c_ulong, c_void};
+
+pub use kernel_sys::{DLT_EN10MB, DLT_NULL, DLT_RAW};
+
+pub const IFF_UP: c_int = kernel_sys::IFF_UP as c_int;
+pub const IFF_BROADCAST: c_int = kernel_sys::IFF_BROADCAST as c_int;
+pub const IFF_POINTOPOINT: c_int = kernel_sys::IFF_POINTOPOINT as c_int;
+pub const IFF_SIMPLEX: c_int = kernel_sys::IFF_SIMPLEX as c_int;
+pub const IFF_MULTICAST: c_int = kernel_sys::IFF_MULTICAST as c_int;
+
+/// The interface is running, set by `Interface::init`
+pub const IFF_DRV_RUNNING: c_int = kernel_sys::IFF_DRV_RUNNING as c_int;
+/// The transmit queue is full
+pub const IFF_DRV_OACTIVE: c_int = kernel_sys::IFF_DRV_OACTIVE as c_int;
+
+/// `_IOW()` for an ioctl taking a `struct ifreq`
+const fn ifreq_iow(num: c_ulong) -> c_ulong {
+    const IOC_IN: c_ulong = 0x8000_0000;
+    const IOCPARM_MASK: c_ulong = 0x1fff;
+    let len = size_of::<kernel_sys::ifreq>() as c_ulong;
+    IOC_IN | (len & IOCPARM_MASK) << 16 | (b'i' as c_ulong) << 8 | num
+}
+
+/// Set the interface address
+pub const SIOCSIFADDR: c_ulong = ifreq_iow(12);
+/// Set the interface flags
+pub const SIOCSIFFLAGS: c_ulong = ifreq_iow(16);
+/// Add a multicast address
+pub const SIOCADDMULTI: c_ulong = ifreq_iow(49);
+/// Delete a multicast address
+pub const SIOCDELMULTI: c_ulong = ifreq_iow(50);
+/// Set the interface MTU
+pub const SIOCSIFMTU: c_ulong = ifreq_iow(52);
+
+/// Interface types from `net/if_types.h`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum IfType {
+    Other = kernel_sys::ifType_IFT_OTHER as u8,
+    Ethernet = kernel_sys::ifType_IFT_ETHER as u8,
+    PropVirtual = kernel_sys::ifType_IFT_PROPVIRTUAL as u8,
+    Tunnel = kernel_sys::ifType_IFT_TUNNEL as u8,
+}
+
+/// A network interface, i.e. `if_t`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Ifnet(NonNull<ifnet>);
+
+impl Ifnet {
+    /// # Safety
+    /// `ifp` must be a valid interface that outlives the `Ifnet`.
+    pub unsafe fn from_ptr(ifp: *mut ifnet) -> Option<Ifnet> {
+        NonNull::new(ifp).map(Ifnet)
+    }
+
+    pub fn as_ptr(self) -> *mut ifnet {
+        self.0.as_ptr()
+    }
+
+    fn if_t(self) -> if_t {
+        self.0.as_ptr() as if_t
+    }
+
+    /// The `IFF_*` flags
+    pub fn flags(self) -> c_int {
+        unsafe { kernel_sys::if_getflags(self.if_t()) }
+    }
+
+    /// Set the flags in `set` and clear those in `clear`
+    pub fn set_flags(self, set: c_int, clear: c_int) {
+        unsafe { kernel_sys::if_setflagbits(self.if_t(), set, clear) };
+    }
+
+    /// The `IFF_DRV_*` flags, which belong to the driver
+    pub fn drv_flags(self) -> c_int {
+        unsafe { kernel_sys::if_getdrvflags(self.if_t()) }
+    }
+
+    pub fn set_drv_flags(self, set: c_int, clear: c_int) {
+        unsafe { kernel_sys::if_setdrvflagbits(self.if_t(), set, clear) };
+    }
+
+    pub fn mtu(self) -> c_int {
+        unsafe { kernel_sys::if_getmtu(self.if_t()) }
+    }
+
+    pub fn set_mtu(self, mtu: c_int) {
+        unsafe { kernel_sys::if_setmtu(self.if_t(), mtu) };
+    }
+
+    /// Set the `IFCAP_*` capabilities the interface supports
+    pub fn set_capabilities(self, capabilities: c_int) {
+        unsafe { kernel_sys::if_setcapabilities(self.if_t(), capabilities) };
+    }
+
+    /// The enabled `IFCAP_*` capabilities
+    pub fn capenable(self) -> c_int {
+        unsafe { kernel_sys::if_getcapenable(self.if_t()) }
+    }
+
+    pub fn set_capenable(self, capabilities: c_int) {
+        unsafe { kernel_sys::if_setcapenable(self.if_t(), capabilities) };
+    }
+
+    /// Pass a received packet up the network stack, i.e. `if_input()`.
+    /// Only Ethernet interfaces have an input routine; without a link
+    /// layer, packets have to be handed to the protocol with
+    /// `netisr_dispatch()` instead.
+    pub fn input(self, mut m: Mbuf) {
+        m.set_rcvif(self.as_ptr());
+        unsafe { kernel_sys::if_input(self.if_t(), m.into_raw()) };
+    }
+
+    /// Pass a packet to BPF listeners, if there are any, i.e.
+    /// `BPF_MTAP()`
+    pub fn bpf_tap(self, m: &MbufChain) {
+        unsafe { kernel_sys::if_bpfmtap(self.if_t(), m.as_ptr()) };
+    }
+
+    /// Handle the ioctls that need nothing from the driver: for Ethernet
+    /// interfaces `ether_ioctl()`, otherwise setting the address or MTU
+    pub fn default_ioctl(
+        self,
+        cmd: c_ulong,
+        data: IoctlData,
+    ) -> Result<(), Errno> {
+        let ether =
+            unsafe { (*self.as_ptr()).if_type } == IfType::Ethernet as u8;
+        match cmd {
+            SIOCADDMULTI | SIOCDELMULTI => Ok(()),
+            _ if ether => {
+                let ret = unsafe {
+                    kernel_sys::ether_ioctl(self.as_ptr(), cmd, data.0)
+                };
+                Errno::result(ret)
+            }
+            SIOCSIFADDR => Ok(()),
+            SIOCSIFMTU => {
+                let ifr = data.0 as *mut kernel_sys::ifreq;
+                let mtu = unsafe { (*ifr).ifr_ifru.ifru_mtu };
+                let range = kernel_sys::IF_MINMTU as c_int
+                    ..=kernel_sys::IF_MAXMTU as c_int;
+                if !range.contains(&mtu) {
+                    return Err(Errno::EINVAL);
+                }
+                self.set_mtu(mtu);
+                Ok(())
+            }
+            _ => Err(Errno::EINVAL),
+        }
+    }
+}
+
+unsafe impl Send for Ifnet {}
+unsafe impl Sync for Ifnet {}
+
+/// The argument of an interface or socket ioctl, usually a
+/// `struct ifreq`
+#[derive(Copy, Clone, Debug)]
+pub struct IoctlData(pub(super) caddr_t);
+
+impl IoctlData {
+    pub fn as_ptr(self) -> caddr_t {
+        self.0
+    }
+}
+
+/// The driver of a network interface
+///
+/// Once the interface is attached the methods may be called concurrently
+/// from any thread.
+pub trait Interface: Send + Sync + Sized + 'static {
+    /// Send `m`, i.e. `if_transmit`. The packet belongs to the driver,
+    /// which drops it if it cannot be sent.
+    fn transmit(&self, ifp: Ifnet, m: Mbuf) -> Result<(), Errno>;
+
+    /// Bring the interface up, i.e. `if_init`
+    fn init(&self, ifp: Ifnet) {
+        ifp.set_drv_flags(IFF_DRV_RUNNING, 0);
+    }
+
+    /// Free any queued packets, i.e. `if_qflush`
+    fn qflush(&self, _ifp: Ifnet) {}
+
+    /// Handle an interface ioctl, i.e. `if_ioctl`. By default setting the
+    /// flags starts or stops the interface, and anything else is passed
+    /// to `Ifnet::default_ioctl`.
+    fn ioctl(
+        &self,
+        ifp: Ifnet,
+        cmd: c_ulong,
+        data: IoctlData,
+    ) -> Result<(), Errno> {
+        match cmd {
+            SIOCSIFFLAGS => {
+                let running = ifp.drv_flags() & IFF_DRV_RUNNING != 0;
+                if ifp.flags() & IFF_UP != 0 {
+                    if !running {
+                        self.init(ifp);
+                    }
+                } else if running {
+                    ifp.set_drv_flags(0, IFF_DRV_RUNNING);
+                }
+                Ok(())
+            }
+            _ => ifp.default_ioctl(cmd, data),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Attached {
+    No,
+    Ethernet,
+    Raw,
+}
+
+/// A network interface driven by `T`, detached and freed when dropped
+///
+/// The interface softc points at the `NetInterface`, so it is always
+/// boxed.
+pub struct NetInterface<T: Interface> {
+    ifp: Ifnet,
+    attached: Attached,
+    // Referenced as if_dname until the interface is freed
+    name: String,
+    handler: T,
+}
+
+unsafe fn softc<'a, T: Interface>(softc: *mut c_void) -> &'a NetInterface<T> {
+    &*(softc as *const NetInterface<T>)
+}
+
+unsafe extern "C" fn if_init<T: Interface>(sc: *mut c_void) {
+    let sc = softc::<T>(sc);
+    sc.handler.init(sc.ifp);
+}
+
+unsafe extern "C" fn if_transmit<T: Interface>(
+    ifp: if_t,
+    m: *mut mbuf,
+) -> c_int {
+    let sc = softc::<T>(kernel_sys::if_getsoftc(ifp));
+    match Mbuf::from_raw(m) {
+        Some(m) => Errno::to_ret(sc.handler.transmit(sc.ifp, m)),
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn if_qflush<T: Interface>(ifp: if_t) {
+    let sc = softc::<T>(kernel_sys::if_getsoftc(ifp));
+    sc.handler.qflush(sc.ifp);
+}
+
+unsafe extern "C" fn if_ioctl<T: Interface>(
+    ifp: if_t,
+    cmd: c_ulong,
+    data: caddr_t,
+) -> c_int {
+    let sc = softc::<T>(kernel_sys::if_getsoftc(ifp));
+    Errno::to_ret(sc.handler.ioctl(sc.ifp, cmd, IoctlData(data)))
+}
+
+impl<T: Interface> NetInterface<T> {
+    /// Allocate an interface named `name` followed by `unit`, e.g.
+    /// `rtun0`. `if_alloc()` may sleep. The interface can be configured
+    /// through `Deref<Target = Ifnet>` before it is attached.
+    pub fn new<C: Sleepable>(
+        _ctx: &C,
+        ty: IfType,
+        name: &str,
+        unit: c_int,
+        handler: T,
+    ) -> Result<Box<NetInterface<T>>, Errno> {
+        let ifp = unsafe { kernel_sys::if_alloc(ty as u8) };
+        let ifp = NonNull::new(ifp).map(Ifnet).ok_or(Errno::ENOSPC)?;
+        let sc = Box::new(NetInterface {
+            ifp,
+            attached: Attached::No,
+            name: cstr_ref!(name).clone(),
+            handler,
+        });
+        let ifh = ifp.if_t();
+        unsafe {
+            let name = sc.name.as_ptr() as *const c_char;
+            kernel_sys::if_initname(ifp.as_ptr(), name, unit);
+            let ptr = &*sc as *const NetInterface<T> as *mut c_void;
+            kernel_sys::if_setsoftc(ifh, ptr);
+            kernel_sys::if_setinitfn(ifh, Some(if_init::<T>));
+            kernel_sys::if_settransmitfn(ifh, Some(if_transmit::<T>));
+            kernel_sys::if_setqflushfn(ifh, Some(if_qflush::<T>));
+            kernel_sys::if_setioctlfn(ifh, Some(if_ioctl::<T>));
+        }
+        Ok(sc)
+    }
+
+    pub fn ifnet(&self) -> Ifnet {
+        self.ifp
+    }
+
+    pub fn handler(&self) -> &T {
+        &self.handler
+    }
+
+    /// Attach as an Ethernet interface with the link-level address
+    /// `lladdr`, i.e. `ether_ifattach()`, which also attaches BPF with
+    /// `DLT_EN10MB`. Returns `EBUSY` if already attached.
+    pub fn ether_attach(&mut self, lladdr: [u8; 6]) -> Result<(), Errno> {
+        if self.attached != Attached::No {
+            return Err(Errno::EBUSY);
+        }
+        self.attached = Attached::Ethernet;
+        unsafe {
+            kernel_sys::ether_ifattach(self.ifp.as_ptr(), lladdr.as_ptr())
+        };
+        Ok(())
+    }
+
+    /// Attach without a link layer, i.e. `if_attach()`, with BPF seeing
+    /// packets as data link type `dlt` (e.g. `DLT_NULL`) with a
+    /// `hdrlen` byte header. Returns `EBUSY` if already attached.
+    ///
+    /// The interface has no input routine then, so received packets
+    /// cannot be passed to `Ifnet::input`.
+    pub fn attach(&mut self, dlt: c_uint, hdrlen: c_uint) -> Result<(), Errno> {
+        if self.attached != Attached::No {
+            return Err(Errno::EBUSY);
+        }
+        self.attached = Attached::Raw;
+        unsafe {
+            kernel_sys::if_attach(self.ifp.as_ptr());
+            kernel_sys::bpfattach(self.ifp.as_ptr(), dlt, hdrlen);
+        }
+        Ok(())
+    }
+}
+
+impl<T: Interface> Deref for NetInterface<T> {
+    type Target = Ifnet;
+
+    fn deref(&self) -> &Ifnet {
+        &self.ifp
+    }
+}
+
+impl<T: Interface> Drop for NetInterface<T> {
+    fn drop(&mut self) {
+        let ifp = self.ifp.as_ptr();
+        unsafe {
+            match self.attached {
+                Attached::No => {}
+                Attached::Ethernet => kernel_sys::ether_ifdetach(ifp),
+                Attached::Raw => {
+                    kernel_sys::bpfdetach(ifp);
+                    kernel_sys::if_detach(ifp);
+                }
+            }
+            kernel_sys::if_free(ifp);
+        }
+    }
+}
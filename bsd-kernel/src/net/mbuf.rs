@@ -0,0 +1,595 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Network packets, see `mbuf(9)`
+//!
+//! `Mbuf` owns a chain of mbufs and frees it when dropped. It dereferences
+//! to `MbufChain`, the borrowed view that is also used for chains owned by
+//! the kernel.
+//!
+//! `m_pullup()` frees the chain when it fails, so it consumes the `Mbuf`:
+//!
+//! ```rust,ignore
+//! let m = m.pullup(size_of::<EtherHeader>() + size_of::<Ip4Header>())?;
+//! let ip: &Ip4Header = m.header(size_of::<EtherHeader>())?;
+//! ```
+//!
+//! The data of an mbuf may be shared with other chains, e.g. copies queued
+//! for retransmission or BPF, so it can only be changed once
+//! `Mbuf::make_writable` has made a private copy:
+//!
+//! ```rust,ignore
+//! let mut m = m.make_writable()?;
+//! let ip: &mut Ip4Header = m.header_mut(size_of::<EtherHeader>())?;
+//! ```
+
+use super::header::Header;
+use crate::error::Errno;
+use core::mem::{size_of, MaybeUninit};
+use core::ops::{Deref, DerefMut};
+use core::ptr::{self, NonNull};
+use core::{fmt, slice};
+use kernel_sys::{ifnet, m_tag, mbuf, pkthdr};
+use libc::{c_char, c_int};
+
+// The leading members of `struct mbuf` and `struct pkthdr`, which
+// bindgen only exposes through nested anonymous unions
+#[repr(C)]
+struct MbufHead {
+    m_next: *mut mbuf,
+    m_nextpkt: *mut mbuf,
+    m_data: *mut c_char,
+    m_len: i32,
+    // m_type:8, m_flags:24
+    m_type_flags: u32,
+    m_pkthdr: PktHdr,
+}
+
+// The leading members of `struct m_ext`
+#[repr(C)]
+struct ExtHead {
+    // ext_count with EXT_FLAG_EMBREF, ext_cnt otherwise
+    ext_ref: ExtRef,
+    ext_size: u32,
+    // ext_type:8, ext_flags:24
+    ext_type_flags: u32,
+}
+
+#[repr(C)]
+union ExtRef {
+    count: u32,
+    cnt: *mut u32,
+}
+
+#[repr(C)]
+struct PktHdr {
+    // Shares a union with the send tag
+    rcvif: *mut ifnet,
+    tags: *mut m_tag,
+    len: i32,
+    flowid: u32,
+    csum_flags: u32,
+}
+
+/// The offset of a field, as a constant
+macro_rules! offset_of {
+    ($ty:ty, $($field:ident).+) => {{
+        let value = MaybeUninit::<$ty>::uninit();
+        let base = value.as_ptr();
+        unsafe {
+            (ptr::addr_of!((*base).$($field).+) as *const u8)
+                .offset_from(base as *const u8) as usize
+        }
+    }};
+}
+
+// Check the copies above against the structures from bindgen
+const _: () = {
+    assert!(size_of::<MbufHead>() <= size_of::<mbuf>());
+    assert!(offset_of!(MbufHead, m_data) == offset_of!(mbuf, m_data));
+    assert!(offset_of!(MbufHead, m_len) == offset_of!(mbuf, m_len));
+    assert!(offset_of!(MbufHead, m_type_flags) == offset_of!(mbuf, m_type));
+    assert!(
+        offset_of!(MbufHead, m_pkthdr)
+            == offset_of!(
+                mbuf,
+                __bindgen_anon_2.__bindgen_anon_1.__bindgen_anon_1.m_pkthdr
+            )
+    );
+
+    assert!(size_of::<PktHdr>() <= size_of::<pkthdr>());
+    assert!(
+        offset_of!(PktHdr, rcvif) == offset_of!(pkthdr, __bindgen_anon_1.rcvif)
+    );
+    assert!(offset_of!(PktHdr, tags) == offset_of!(pkthdr, tags));
+    assert!(offset_of!(PktHdr, len) == offset_of!(pkthdr, len));
+    assert!(offset_of!(PktHdr, flowid) == offset_of!(pkthdr, flowid));
+    assert!(offset_of!(PktHdr, csum_flags) == offset_of!(pkthdr, csum_flags));
+};
+
+/// A borrowed mbuf chain, i.e. a `struct mbuf`
+#[repr(transparent)]
+pub struct MbufChain(mbuf);
+
+impl MbufChain {
+    /// # Safety
+    /// `m` must be a valid mbuf chain that is not accessed otherwise for
+    /// `'a`.
+    pub unsafe fn from_ptr<'a>(m: *mut mbuf) -> &'a mut MbufChain {
+        &mut *(m as *mut MbufChain)
+    }
+
+    pub fn as_ptr(&self) -> *mut mbuf {
+        &self.0 as *const mbuf as *mut mbuf
+    }
+
+    fn head(&self) -> &MbufHead {
+        unsafe { &*(self.as_ptr() as *const MbufHead) }
+    }
+
+    fn head_mut(&mut self) -> &mut MbufHead {
+        unsafe { &mut *(self.as_ptr() as *mut MbufHead) }
+    }
+
+    /// The `M_*` flags of the first mbuf
+    pub fn flags(&self) -> u32 {
+        self.head().m_type_flags >> 8
+    }
+
+    /// Whether the first mbuf has a packet header, `M_PKTHDR`
+    pub fn has_pkthdr(&self) -> bool {
+        self.flags() & kernel_sys::M_PKTHDR as u32 != 0
+    }
+
+    fn pkthdr(&self) -> &PktHdr {
+        assert!(self.has_pkthdr(), "mbuf without packet header");
+        &self.head().m_pkthdr
+    }
+
+    fn pkthdr_mut(&mut self) -> &mut PktHdr {
+        assert!(self.has_pkthdr(), "mbuf without packet header");
+        &mut self.head_mut().m_pkthdr
+    }
+
+    /// The data of the first mbuf
+    pub fn data(&self) -> &[u8] {
+        let head = self.head();
+        unsafe {
+            slice::from_raw_parts(head.m_data as *const u8, head.m_len as usize)
+        }
+    }
+
+    /// The data of the first mbuf for changing it, or `None` if it is not
+    /// writable
+    pub fn data_mut(&mut self) -> Option<&mut [u8]> {
+        if !self.is_writable() {
+            return None;
+        }
+        let head = self.head_mut();
+        unsafe {
+            Some(slice::from_raw_parts_mut(
+                head.m_data as *mut u8,
+                head.m_len as usize,
+            ))
+        }
+    }
+
+    /// Whether the data of the first mbuf may be changed, i.e.
+    /// `M_WRITABLE()`: it is not read-only and its external storage, if
+    /// any, is not shared with other mbufs
+    pub fn is_writable(&self) -> bool {
+        let flags = self.flags();
+        if flags & (kernel_sys::M_RDONLY | kernel_sys::M_EXTPG) as u32 != 0 {
+            return false;
+        }
+        if flags & kernel_sys::M_EXT as u32 == 0 {
+            return true;
+        }
+        // m_extrefcnt()
+        let ext = unsafe {
+            &*(ptr::addr_of!(
+                self.0
+                    .__bindgen_anon_2
+                    .__bindgen_anon_1
+                    .__bindgen_anon_2
+                    .m_ext
+            ) as *const ExtHead)
+        };
+        let embedded = kernel_sys::EXT_FLAG_EMBREF as u32;
+        let refs = unsafe {
+            if (ext.ext_type_flags >> 8) & embedded != 0 {
+                ptr::read_volatile(&ext.ext_ref.count)
+            } else {
+                ptr::read_volatile(ext.ext_ref.cnt)
+            }
+        };
+        refs == 1
+    }
+
+    /// The next mbuf of the chain
+    pub fn next(&self) -> Option<&MbufChain> {
+        let next = self.head().m_next;
+        if next.is_null() {
+            None
+        } else {
+            Some(unsafe { MbufChain::from_ptr(next) })
+        }
+    }
+
+    /// Iterate over the data of each mbuf of the chain
+    pub fn segments(&self) -> Segments {
+        Segments { m: Some(self) }
+    }
+
+    /// The length of the packet, from the packet header if there is one
+    pub fn len(&self) -> usize {
+        if self.has_pkthdr() {
+            self.pkthdr().len as usize
+        } else {
+            self.segments().map(<[u8]>::len).sum()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// View `H` at `offset` into the data of the first mbuf, or `None` if
+    /// the first mbuf is too short. Use `Mbuf::pullup` first to make sure
+    /// it is not.
+    pub fn header<H: Header>(&self, offset: usize) -> Option<&H> {
+        let bytes = self.data().get(offset..offset + size_of::<H>())?;
+        Some(unsafe { &*(bytes.as_ptr() as *const H) })
+    }
+
+    /// View `H` at `offset` for changing it, or `None` if the first mbuf is
+    /// too short or not writable
+    pub fn header_mut<H: Header>(&mut self, offset: usize) -> Option<&mut H> {
+        let bytes =
+            self.data_mut()?.get_mut(offset..offset + size_of::<H>())?;
+        Some(unsafe { &mut *(bytes.as_mut_ptr() as *mut H) })
+    }
+
+    /// Copy packet data from `offset` into `buf`, i.e. `m_copydata()`.
+    /// Returns `EINVAL` if the packet is too short.
+    pub fn copy_data(
+        &self,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<(), Errno> {
+        match offset.checked_add(buf.len()) {
+            Some(end) if end <= self.len() => {}
+            _ => return Err(Errno::EINVAL),
+        }
+        unsafe {
+            kernel_sys::m_copydata(
+                self.as_ptr(),
+                offset as c_int,
+                buf.len() as c_int,
+                buf.as_mut_ptr() as *mut c_char,
+            )
+        };
+        Ok(())
+    }
+
+    /// Append `data` to the chain, adding mbufs as needed, i.e.
+    /// `m_append()`. This does not sleep, and returns `ENOBUFS` if no mbufs
+    /// are available.
+    pub fn append(&mut self, data: &[u8]) -> Result<(), Errno> {
+        let ret = unsafe {
+            kernel_sys::m_append(
+                self.as_ptr(),
+                data.len() as c_int,
+                data.as_ptr() as *const c_char,
+            )
+        };
+        if ret == 0 {
+            return Err(Errno::ENOBUFS);
+        }
+        Ok(())
+    }
+
+    /// The interface the packet was received on
+    pub fn rcvif(&self) -> *mut ifnet {
+        self.pkthdr().rcvif
+    }
+
+    pub fn set_rcvif(&mut self, ifp: *mut ifnet) {
+        self.pkthdr_mut().rcvif = ifp;
+    }
+
+    /// The flow hash of the packet, e.g. for RSS
+    pub fn flowid(&self) -> u32 {
+        self.pkthdr().flowid
+    }
+
+    pub fn set_flowid(&mut self, flowid: u32) {
+        self.pkthdr_mut().flowid = flowid;
+    }
+
+    /// The `CSUM_*` checksum offload flags
+    pub fn csum_flags(&self) -> u32 {
+        self.pkthdr().csum_flags
+    }
+
+    pub fn set_csum_flags(&mut self, flags: u32) {
+        self.pkthdr_mut().csum_flags = flags;
+    }
+
+    /// The first tag of the packet with `cookie` and `ty`, i.e.
+    /// `m_tag_locate()`
+    pub fn find_tag(&self, cookie: u32, ty: c_int) -> Option<&MTagRef> {
+        assert!(self.has_pkthdr(), "mbuf without packet header");
+        let tag = unsafe {
+            kernel_sys::m_tag_locate(self.as_ptr(), cookie, ty, ptr::null_mut())
+        };
+        if tag.is_null() {
+            None
+        } else {
+            Some(unsafe { &*(tag as *const MTagRef) })
+        }
+    }
+
+    /// Attach `tag` to the packet, i.e. `m_tag_prepend()`
+    pub fn prepend_tag(&mut self, tag: MTag) {
+        let pkthdr = self.pkthdr_mut();
+        let tag = tag.into_raw();
+        unsafe { (*tag).m_tag_link.sle_next = pkthdr.tags };
+        pkthdr.tags = tag;
+    }
+
+    /// Remove and free the first tag with `cookie` and `ty`, i.e.
+    /// `m_tag_delete()`. Returns whether there was one.
+    pub fn delete_tag(&mut self, cookie: u32, ty: c_int) -> bool {
+        match self.find_tag(cookie, ty) {
+            Some(tag) => {
+                let tag = tag.as_ptr();
+                unsafe { kernel_sys::m_tag_delete(self.as_ptr(), tag) };
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for MbufChain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MbufChain")
+            .field("m", &self.as_ptr())
+            .field("len", &self.len())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
+
+/// Iterator over the data of the mbufs of a chain
+#[derive(Debug)]
+pub struct Segments<'a> {
+    m: Option<&'a MbufChain>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let m = self.m?;
+        self.m = m.next();
+        Some(m.data())
+    }
+}
+
+/// An owned mbuf chain, freed with `m_freem()` when dropped
+pub struct Mbuf(NonNull<mbuf>);
+
+impl Mbuf {
+    /// Allocate a packet with room for `size` bytes, i.e. `m_get2()`. This
+    /// does not sleep, and returns `None` if no mbufs are available.
+    pub fn alloc(size: usize) -> Option<Mbuf> {
+        let m = unsafe {
+            kernel_sys::m_get2(
+                size as c_int,
+                kernel_sys::M_NOWAIT,
+                kernel_sys::MT_DATA as _,
+                kernel_sys::M_PKTHDR,
+            )
+        };
+        NonNull::new(m).map(Mbuf)
+    }
+
+    /// Take ownership of the chain `m`
+    ///
+    /// # Safety
+    /// `m` must be a valid mbuf chain that nothing else frees.
+    pub unsafe fn from_raw(m: *mut mbuf) -> Option<Mbuf> {
+        NonNull::new(m).map(Mbuf)
+    }
+
+    /// Give up ownership of the chain, e.g. to pass it to the kernel
+    pub fn into_raw(self) -> *mut mbuf {
+        let m = self.0.as_ptr();
+        core::mem::forget(self);
+        m
+    }
+
+    /// Make the first `len` bytes of the packet contiguous in the first
+    /// mbuf, i.e. `m_pullup()`. If that fails, the chain is freed and
+    /// `None` is returned.
+    pub fn pullup(self, len: usize) -> Option<Mbuf> {
+        if self.data().len() >= len {
+            return Some(self);
+        }
+        let m = unsafe { kernel_sys::m_pullup(self.into_raw(), len as c_int) };
+        NonNull::new(m).map(Mbuf)
+    }
+
+    /// Make the data of all mbufs of the chain writable, copying what is
+    /// shared, i.e. `m_unshare()`. This does not sleep. If it fails, the
+    /// chain is freed and `None` is returned.
+    pub fn make_writable(self) -> Option<Mbuf> {
+        let m = unsafe {
+            kernel_sys::m_unshare(self.into_raw(), kernel_sys::M_NOWAIT)
+        };
+        NonNull::new(m).map(Mbuf)
+    }
+}
+
+impl Deref for Mbuf {
+    type Target = MbufChain;
+
+    fn deref(&self) -> &MbufChain {
+        unsafe { MbufChain::from_ptr(self.0.as_ptr()) }
+    }
+}
+
+impl DerefMut for Mbuf {
+    fn deref_mut(&mut self) -> &mut MbufChain {
+        unsafe { MbufChain::from_ptr(self.0.as_ptr()) }
+    }
+}
+
+impl fmt::Debug for Mbuf {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Drop for Mbuf {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::m_freem(self.0.as_ptr()) };
+    }
+}
+
+unsafe impl Send for Mbuf {}
+unsafe impl Sync for Mbuf {}
+
+/// A borrowed packet tag, i.e. a `struct m_tag` followed by its data
+#[repr(transparent)]
+pub struct MTagRef(m_tag);
+
+impl MTagRef {
+    pub fn as_ptr(&self) -> *mut m_tag {
+        &self.0 as *const m_tag as *mut m_tag
+    }
+
+    pub fn cookie(&self) -> u32 {
+        self.0.m_tag_cookie
+    }
+
+    /// The type of the tag, unique for its cookie
+    pub fn ty(&self) -> c_int {
+        self.0.m_tag_id as c_int
+    }
+
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(
+                self.as_ptr().add(1) as *const u8,
+                self.0.m_tag_len as usize,
+            )
+        }
+    }
+
+    /// The data of a tag, which is never shared, as copies of a packet get
+    /// copies of its tags
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(
+                self.as_ptr().add(1) as *mut u8,
+                self.0.m_tag_len as usize,
+            )
+        }
+    }
+}
+
+impl fmt::Debug for MTagRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MTag")
+            .field("cookie", &self.cookie())
+            .field("ty", &self.ty())
+            .field("len", &self.0.m_tag_len)
+            .finish()
+    }
+}
+
+/// An owned packet tag that is not attached to a packet, freed when
+/// dropped
+pub struct MTag(NonNull<m_tag>);
+
+impl MTag {
+    /// Allocate a tag with `len` bytes of data, i.e. `m_tag_alloc()`.
+    /// `cookie` identifies the module, usually `MTAG_ABI_COMPAT` or a
+    /// date, and `ty` the kind of tag. This does not sleep, and returns
+    /// `None` if no memory is available.
+    pub fn alloc(cookie: u32, ty: c_int, len: usize) -> Option<MTag> {
+        let tag = unsafe {
+            kernel_sys::m_tag_alloc(
+                cookie,
+                ty,
+                len as c_int,
+                kernel_sys::M_NOWAIT,
+            )
+        };
+        NonNull::new(tag).map(MTag)
+    }
+
+    fn into_raw(self) -> *mut m_tag {
+        let tag = self.0.as_ptr();
+        core::mem::forget(self);
+        tag
+    }
+}
+
+impl Deref for MTag {
+    type Target = MTagRef;
+
+    fn deref(&self) -> &MTagRef {
+        unsafe { &*(self.0.as_ptr() as *const MTagRef) }
+    }
+}
+
+impl DerefMut for MTag {
+    fn deref_mut(&mut self) -> &mut MTagRef {
+        unsafe { &mut *(self.0.as_ptr() as *mut MTagRef) }
+    }
+}
+
+impl fmt::Debug for MTag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl Drop for MTag {
+    fn drop(&mut self) {
+        // m_tag_free() is inline
+        unsafe {
+            let tag = self.0.as_ptr();
+            if let Some(free) = (*tag).m_tag_free {
+                free(tag);
+            }
+        }
+    }
+}
+
+unsafe impl Send for MTag {}
+unsafe impl Sync for MTag {}
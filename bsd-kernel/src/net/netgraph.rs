@@ -0,0 +1,794 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Netgraph node types, see `netgraph(4)` and `netgraph(9)`
+//!
+//! A node type implements `NodeType`, whose value is the private state of
+//! a node, and is registered with `netgraph_type!`. Control messages can
+//! be converted to and from ASCII by `ngctl msg` if they are listed there
+//! with their `ng_parse_type`s.
+//!
+//! ```rust,ignore
+//! const NGM_ECHO_COOKIE: u32 = 1_674_231_422;
+//! const NGM_ECHO_SET_DELAY: u32 = 1;
+//!
+//! struct Echo {
+//!     delay: AtomicU32,
+//! }
+//!
+//! impl NodeType for Echo {
+//!     fn constructor(_node: Node) -> Result<Self, Errno> {
+//!         Ok(Echo { delay: AtomicU32::new(0) })
+//!     }
+//!
+//!     fn rcvdata(&self, hook: Hook, m: Mbuf) -> Result<(), Errno> {
+//!         // Send the packet back where it came from
+//!         hook.send_data(m)
+//!     }
+//!
+//!     fn rcvmsg(
+//!         &self,
+//!         _node: Node,
+//!         msg: &Message,
+//!         _lasthook: Option<Hook>,
+//!     ) -> Result<Option<Message>, Errno> {
+//!         match (msg.cookie(), msg.cmd()) {
+//!             (NGM_ECHO_COOKIE, NGM_ECHO_SET_DELAY) => {
+//!                 let delay = msg.arg::<u32>().ok_or(Errno::EINVAL)?;
+//!                 self.delay.store(*delay, Ordering::Relaxed);
+//!                 Ok(None)
+//!             }
+//!             _ => Err(Errno::EINVAL),
+//!         }
+//!     }
+//! }
+//!
+//! bsd_kernel::netgraph_type!(echo, Echo, [
+//!     (NGM_ECHO_COOKIE, NGM_ECHO_SET_DELAY, "setdelay", uint32, none),
+//! ]);
+//! ```
+
+use super::mbuf::Mbuf;
+use crate::c_str;
+use crate::error::Errno;
+use alloc::boxed::Box;
+use core::mem::{align_of, size_of};
+use core::ptr::{self, NonNull};
+use core::{fmt, slice};
+use kernel_sys::{
+    hook_p, item_p, ng_cmdlist, ng_hook, ng_mesg, ng_node,
+    ng_parse_struct_field, ng_parse_type, ng_type, node_p,
+};
+use libc::{c_char, c_int, c_void};
+
+/// The ID of a node, e.g. the address of a message
+pub type NodeId = kernel_sys::ng_ID_t;
+
+/// A netgraph node
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Node(NonNull<ng_node>);
+
+impl Node {
+    pub fn as_ptr(self) -> node_p {
+        self.0.as_ptr()
+    }
+
+    /// The name of the node, empty if it has none
+    pub fn name(&self) -> &str {
+        let name = unsafe { &(*self.as_ptr()).nd_name };
+        unsafe { c_str(name.as_ptr()) }.unwrap_or("")
+    }
+
+    pub fn id(self) -> NodeId {
+        unsafe { (*self.as_ptr()).nd_ID }
+    }
+
+    /// The number of hooks connected to the node
+    pub fn num_hooks(self) -> c_int {
+        unsafe { (*self.as_ptr()).nd_numhooks }
+    }
+
+    /// Whether the node is not being shut down, i.e. `NG_NODE_IS_VALID()`
+    pub fn is_valid(self) -> bool {
+        let flags = unsafe { (*self.as_ptr()).nd_flags };
+        flags & kernel_sys::NGF_INVALID as c_int == 0
+    }
+
+    /// Shut the node down, i.e. `ng_rmnode_self()`
+    pub fn remove(self) {
+        unsafe { kernel_sys::ng_rmnode_self(self.as_ptr()) };
+    }
+
+    /// Send `msg` to the node with ID `dest`, with replies going to
+    /// `retaddr` (or this node if 0), i.e. `NG_SEND_MSG_ID()`
+    pub fn send_msg(
+        self,
+        msg: Message,
+        dest: NodeId,
+        retaddr: NodeId,
+    ) -> Result<(), Errno> {
+        unsafe {
+            let item = package_msg(msg)?;
+            Errno::result(kernel_sys::ng_address_ID(
+                self.as_ptr(),
+                item,
+                dest,
+                retaddr,
+            ))?;
+            Errno::result(kernel_sys::ng_snd_item(item, 0))
+        }
+    }
+}
+
+unsafe impl Send for Node {}
+unsafe impl Sync for Node {}
+
+/// A hook of a node, the end of an edge in the graph
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Hook(NonNull<ng_hook>);
+
+impl Hook {
+    pub fn as_ptr(self) -> hook_p {
+        self.0.as_ptr()
+    }
+
+    pub fn name(&self) -> &str {
+        let name = unsafe { &(*self.as_ptr()).hk_name };
+        unsafe { c_str(name.as_ptr()) }.unwrap_or("")
+    }
+
+    /// The node the hook belongs to
+    pub fn node(self) -> Node {
+        Node(unsafe { NonNull::new_unchecked((*self.as_ptr()).hk_node) })
+    }
+
+    /// Data for the node type, null unless set with `set_private`, e.g.
+    /// to tell hooks apart without comparing names
+    pub fn private(self) -> *mut c_void {
+        unsafe { (*self.as_ptr()).hk_private }
+    }
+
+    pub fn set_private(self, private: *mut c_void) {
+        unsafe { (*self.as_ptr()).hk_private = private };
+    }
+
+    /// Send `m` out of the hook to the connected node, i.e.
+    /// `NG_SEND_DATA_ONLY()`. The packet is freed if this fails.
+    pub fn send_data(self, m: Mbuf) -> Result<(), Errno> {
+        let flags = kernel_sys::NG_NOFLAGS as c_int;
+        unsafe {
+            // Frees the mbuf if it fails
+            let item = kernel_sys::ng_package_data(m.into_raw(), flags);
+            if item.is_null() {
+                return Err(Errno::ENOMEM);
+            }
+            // Frees the item if it fails
+            Errno::result(kernel_sys::ng_address_hook(
+                ptr::null_mut(),
+                item,
+                self.as_ptr(),
+                0,
+            ))?;
+            Errno::result(kernel_sys::ng_snd_item(item, flags))
+        }
+    }
+
+    /// Send `msg` out of the hook from `here`, with replies going to
+    /// `retaddr` (or `here` if 0), i.e. `NG_SEND_MSG_HOOK()`
+    pub fn send_msg(
+        self,
+        here: Node,
+        msg: Message,
+        retaddr: NodeId,
+    ) -> Result<(), Errno> {
+        unsafe {
+            let item = package_msg(msg)?;
+            Errno::result(kernel_sys::ng_address_hook(
+                here.as_ptr(),
+                item,
+                self.as_ptr(),
+                retaddr,
+            ))?;
+            Errno::result(kernel_sys::ng_snd_item(item, 0))
+        }
+    }
+}
+
+unsafe impl Send for Hook {}
+unsafe impl Sync for Hook {}
+
+/// `ng_package_msg()`, which frees the message if it fails
+unsafe fn package_msg(msg: Message) -> Result<item_p, Errno> {
+    let item = kernel_sys::ng_package_msg(
+        msg.into_raw(),
+        kernel_sys::NG_NOFLAGS as c_int,
+    );
+    if item.is_null() {
+        Err(Errno::ENOMEM)
+    } else {
+        Ok(item)
+    }
+}
+
+/// Plain data that can be the argument of a `Message`
+///
+/// # Safety
+/// Any bit pattern must be a valid value of the type, and it must have
+/// no padding.
+pub unsafe trait MessageArg: Copy {}
+
+macro_rules! message_args {
+    ($($ty:ty),*) => {$(
+        unsafe impl MessageArg for $ty {}
+    )*};
+}
+
+message_args!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+unsafe impl<T: MessageArg, const N: usize> MessageArg for [T; N] {}
+
+/// A control message, i.e. `struct ng_mesg`, freed when dropped
+pub struct Message(NonNull<ng_mesg>);
+
+impl Message {
+    /// A message with the type cookie `cookie` and command `cmd`, whose
+    /// name is `cmdstr`, i.e. `NG_MKMESSAGE()`. Returns `None` if no
+    /// memory is available.
+    pub fn new(
+        cookie: u32,
+        cmd: u32,
+        cmdstr: &str,
+        arg: &[u8],
+    ) -> Option<Message> {
+        let msg = Message::alloc(arg)?;
+        let header = unsafe { &mut (*msg.0.as_ptr()).header };
+        header.typecookie = cookie;
+        header.cmd = cmd;
+        // Left null-terminated by the zeroed allocation
+        let len = cmdstr.len().min(header.cmdstr.len() - 1);
+        for (dst, src) in
+            header.cmdstr.iter_mut().zip(&cmdstr.as_bytes()[..len])
+        {
+            *dst = *src as _;
+        }
+        Some(msg)
+    }
+
+    /// A response to this message carrying `arg`, i.e. `NG_MKRESPONSE()`
+    pub fn response(&self, arg: &[u8]) -> Option<Message> {
+        let rsp = Message::alloc(arg)?;
+        let header = unsafe { &mut (*rsp.0.as_ptr()).header };
+        let msg = self.header();
+        header.token = msg.token;
+        header.typecookie = msg.typecookie;
+        header.cmd = msg.cmd;
+        header.cmdstr = msg.cmdstr;
+        header.flags |= kernel_sys::NGF_RESP as u32;
+        Some(rsp)
+    }
+
+    /// A message without a command, carrying `arg`
+    fn alloc(arg: &[u8]) -> Option<Message> {
+        let msg = unsafe {
+            kernel_sys::malloc(
+                size_of::<ng_mesg>() + arg.len(),
+                &mut kernel_sys::M_NETGRAPH_MSG[0],
+                kernel_sys::M_NOWAIT | kernel_sys::M_ZERO,
+            ) as *mut ng_mesg
+        };
+        let msg = Message(NonNull::new(msg)?);
+        unsafe {
+            let header = &mut (*msg.0.as_ptr()).header;
+            header.version = kernel_sys::NG_VERSION as u8;
+            header.arglen = arg.len() as u32;
+            let data = (*msg.0.as_ptr()).data.as_mut_ptr() as *mut u8;
+            ptr::copy_nonoverlapping(arg.as_ptr(), data, arg.len());
+        }
+        Some(msg)
+    }
+
+    /// # Safety
+    /// `msg` must be a valid message allocated from `M_NETGRAPH_MSG`,
+    /// which the `Message` takes ownership of.
+    pub unsafe fn from_raw(msg: *mut ng_mesg) -> Option<Message> {
+        NonNull::new(msg).map(Message)
+    }
+
+    /// Give up ownership of the message
+    pub fn into_raw(self) -> *mut ng_mesg {
+        let msg = self.0.as_ptr();
+        core::mem::forget(self);
+        msg
+    }
+
+    fn header(&self) -> &kernel_sys::ng_mesg_ng_msghdr {
+        unsafe { &(*self.0.as_ptr()).header }
+    }
+
+    pub fn cookie(&self) -> u32 {
+        self.header().typecookie
+    }
+
+    pub fn cmd(&self) -> u32 {
+        self.header().cmd
+    }
+
+    /// The name of the command
+    pub fn cmdstr(&self) -> &str {
+        let cmdstr = &self.header().cmdstr;
+        let len = cmdstr.iter().position(|&c| c == 0).unwrap_or(cmdstr.len());
+        let bytes =
+            unsafe { slice::from_raw_parts(cmdstr.as_ptr() as *const u8, len) };
+        core::str::from_utf8(bytes).unwrap_or("")
+    }
+
+    /// Whether this is a response to another message
+    pub fn is_response(&self) -> bool {
+        self.header().flags & kernel_sys::NGF_RESP as u32 != 0
+    }
+
+    /// The argument of the message
+    pub fn data(&self) -> &[u8] {
+        unsafe {
+            let data = (*self.0.as_ptr()).data.as_ptr() as *const u8;
+            slice::from_raw_parts(data, self.header().arglen as usize)
+        }
+    }
+
+    /// The argument as a `T`, if it is large enough and suitably aligned
+    pub fn arg<T: MessageArg>(&self) -> Option<&T> {
+        let data = self.data();
+        if data.len() < size_of::<T>()
+            || data.as_ptr() as usize % align_of::<T>() != 0
+        {
+            return None;
+        }
+        Some(unsafe { &*(data.as_ptr() as *const T) })
+    }
+}
+
+impl fmt::Debug for Message {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("cookie", &self.cookie())
+            .field("cmd", &self.cmd())
+            .field("cmdstr", &self.cmdstr())
+            .field("arglen", &self.data().len())
+            .finish()
+    }
+}
+
+impl Drop for Message {
+    fn drop(&mut self) {
+        unsafe {
+            kernel_sys::free(
+                self.0.as_ptr() as *mut c_void,
+                &mut kernel_sys::M_NETGRAPH_MSG[0],
+            )
+        };
+    }
+}
+
+unsafe impl Send for Message {}
+unsafe impl Sync for Message {}
+
+/// A netgraph node type. A value is created for each node by
+/// `constructor` and dropped when the node is shut down.
+///
+/// Data and messages may arrive concurrently on different hooks.
+pub trait NodeType: Sized + Send + Sync + 'static {
+    /// Set up a new node, i.e. `ng_constructor_t`
+    fn constructor(node: Node) -> Result<Self, Errno>;
+
+    /// The node is being shut down, i.e. `ng_shutdown_t`, after which
+    /// `self` is dropped
+    fn shutdown(&self, _node: Node) {}
+
+    /// Accept or reject a new hook named `name`, i.e. `ng_newhook_t`
+    fn newhook(
+        &self,
+        _node: Node,
+        _hook: Hook,
+        _name: &str,
+    ) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// The hook is connected to another node, i.e. `ng_connect_t`
+    fn connect(&self, _hook: Hook) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Handle a packet arriving on `hook`, i.e. `ng_rcvdata_t`. By
+    /// default it is dropped.
+    fn rcvdata(&self, _hook: Hook, _m: Mbuf) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// Handle a control message, returning the response to send back (see
+    /// `Message::response`), if any, i.e. `ng_rcvmsg_t`. `lasthook` is the
+    /// hook the message arrived on, if any.
+    fn rcvmsg(
+        &self,
+        _node: Node,
+        _msg: &Message,
+        _lasthook: Option<Hook>,
+    ) -> Result<Option<Message>, Errno> {
+        Err(Errno::EINVAL)
+    }
+
+    /// The hook is disconnected, i.e. `ng_disconnect_t`. By default the
+    /// node is shut down when its last hook is gone.
+    fn disconnect(&self, hook: Hook) -> Result<(), Errno> {
+        let node = hook.node();
+        if node.num_hooks() == 0 && node.is_valid() {
+            node.remove();
+        }
+        Ok(())
+    }
+}
+
+unsafe fn private<'a, N: NodeType>(node: node_p) -> &'a N {
+    &*((*node).nd_private as *const N)
+}
+
+unsafe fn hook_private<'a, N: NodeType>(hook: hook_p) -> (Hook, &'a N) {
+    let hook = Hook(NonNull::new_unchecked(hook));
+    (hook, private::<N>(hook.node().as_ptr()))
+}
+
+unsafe extern "C" fn ng_constructor<N: NodeType>(node: node_p) -> c_int {
+    match N::constructor(Node(NonNull::new_unchecked(node))) {
+        Ok(state) => {
+            (*node).nd_private = Box::into_raw(Box::new(state)) as *mut c_void;
+            0
+        }
+        Err(e) => e.raw(),
+    }
+}
+
+unsafe extern "C" fn ng_shutdown<N: NodeType>(node: node_p) -> c_int {
+    let state = private::<N>(node);
+    state.shutdown(Node(NonNull::new_unchecked(node)));
+    drop(Box::from_raw(state as *const N as *mut N));
+    (*node).nd_private = ptr::null_mut();
+    // NG_NODE_UNREF(), dropping the reference of the constructor
+    kernel_sys::ng_unref_node(node);
+    0
+}
+
+unsafe extern "C" fn ng_newhook<N: NodeType>(
+    node: node_p,
+    hook: hook_p,
+    name: *const c_char,
+) -> c_int {
+    let state = private::<N>(node);
+    let node = Node(NonNull::new_unchecked(node));
+    let hook = Hook(NonNull::new_unchecked(hook));
+    let name = c_str(name).unwrap_or("");
+    Errno::to_ret(state.newhook(node, hook, name))
+}
+
+unsafe extern "C" fn ng_connect<N: NodeType>(hook: hook_p) -> c_int {
+    let (hook, state) = hook_private::<N>(hook);
+    Errno::to_ret(state.connect(hook))
+}
+
+unsafe extern "C" fn ng_rcvdata<N: NodeType>(
+    hook: hook_p,
+    item: item_p,
+) -> c_int {
+    // NGI_GET_M()
+    let m = (*item).body.da_m;
+    (*item).body.da_m = ptr::null_mut();
+    kernel_sys::ng_free_item(item);
+    let (hook, state) = hook_private::<N>(hook);
+    match Mbuf::from_raw(m) {
+        Some(m) => Errno::to_ret(state.rcvdata(hook, m)),
+        None => 0,
+    }
+}
+
+unsafe extern "C" fn ng_rcvmsg<N: NodeType>(
+    node: node_p,
+    item: item_p,
+    lasthook: hook_p,
+) -> c_int {
+    // NGI_GET_MSG()
+    let msg = (*item).body.msg.msg_msg;
+    (*item).body.msg.msg_msg = ptr::null_mut();
+    let msg = match Message::from_raw(msg) {
+        Some(msg) => msg,
+        None => {
+            kernel_sys::ng_free_item(item);
+            return Errno::EINVAL.raw();
+        }
+    };
+    let state = private::<N>(node);
+    let here = Node(NonNull::new_unchecked(node));
+    let lasthook = NonNull::new(lasthook).map(Hook);
+    let (mut error, resp) = match state.rcvmsg(here, &msg, lasthook) {
+        Ok(resp) => (0, resp),
+        Err(e) => (e.raw(), None),
+    };
+    // NG_RESPOND_MSG()
+    match resp {
+        Some(resp) => {
+            let dest = (*item).body.msg.msg_retaddr;
+            (*item).body.msg.msg_retaddr = 0;
+            (*item).body.msg.msg_msg = resp.into_raw();
+            error = kernel_sys::ng_address_ID(node, item, dest, 0);
+            if error == 0 {
+                error = kernel_sys::ng_snd_item(
+                    item,
+                    kernel_sys::NG_QUEUE as c_int,
+                );
+            }
+        }
+        None => kernel_sys::ng_free_item(item),
+    }
+    error
+}
+
+unsafe extern "C" fn ng_disconnect<N: NodeType>(hook: hook_p) -> c_int {
+    let (hook, state) = hook_private::<N>(hook);
+    Errno::to_ret(state.disconnect(hook))
+}
+
+/// The `ng_type` of `N` named `name`, a null-terminated string, with the
+/// commands `cmdlist` (terminated by a zeroed entry)
+pub const fn ng_type<N: NodeType>(
+    name: &'static str,
+    cmdlist: &'static [ng_cmdlist],
+) -> ng_type {
+    ng_type {
+        version: kernel_sys::NG_ABI_VERSION as u32,
+        name: name.as_ptr() as *const c_char,
+        mod_event: None,
+        constructor: Some(ng_constructor::<N>),
+        rcvmsg: Some(ng_rcvmsg::<N>),
+        close: None,
+        shutdown: Some(ng_shutdown::<N>),
+        newhook: Some(ng_newhook::<N>),
+        findhook: None,
+        connect: Some(ng_connect::<N>),
+        rcvdata: Some(ng_rcvdata::<N>),
+        disconnect: Some(ng_disconnect::<N>),
+        cmdlist: cmdlist.as_ptr(),
+        types: kernel_sys::ng_type__bindgen_ty_1 {
+            le_next: ptr::null_mut(),
+            le_prev: ptr::null_mut(),
+        },
+        refs: 0,
+    }
+}
+
+/// An entry of a command list: the command `cmd` of type cookie `cookie`
+/// named `name`, a null-terminated string, with argument and response
+/// types `mesg` and `resp` for converting them to and from ASCII
+pub const fn command(
+    cookie: u32,
+    cmd: u32,
+    name: &'static str,
+    mesg: Option<&'static ng_parse_type>,
+    resp: Option<&'static ng_parse_type>,
+) -> ng_cmdlist {
+    const fn ptr_of(t: Option<&'static ng_parse_type>) -> *const ng_parse_type {
+        match t {
+            Some(t) => t,
+            None => ptr::null(),
+        }
+    }
+    ng_cmdlist {
+        cookie,
+        cmd: cmd as c_int,
+        name: name.as_ptr() as *const c_char,
+        mesgType: ptr_of(mesg),
+        respType: ptr_of(resp),
+    }
+}
+
+/// The entry ending a command list
+pub const COMMAND_END: ng_cmdlist = ng_cmdlist {
+    cookie: 0,
+    cmd: 0,
+    name: ptr::null(),
+    mesgType: ptr::null(),
+    respType: ptr::null(),
+};
+
+/// A field of a structure type named `name`, a null-terminated string
+pub const fn field(
+    name: &'static str,
+    ty: &'static ng_parse_type,
+) -> ng_parse_struct_field {
+    ng_parse_struct_field {
+        name: name.as_ptr() as *const c_char,
+        type_: ty,
+        alignment: 0,
+    }
+}
+
+/// The entry ending a list of structure fields
+pub const FIELD_END: ng_parse_struct_field = ng_parse_struct_field {
+    name: ptr::null(),
+    type_: ptr::null(),
+    alignment: 0,
+};
+
+/// A structure type with the fields `fields` (terminated by `FIELD_END`),
+/// derived from `ng_parse_struct_type`, which is passed as `supertype`
+/// because constant functions cannot refer to statics
+pub const fn parse_struct(
+    supertype: &'static ng_parse_type,
+    fields: &'static [ng_parse_struct_field],
+) -> ng_parse_type {
+    ng_parse_type {
+        supertype,
+        info: fields.as_ptr() as *const c_void,
+        private: ptr::null_mut(),
+        parse: None,
+        unparse: None,
+        getDefault: None,
+        getAlign: None,
+    }
+}
+
+/// The `ng_parse_type` named by `$ty`: `none`, one of the integer types
+/// (e.g. `uint32`), `string`, or one declared with `ng_parse_struct!`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __ng_parse_type {
+    (none) => {
+        None
+    };
+    (int8) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_int8_type })
+    };
+    (int16) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_int16_type })
+    };
+    (int32) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_int32_type })
+    };
+    (int64) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_int64_type })
+    };
+    (uint8) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_uint8_type })
+    };
+    (uint16) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_uint16_type })
+    };
+    (uint32) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_uint32_type })
+    };
+    (uint64) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_uint64_type })
+    };
+    (string) => {
+        Some(unsafe { &$crate::kernel_sys::ng_parse_string_type })
+    };
+    ($ty:expr) => {
+        Some(&$ty.0)
+    };
+}
+
+/// Declare `$name`, a `Static<ng_parse_type>` for a `#[repr(C)]`
+/// structure with the given fields and their types (as for
+/// `netgraph_type!`), so that it can be used as a message type
+///
+/// ```rust,ignore
+/// bsd_kernel::ng_parse_struct!(
+///     ECHO_STATS_TYPE,
+///     [(packets, uint64), (delay, uint32)]
+/// );
+/// ```
+#[macro_export]
+macro_rules! ng_parse_struct {
+    ($name:ident, [$(($field:ident, $ty:tt)),* $(,)?]) => {
+        static $name: $crate::linker_set::Static<
+            $crate::kernel_sys::ng_parse_type,
+        > = $crate::linker_set::Static($crate::net::netgraph::parse_struct(
+            unsafe { &$crate::kernel_sys::ng_parse_struct_type },
+            {
+                static FIELDS: $crate::linker_set::Static<
+                    [$crate::kernel_sys::ng_parse_struct_field;
+                        <[&str]>::len(&[$(stringify!($field)),*]) + 1],
+                > = $crate::linker_set::Static([
+                    $($crate::net::netgraph::field(
+                        $crate::cstr!(stringify!($field)),
+                        match $crate::__ng_parse_type!($ty) {
+                            Some(ty) => ty,
+                            None => panic!("fields need a type"),
+                        },
+                    ),)*
+                    $crate::net::netgraph::FIELD_END,
+                ]);
+                &FIELDS.0
+            },
+        ));
+    };
+}
+
+/// Register `$node`, a `NodeType`, as the netgraph node type `$name`,
+/// i.e. `NETGRAPH_INIT()`. Each command is given as `(cookie, cmd,
+/// "name", mesg, resp)`, with the argument and response types given as
+/// for `ng_parse_struct!` or `none`.
+///
+/// Like in C, the module is named `ng_$name` and depends on `netgraph`.
+#[macro_export]
+macro_rules! netgraph_type {
+    ($name:ident, $node:ty) => {
+        $crate::netgraph_type!($name, $node, []);
+    };
+    ($name:ident, $node:ty, [$(($cookie:expr, $cmd:expr, $cmdname:literal,
+        $mesg:tt, $resp:tt)),* $(,)?]) => {
+        const _: () = {
+            static CMDLIST: $crate::linker_set::Static<
+                [$crate::kernel_sys::ng_cmdlist;
+                    <[&str]>::len(&[$($cmdname),*]) + 1],
+            > = $crate::linker_set::Static([
+                $($crate::net::netgraph::command(
+                    $cookie,
+                    $cmd,
+                    $crate::cstr!($cmdname),
+                    $crate::__ng_parse_type!($mesg),
+                    $crate::__ng_parse_type!($resp),
+                ),)*
+                $crate::net::netgraph::COMMAND_END,
+            ]);
+            // The kernel links this into its list of types
+            static TYPE: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::ng_type,
+            > = $crate::linker_set::StaticMut::new(
+                $crate::net::netgraph::ng_type::<$node>(
+                    $crate::cstr!(stringify!($name)),
+                    &CMDLIST.0,
+                ),
+            );
+
+            static MODULE_DATA: $crate::linker_set::Static<
+                $crate::kernel_sys::moduledata_t,
+            > = $crate::linker_set::Static($crate::kernel_sys::moduledata_t {
+                name: $crate::cstr!(concat!("ng_", stringify!($name))).as_ptr()
+                    as *const $crate::libc::c_char,
+                evhand: Some($crate::kernel_sys::ng_mod_event),
+                priv_: TYPE.get() as *mut $crate::libc::c_void,
+            });
+
+            $crate::__declare_module!(
+                concat!("ng_", stringify!($name)),
+                MODULE_DATA,
+                $crate::sysinit::Subsystem::Pseudo,
+                $crate::sysinit::Order::Middle
+            );
+            $crate::module_depend!(
+                netgraph,
+                $crate::kernel_sys::NG_ABI_VERSION as _,
+                $crate::kernel_sys::NG_ABI_VERSION as _,
+                $crate::kernel_sys::NG_ABI_VERSION as _
+            );
+        };
+    };
+}
@@ -0,0 +1,300 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Packet filter hooks, see `pfil(9)`
+//!
+//! A `PfilHook` runs a closure for every packet passing through the IPv4,
+//! IPv6 or Ethernet layer of the current vnet until it is dropped:
+//!
+//! ```rust,ignore
+//! use bsd_kernel::net::pfil::{PfilHook, PfilReturn, PfilType, PFIL_IN};
+//! use bsd_kernel::net::header::IPPROTO_UDP;
+//! use bsd_kernel::net::Ip4Header;
+//!
+//! let hook = PfilHook::new(
+//!     PfilType::Ip4,
+//!     "rfw",
+//!     "default",
+//!     PFIL_IN,
+//!     Box::new(|m, _dir, _ifp| {
+//!         let ip = m.header::<Ip4Header>(0);
+//!         if ip.map_or(false, |ip| ip.protocol == IPPROTO_UDP) {
+//!             PfilReturn::Dropped
+//!         } else {
+//!             PfilReturn::Pass(m)
+//!         }
+//!     }),
+//! )?;
+//! ```
+
+use super::ifnet::Ifnet;
+use super::mbuf::Mbuf;
+use crate::cstr_ref;
+use crate::error::Errno;
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::mem::align_of;
+use core::{fmt, ptr};
+use kernel_sys::{mbuf, pfil_hook_t, pfil_packet_t, pfil_return_t};
+use libc::{c_char, c_int, c_void};
+
+/// Hook inbound packets
+pub const PFIL_IN: c_int = kernel_sys::PFIL_IN as c_int;
+/// Hook outbound packets
+pub const PFIL_OUT: c_int = kernel_sys::PFIL_OUT as c_int;
+
+const PFIL_MEMPTR: c_int = kernel_sys::PFIL_MEMPTR as c_int;
+const PFIL_LENMASK: c_int = kernel_sys::PFIL_LENMASK as c_int;
+
+/// `struct pfil_link_args`, whose anonymous unions do not make it through
+/// bindgen in a usable form
+#[repr(C)]
+struct LinkArgs {
+    version: c_int,
+    flags: c_int,
+    headname: *const c_char,
+    hook: pfil_hook_t,
+    // The hook union also holds the module and rule names
+    _rulname: *const c_char,
+}
+
+extern "C" {
+    fn pfil_link(args: *mut LinkArgs) -> c_int;
+}
+
+/// The layer a hook is added to
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PfilType {
+    /// IPv4 packets, starting at the IP header
+    Ip4,
+    /// IPv6 packets, starting at the IPv6 header
+    Ip6,
+    /// Ethernet frames, starting at the Ethernet header
+    Ethernet,
+}
+
+impl PfilType {
+    fn raw(self) -> kernel_sys::pfil_types {
+        match self {
+            PfilType::Ip4 => kernel_sys::pfil_types_PFIL_TYPE_IP4,
+            PfilType::Ip6 => kernel_sys::pfil_types_PFIL_TYPE_IP6,
+            PfilType::Ethernet => kernel_sys::pfil_types_PFIL_TYPE_ETHERNET,
+        }
+    }
+
+    /// The name of the head the hook is linked to, null-terminated
+    fn head(self) -> &'static str {
+        match self {
+            PfilType::Ip4 => "inet\0",
+            PfilType::Ip6 => "inet6\0",
+            PfilType::Ethernet => "ethernet\0",
+        }
+    }
+}
+
+/// The direction a packet is travelling in
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Direction {
+    In,
+    Out,
+}
+
+/// What became of a packet passed to a hook
+#[derive(Debug)]
+pub enum PfilReturn {
+    /// Let the packet continue, i.e. `PFIL_PASS`
+    Pass(Mbuf),
+    /// The packet was dropped (and freed), i.e. `PFIL_DROPPED`
+    Dropped,
+    /// The hook kept the packet, e.g. to reinject it later, i.e.
+    /// `PFIL_CONSUMED`
+    Consumed,
+}
+
+/// The closure run for each packet, with the interface it is received
+/// on or sent through. It may be called concurrently, in the network
+/// epoch.
+pub type Handler =
+    dyn Fn(Mbuf, Direction, Option<Ifnet>) -> PfilReturn + Send + Sync;
+
+unsafe extern "C" fn pfil_func(
+    p: pfil_packet_t,
+    ifp: *mut kernel_sys::ifnet,
+    flags: c_int,
+    ruleset: *mut c_void,
+    _inp: *mut kernel_sys::inpcb,
+) -> pfil_return_t {
+    let dir = if flags & PFIL_IN != 0 {
+        Direction::In
+    } else {
+        Direction::Out
+    };
+    let handler = &*(ruleset as *const Box<Handler>);
+    if flags & PFIL_MEMPTR != 0 {
+        return run_memptr(p, ifp, flags, dir, &**handler);
+    }
+    let m = match Mbuf::from_raw(*p.m) {
+        Some(m) => m,
+        None => return kernel_sys::pfil_return_t_PFIL_PASS,
+    };
+    match handler(m, dir, Ifnet::from_ptr(ifp)) {
+        PfilReturn::Pass(m) => {
+            // The hook may have replaced the mbuf, e.g. by pulling it up
+            *p.m = m.into_raw();
+            kernel_sys::pfil_return_t_PFIL_PASS
+        }
+        PfilReturn::Dropped => {
+            *p.m = ptr::null_mut();
+            kernel_sys::pfil_return_t_PFIL_DROPPED
+        }
+        PfilReturn::Consumed => {
+            *p.m = ptr::null_mut();
+            kernel_sys::pfil_return_t_PFIL_CONSUMED
+        }
+    }
+}
+
+/// Run `handler` on a packet that a driver passed in its own memory, e.g.
+/// an `iflib(4)` receive buffer. The handler is given a copy in an mbuf,
+/// which replaces the driver memory if the packet passes.
+unsafe fn run_memptr(
+    p: pfil_packet_t,
+    ifp: *mut kernel_sys::ifnet,
+    flags: c_int,
+    dir: Direction,
+    handler: &Handler,
+) -> pfil_return_t {
+    let len = flags & PFIL_LENMASK;
+    let m = kernel_sys::m_devget(p.mem as *mut c_char, len, 0, ifp, None);
+    let m = match Mbuf::from_raw(m) {
+        Some(m) => m,
+        // Without memory for the copy the packet cannot be filtered
+        None => return kernel_sys::pfil_return_t_PFIL_DROPPED,
+    };
+    match handler(m, dir, Ifnet::from_ptr(ifp)) {
+        PfilReturn::Pass(m) => {
+            // Where pfil_mem2mbuf() looks for the mbuf, i.e.
+            // pfil_packet_align()
+            let align = align_of::<*mut mbuf>();
+            let slot = (p.mem as usize + align - 1) & !(align - 1);
+            *(slot as *mut *mut mbuf) = m.into_raw();
+            kernel_sys::pfil_return_t_PFIL_REALLOCED
+        }
+        PfilReturn::Dropped => kernel_sys::pfil_return_t_PFIL_DROPPED,
+        PfilReturn::Consumed => kernel_sys::pfil_return_t_PFIL_CONSUMED,
+    }
+}
+
+/// A closure hooked into a packet path, unlinked and removed when dropped
+pub struct PfilHook {
+    hook: pfil_hook_t,
+    ty: PfilType,
+    flags: c_int,
+    // Referenced by the hook until it is removed
+    modname: String,
+    rulname: String,
+    // Boxed twice so that the kernel can be given a thin pointer
+    _handler: Box<Box<Handler>>,
+}
+
+impl PfilHook {
+    /// Add a hook named `modname:rulname` and link it to the head for
+    /// `ty` for packets in the directions in `flags` (`PFIL_IN` and/or
+    /// `PFIL_OUT`). Returns `ENOENT` if there is no such head.
+    ///
+    /// Packets that drivers pass in their own memory rather than in mbufs
+    /// (`PFIL_MEMPTR`) are copied into an mbuf for the handler, which is
+    /// handed back to the driver if the packet passes.
+    pub fn new(
+        ty: PfilType,
+        modname: &str,
+        rulname: &str,
+        flags: c_int,
+        handler: Box<Handler>,
+    ) -> Result<PfilHook, Errno> {
+        let flags = flags & (PFIL_IN | PFIL_OUT);
+        let handler = Box::new(handler);
+        let modname = cstr_ref!(modname).clone();
+        let rulname = cstr_ref!(rulname).clone();
+        let mut args = kernel_sys::pfil_hook_args {
+            pa_version: kernel_sys::PFIL_VERSION as c_int,
+            // Take packets in driver memory as they are, see `run_memptr`
+            pa_flags: flags | PFIL_MEMPTR,
+            pa_type: ty.raw(),
+            pa_func: Some(pfil_func),
+            pa_ruleset: &*handler as *const Box<Handler> as *mut c_void,
+            pa_modname: modname.as_ptr() as *const c_char,
+            pa_rulname: rulname.as_ptr() as *const c_char,
+        };
+        let hook = unsafe { kernel_sys::pfil_add_hook(&mut args) };
+        let hook = PfilHook {
+            hook,
+            ty,
+            flags,
+            modname,
+            rulname,
+            _handler: handler,
+        };
+        Errno::result(hook.link(kernel_sys::PFIL_APPEND as c_int))?;
+        Ok(hook)
+    }
+
+    fn link(&self, flags: c_int) -> c_int {
+        let mut args = LinkArgs {
+            version: kernel_sys::PFIL_VERSION as c_int,
+            flags: self.flags | flags | kernel_sys::PFIL_HOOKPTR as c_int,
+            headname: self.ty.head().as_ptr() as *const c_char,
+            hook: self.hook,
+            _rulname: ptr::null(),
+        };
+        unsafe { pfil_link(&mut args) }
+    }
+}
+
+impl fmt::Debug for PfilHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "PfilHook {{ type: {:?}, name: {}:{} }}",
+            self.ty,
+            self.modname.trim_end_matches('\0'),
+            self.rulname.trim_end_matches('\0')
+        )
+    }
+}
+
+impl Drop for PfilHook {
+    fn drop(&mut self) {
+        // Unlinking fails if linking did, which is fine
+        self.link(kernel_sys::PFIL_UNLINK as c_int);
+        unsafe {
+            kernel_sys::pfil_remove_hook(self.hook);
+            // The hook may still be running on other CPUs until the
+            // network epoch ends, i.e. NET_EPOCH_WAIT()
+            kernel_sys::epoch_wait_preempt(kernel_sys::net_epoch_preempt);
+        }
+    }
+}
+
+unsafe impl Send for PfilHook {}
+unsafe impl Sync for PfilHook {}
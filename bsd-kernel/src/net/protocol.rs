@@ -0,0 +1,497 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! New socket protocol families, see `domain(9)` and `protosw`
+//!
+//! A datagram protocol implements `Protocol`, and `protocol_domain!`
+//! registers it as the only protocol of a new address family, so that
+//! `socket(AF_X, SOCK_DGRAM, 0)` in user space creates a socket handled by
+//! it. Passing `Protocol::PROTOCOL` instead of 0 works too. Like in C, a
+//! domain cannot be removed again, so the module it is declared in refuses
+//! to unload.
+//!
+//! ```rust,ignore
+//! struct Echo;
+//!
+//! impl Protocol for Echo {
+//!     fn attach(_so: Socket, _proto: c_int) -> Result<Self, Errno> {
+//!         Ok(Echo)
+//!     }
+//!
+//!     fn send(
+//!         &self,
+//!         so: Socket,
+//!         m: Mbuf,
+//!         addr: Option<&sockaddr>,
+//!         _control: Option<Mbuf>,
+//!     ) -> Result<(), Errno> {
+//!         let addr = addr.ok_or(Errno::EINVAL)?;
+//!         so.deliver(m, addr)
+//!     }
+//! }
+//!
+//! bsd_kernel::protocol_domain!(echo, 42, Echo);
+//! ```
+
+use super::ifnet::{Ifnet, IoctlData};
+use super::mbuf::Mbuf;
+use crate::error::Errno;
+use crate::linker_set::StaticMut;
+use crate::{mtx_lock, mtx_unlock};
+use alloc::boxed::Box;
+use core::ptr::{self, NonNull};
+use kernel_sys::{
+    caddr_t, domain, mbuf, pr_usrreqs, protosw, sockaddr, socket, thread, uio,
+};
+use libc::{c_char, c_int, c_ulong, c_void};
+
+/// A socket of a `Protocol`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Socket(NonNull<socket>);
+
+impl Socket {
+    pub fn as_ptr(self) -> *mut socket {
+        self.0.as_ptr()
+    }
+
+    /// Mark the socket connected, e.g. from `Protocol::connect`, i.e.
+    /// `soisconnected()`
+    pub fn set_connected(self) {
+        unsafe { kernel_sys::soisconnected(self.as_ptr()) };
+    }
+
+    /// Mark the socket disconnected, i.e. `soisdisconnected()`
+    pub fn set_disconnected(self) {
+        unsafe { kernel_sys::soisdisconnected(self.as_ptr()) };
+    }
+
+    /// Queue the datagram `m` from `from` for receiving and wake up
+    /// readers. Returns `ENOBUFS` and drops `m` if the receive buffer is
+    /// full.
+    pub fn deliver(self, m: Mbuf, from: &sockaddr) -> Result<(), Errno> {
+        let so = self.as_ptr();
+        unsafe {
+            // so_rcv is in an anonymous union with the fields of
+            // listening sockets
+            let sb = &mut (*so).__bindgen_anon_1.__bindgen_anon_1.so_rcv;
+            mtx_lock(&mut sb.sb_mtx);
+            let m = m.into_raw();
+            if kernel_sys::sbappendaddr_locked(sb, from, m, ptr::null_mut())
+                == 0
+            {
+                mtx_unlock(&mut sb.sb_mtx);
+                drop(Mbuf::from_raw(m));
+                return Err(Errno::ENOBUFS);
+            }
+            // sorwakeup_locked(), which drops the lock
+            kernel_sys::sowakeup(so, sb);
+        }
+        Ok(())
+    }
+}
+
+unsafe impl Send for Socket {}
+unsafe impl Sync for Socket {}
+
+/// The arguments of `pru_soreceive`, for protocols that receive data in
+/// their own way
+#[derive(Debug)]
+pub struct Receive {
+    so: Socket,
+    psa: *mut *mut sockaddr,
+    uio: *mut uio,
+    mp0: *mut *mut mbuf,
+    controlp: *mut *mut mbuf,
+    flagsp: *mut c_int,
+}
+
+impl Receive {
+    /// Where the data goes
+    pub fn uio(&self) -> *mut uio {
+        self.uio
+    }
+
+    /// The `MSG_*` flags of the receive call
+    pub fn flags(&self) -> c_int {
+        if self.flagsp.is_null() {
+            0
+        } else {
+            unsafe { *self.flagsp }
+        }
+    }
+
+    /// Receive from the socket buffer, i.e. `soreceive_generic()`
+    pub fn generic(self) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::soreceive_generic(
+                self.so.as_ptr(),
+                self.psa,
+                self.uio,
+                self.mp0,
+                self.controlp,
+                self.flagsp,
+            )
+        })
+    }
+}
+
+/// A datagram socket protocol, i.e. the `pr_usrreqs` of a `protosw`
+///
+/// A value is created for each socket by `attach` and dropped when the
+/// socket is closed. The methods may be called concurrently for different
+/// sockets.
+pub trait Protocol: Sized + Send + Sync + 'static {
+    /// The protocol number, i.e. `pr_protocol`
+    const PROTOCOL: c_int = 0;
+    /// Space reserved for sending, in bytes
+    const SEND_SPACE: c_ulong = 9216;
+    /// Space reserved for received datagrams, in bytes
+    const RECV_SPACE: c_ulong = 41600;
+
+    /// Set up a new socket, i.e. `pru_attach`. `proto` is the protocol
+    /// number passed to `socket(2)`, which is either 0 or `PROTOCOL`.
+    fn attach(so: Socket, proto: c_int) -> Result<Self, Errno>;
+
+    /// The socket is being closed, i.e. `pru_detach`, after which `self`
+    /// is dropped
+    fn detach(&self, _so: Socket) {}
+
+    /// `bind(2)`, i.e. `pru_bind`
+    fn bind(&self, _so: Socket, _addr: &sockaddr) -> Result<(), Errno> {
+        Err(Errno::EOPNOTSUPP)
+    }
+
+    /// `connect(2)`, i.e. `pru_connect`. Call `Socket::set_connected` on
+    /// success so that datagrams can be sent without an address.
+    fn connect(&self, _so: Socket, _addr: &sockaddr) -> Result<(), Errno> {
+        Err(Errno::EOPNOTSUPP)
+    }
+
+    /// Send the datagram `m` to `addr`, or to the connected address if
+    /// `None`, i.e. `pru_send`
+    fn send(
+        &self,
+        so: Socket,
+        m: Mbuf,
+        addr: Option<&sockaddr>,
+        control: Option<Mbuf>,
+    ) -> Result<(), Errno>;
+
+    /// Receive data, i.e. `pru_soreceive`. By default datagrams queued by
+    /// `Socket::deliver` are received.
+    fn soreceive(&self, _so: Socket, rcv: Receive) -> Result<(), Errno> {
+        rcv.generic()
+    }
+
+    /// Handle an `ioctl(2)` on the socket, i.e. `pru_control`
+    fn control(
+        &self,
+        _so: Socket,
+        _cmd: c_ulong,
+        _data: IoctlData,
+        _ifp: Option<Ifnet>,
+    ) -> Result<(), Errno> {
+        Err(Errno::EOPNOTSUPP)
+    }
+}
+
+unsafe fn pcb<'a, P: Protocol>(so: *mut socket) -> (Socket, &'a P) {
+    let sock = Socket(NonNull::new_unchecked(so));
+    (sock, &*((*so).so_pcb as *const P))
+}
+
+unsafe extern "C" fn pru_attach<P: Protocol>(
+    so: *mut socket,
+    proto: c_int,
+    _td: *mut thread,
+) -> c_int {
+    let ret = kernel_sys::soreserve(so, P::SEND_SPACE, P::RECV_SPACE);
+    if ret != 0 {
+        return ret;
+    }
+    match P::attach(Socket(NonNull::new_unchecked(so)), proto) {
+        Ok(pcb) => {
+            (*so).so_pcb = Box::into_raw(Box::new(pcb)) as *mut c_void;
+            0
+        }
+        Err(e) => e.raw(),
+    }
+}
+
+unsafe extern "C" fn pru_detach<P: Protocol>(so: *mut socket) {
+    let (sock, pcb) = pcb::<P>(so);
+    pcb.detach(sock);
+    drop(Box::from_raw(pcb as *const P as *mut P));
+    (*so).so_pcb = ptr::null_mut();
+}
+
+unsafe extern "C" fn pru_bind<P: Protocol>(
+    so: *mut socket,
+    nam: *mut sockaddr,
+    _td: *mut thread,
+) -> c_int {
+    let (sock, pcb) = pcb::<P>(so);
+    Errno::to_ret(pcb.bind(sock, &*nam))
+}
+
+unsafe extern "C" fn pru_connect<P: Protocol>(
+    so: *mut socket,
+    nam: *mut sockaddr,
+    _td: *mut thread,
+) -> c_int {
+    let (sock, pcb) = pcb::<P>(so);
+    Errno::to_ret(pcb.connect(sock, &*nam))
+}
+
+unsafe extern "C" fn pru_control<P: Protocol>(
+    so: *mut socket,
+    cmd: c_ulong,
+    data: caddr_t,
+    ifp: *mut kernel_sys::ifnet,
+    _td: *mut thread,
+) -> c_int {
+    let (sock, pcb) = pcb::<P>(so);
+    let ifp = Ifnet::from_ptr(ifp);
+    Errno::to_ret(pcb.control(sock, cmd, IoctlData(data), ifp))
+}
+
+unsafe extern "C" fn pru_send<P: Protocol>(
+    so: *mut socket,
+    _flags: c_int,
+    m: *mut mbuf,
+    addr: *mut sockaddr,
+    control: *mut mbuf,
+    _td: *mut thread,
+) -> c_int {
+    // Both mbufs belong to the protocol, whatever happens
+    let m = Mbuf::from_raw(m);
+    let control = Mbuf::from_raw(control);
+    let (sock, pcb) = pcb::<P>(so);
+    match m {
+        Some(m) => Errno::to_ret(pcb.send(sock, m, addr.as_ref(), control)),
+        None => Errno::EINVAL.raw(),
+    }
+}
+
+unsafe extern "C" fn pru_soreceive<P: Protocol>(
+    so: *mut socket,
+    psa: *mut *mut sockaddr,
+    uio: *mut uio,
+    mp0: *mut *mut mbuf,
+    controlp: *mut *mut mbuf,
+    flagsp: *mut c_int,
+) -> c_int {
+    let (sock, pcb) = pcb::<P>(so);
+    let rcv = Receive {
+        so: sock,
+        psa,
+        uio,
+        mp0,
+        controlp,
+        flagsp,
+    };
+    Errno::to_ret(pcb.soreceive(sock, rcv))
+}
+
+/// The `pr_usrreqs` of `P`. The operations left out are filled in with
+/// the defaults when the domain is initialised.
+pub const fn usrreqs<P: Protocol>() -> pr_usrreqs {
+    pr_usrreqs {
+        pru_abort: None,
+        pru_accept: None,
+        pru_attach: Some(pru_attach::<P>),
+        pru_bind: Some(pru_bind::<P>),
+        pru_connect: Some(pru_connect::<P>),
+        pru_connect2: None,
+        pru_control: Some(pru_control::<P>),
+        pru_detach: Some(pru_detach::<P>),
+        pru_disconnect: None,
+        pru_listen: None,
+        pru_peeraddr: None,
+        pru_rcvd: None,
+        pru_rcvoob: None,
+        pru_send: Some(pru_send::<P>),
+        pru_ready: None,
+        pru_sense: None,
+        pru_shutdown: None,
+        pru_flush: None,
+        pru_sockaddr: None,
+        pru_sosend: None,
+        pru_soreceive: Some(pru_soreceive::<P>),
+        pru_sopoll: None,
+        pru_sosetlabel: None,
+        pru_close: None,
+        pru_bindat: None,
+        pru_connectat: None,
+        pru_aio_queue: None,
+    }
+}
+
+/// The `protosw` of datagram protocol number `protocol` in `domain`.
+/// `socket(2)` also selects it for protocol 0, as the only datagram
+/// protocol of the domain.
+pub const fn protosw(
+    domain: &'static StaticMut<domain>,
+    protocol: c_int,
+    usrreqs: &'static StaticMut<pr_usrreqs>,
+) -> protosw {
+    protosw {
+        pr_type: kernel_sys::SOCK_DGRAM as _,
+        pr_domain: domain.get(),
+        pr_protocol: protocol as _,
+        pr_flags: (kernel_sys::PR_ATOMIC | kernel_sys::PR_ADDR) as _,
+        pr_input: None,
+        pr_output: None,
+        pr_ctlinput: None,
+        pr_ctloutput: None,
+        pr_init: None,
+        pr_fasttimo: None,
+        pr_slowtimo: None,
+        pr_drain: None,
+        pr_usrreqs: usrreqs.get(),
+    }
+}
+
+/// The `domain` for address family `family` named `name`, a
+/// null-terminated string, with the protocol `protosw`
+pub const fn domain(
+    family: c_int,
+    name: &'static str,
+    protosw: &'static StaticMut<protosw>,
+) -> domain {
+    domain {
+        dom_family: family,
+        dom_name: name.as_ptr() as *mut c_char,
+        dom_init: None,
+        dom_destroy: None,
+        dom_externalize: None,
+        dom_dispose: None,
+        dom_protosw: protosw.get(),
+        dom_protoswNPROTOSW: unsafe { protosw.get().add(1) },
+        dom_next: ptr::null_mut(),
+        dom_rtattach: None,
+        dom_rtdetach: None,
+        dom_ifattach: None,
+        dom_ifdetach: None,
+        dom_ifmtu: None,
+    }
+}
+
+/// `domain_add()` as a `sysinit` function
+///
+/// # Safety
+/// `dp` must point to a `domain` that is never freed.
+pub unsafe extern "C" fn domain_add(dp: *const c_void) {
+    kernel_sys::domain_add(dp as *mut c_void);
+}
+
+/// `domain_init()` as a `sysinit` function
+///
+/// # Safety
+/// `dp` must point to a `domain` added by `domain_add`.
+pub unsafe extern "C" fn domain_init(dp: *const c_void) {
+    kernel_sys::domain_init(dp as *mut c_void);
+}
+
+/// The event handler of the module declaring a domain, which cannot be
+/// unloaded
+pub extern "C" fn domain_module_handler(
+    _module: kernel_sys::module_t,
+    event: c_int,
+    _arg: *mut c_void,
+) -> c_int {
+    match event as u32 {
+        kernel_sys::modeventtype_MOD_LOAD
+        | kernel_sys::modeventtype_MOD_SHUTDOWN => 0,
+        kernel_sys::modeventtype_MOD_QUIESCE
+        | kernel_sys::modeventtype_MOD_UNLOAD => Errno::EBUSY.raw(),
+        _ => Errno::EOPNOTSUPP.raw(),
+    }
+}
+
+/// Register `$protocol`, a `Protocol`, as the datagram protocol of a new
+/// domain named `$name` for address family `$family`, i.e.
+/// `DOMAIN_SET()`. This also declares a module named `$name`, which
+/// cannot be unloaded.
+#[macro_export]
+macro_rules! protocol_domain {
+    ($name:ident, $family:expr, $protocol:ty) => {
+        const _: () = {
+            // The kernel links these into its lists of domains
+            static USRREQS: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::pr_usrreqs,
+            > = $crate::linker_set::StaticMut::new(
+                $crate::net::protocol::usrreqs::<$protocol>(),
+            );
+            static PROTOSW: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::protosw,
+            > = $crate::linker_set::StaticMut::new(
+                $crate::net::protocol::protosw(
+                    &DOMAIN,
+                    <$protocol as $crate::net::protocol::Protocol>::PROTOCOL,
+                    &USRREQS,
+                ),
+            );
+            static DOMAIN: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::domain,
+            > = $crate::linker_set::StaticMut::new(
+                $crate::net::protocol::domain(
+                    $family,
+                    $crate::cstr!(stringify!($name)),
+                    &PROTOSW,
+                ),
+            );
+
+            $crate::__sysinit!(
+                "set_sysinit_set",
+                $crate::sysinit::Subsystem::ProtoDomain,
+                $crate::sysinit::Order::First,
+                $crate::net::protocol::domain_add,
+                &DOMAIN
+            );
+            $crate::__sysinit!(
+                "set_sysinit_set",
+                $crate::sysinit::Subsystem::ProtoDomain,
+                $crate::sysinit::Order::Second,
+                $crate::net::protocol::domain_init,
+                &DOMAIN
+            );
+
+            static MODULE_DATA: $crate::linker_set::Static<
+                $crate::kernel_sys::moduledata_t,
+            > = $crate::linker_set::Static($crate::kernel_sys::moduledata_t {
+                name: $crate::cstr!(stringify!($name)).as_ptr()
+                    as *const $crate::libc::c_char,
+                evhand: Some($crate::net::protocol::domain_module_handler as _),
+                priv_: ::core::ptr::null_mut(),
+            });
+
+            $crate::__declare_module!(
+                stringify!($name),
+                MODULE_DATA,
+                $crate::sysinit::Subsystem::ProtoDomain,
+                $crate::sysinit::Order::Any
+            );
+        };
+    };
+}
@@ -0,0 +1,522 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Sockets used from within the kernel, see `socket(9)`
+//!
+//! A `KernelSocket` is closed when dropped. Operations that may block take
+//! a `Sleepable` context, as they must be called from a context that may
+//! sleep. `KernelSocket::stream` pairs a stream socket with such a context
+//! for `io::Read` and `io::Write`.
+//!
+//! ```rust,ignore
+//! use bsd_kernel::io::Write;
+//! use bsd_kernel::net::{Domain, KernelSocket, SocketAddr, SocketType};
+//!
+//! let sock = KernelSocket::new(ctx, Domain::Inet, SocketType::Stream)?;
+//! sock.connect(ctx, &SocketAddr::V4 { addr: [127, 0, 0, 1], port: 9000 })?;
+//! writeln!(sock.stream(ctx), "uptime {}", uptime)?;
+//! ```
+
+use crate::allocator::Sleepable;
+use crate::error::Errno;
+use crate::io::{self, Read, Write};
+use crate::{curthread, mtx_lock, mtx_unlock};
+use alloc::string::String;
+use core::mem::{self, size_of};
+use core::ptr::{self, NonNull};
+use kernel_sys::{sockaddr, socket};
+use libc::{c_char, c_int, c_void};
+
+const ERESTART: c_int = -1;
+
+/// The protocol family of a socket
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Domain {
+    /// IPv4
+    Inet,
+    /// IPv6
+    Inet6,
+    /// Unix domain sockets, named by paths in the file system
+    Local,
+}
+
+impl Domain {
+    fn raw(self) -> c_int {
+        (match self {
+            Domain::Inet => kernel_sys::AF_INET,
+            Domain::Inet6 => kernel_sys::AF_INET6,
+            Domain::Local => kernel_sys::AF_LOCAL,
+        }) as c_int
+    }
+}
+
+/// The kind of socket, which also selects the default protocol of the
+/// domain, e.g. TCP or UDP
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SocketType {
+    /// Reliable byte streams, e.g. TCP
+    Stream,
+    /// Datagrams, e.g. UDP
+    Datagram,
+}
+
+impl SocketType {
+    fn raw(self) -> c_int {
+        (match self {
+            SocketType::Stream => kernel_sys::SOCK_STREAM,
+            SocketType::Datagram => kernel_sys::SOCK_DGRAM,
+        }) as c_int
+    }
+}
+
+/// The address of a socket. IP addresses are in network byte order.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SocketAddr {
+    V4 { addr: [u8; 4], port: u16 },
+    V6 { addr: [u8; 16], port: u16 },
+    Unix(String),
+}
+
+impl SocketAddr {
+    fn to_raw(&self) -> Result<kernel_sys::sockaddr_storage, Errno> {
+        let mut ss: kernel_sys::sockaddr_storage = unsafe { mem::zeroed() };
+        let ptr = &mut ss as *mut kernel_sys::sockaddr_storage;
+        match self {
+            SocketAddr::V4 { addr, port } => {
+                let sin = ptr as *mut kernel_sys::sockaddr_in;
+                unsafe {
+                    (*sin).sin_len = size_of::<kernel_sys::sockaddr_in>() as u8;
+                    (*sin).sin_family = kernel_sys::AF_INET as u8;
+                    (*sin).sin_port = port.to_be();
+                    (*sin).sin_addr.s_addr = u32::from_ne_bytes(*addr);
+                }
+            }
+            SocketAddr::V6 { addr, port } => {
+                let sin6 = ptr as *mut kernel_sys::sockaddr_in6;
+                unsafe {
+                    (*sin6).sin6_len =
+                        size_of::<kernel_sys::sockaddr_in6>() as u8;
+                    (*sin6).sin6_family = kernel_sys::AF_INET6 as u8;
+                    (*sin6).sin6_port = port.to_be();
+                    (*sin6).sin6_addr.__u6_addr.__u6_addr8 = *addr;
+                }
+            }
+            SocketAddr::Unix(path) => {
+                let sun = ptr as *mut kernel_sys::sockaddr_un;
+                let sun_path = unsafe { &mut (*sun).sun_path };
+                // Leave room for the terminating null
+                if path.len() >= sun_path.len() || path.contains('\0') {
+                    return Err(Errno::EINVAL);
+                }
+                for (dst, src) in sun_path.iter_mut().zip(path.bytes()) {
+                    *dst = src as c_char;
+                }
+                unsafe {
+                    // SUN_LEN()
+                    (*sun).sun_len = (2 + path.len()) as u8;
+                    (*sun).sun_family = kernel_sys::AF_LOCAL as u8;
+                }
+            }
+        }
+        Ok(ss)
+    }
+
+    /// # Safety
+    /// `sa` must be null or point to a valid socket address.
+    unsafe fn from_raw(sa: *const sockaddr) -> Option<SocketAddr> {
+        if sa.is_null() {
+            return None;
+        }
+        match c_int::from((*sa).sa_family) {
+            kernel_sys::AF_INET => {
+                let sin = &*(sa as *const kernel_sys::sockaddr_in);
+                Some(SocketAddr::V4 {
+                    addr: sin.sin_addr.s_addr.to_ne_bytes(),
+                    port: u16::from_be(sin.sin_port),
+                })
+            }
+            kernel_sys::AF_INET6 => {
+                let sin6 = &*(sa as *const kernel_sys::sockaddr_in6);
+                Some(SocketAddr::V6 {
+                    addr: sin6.sin6_addr.__u6_addr.__u6_addr8,
+                    port: u16::from_be(sin6.sin6_port),
+                })
+            }
+            kernel_sys::AF_LOCAL => {
+                let sun = &*(sa as *const kernel_sys::sockaddr_un);
+                let len = (sun.sun_len as usize)
+                    .saturating_sub(2)
+                    .min(sun.sun_path.len());
+                let path = &*(&sun.sun_path[..len] as *const [c_char]
+                    as *const [u8]);
+                let path = match path.iter().position(|&b| b == 0) {
+                    Some(end) => &path[..end],
+                    None => path,
+                };
+                Some(SocketAddr::Unix(
+                    String::from_utf8_lossy(path).into_owned(),
+                ))
+            }
+            _ => None,
+        }
+    }
+
+    /// Take ownership of a socket address allocated by the kernel
+    unsafe fn from_soname(sa: *mut sockaddr) -> Option<SocketAddr> {
+        let addr = SocketAddr::from_raw(sa);
+        if !sa.is_null() {
+            kernel_sys::free(sa as *mut c_void, &mut kernel_sys::M_SONAME[0]);
+        }
+        addr
+    }
+}
+
+/// Translate errors of interrupted sleeps as the system calls do
+fn sleep_result(error: c_int) -> Result<(), Errno> {
+    match error {
+        ERESTART => Err(Errno::EINTR),
+        error => Errno::result(error),
+    }
+}
+
+/// A socket owned by the kernel, closed when dropped
+#[derive(Debug)]
+pub struct KernelSocket {
+    so: NonNull<socket>,
+    ty: SocketType,
+}
+
+impl KernelSocket {
+    /// Create a socket with the default protocol for `domain` and `ty`,
+    /// i.e. `socreate()`, with the credentials of the current thread
+    pub fn new<C: Sleepable>(
+        _ctx: &C,
+        domain: Domain,
+        ty: SocketType,
+    ) -> Result<KernelSocket, Errno> {
+        let mut so = ptr::null_mut();
+        let td = curthread();
+        Errno::result(unsafe {
+            kernel_sys::socreate(
+                domain.raw(),
+                &mut so,
+                ty.raw(),
+                0,
+                (*td).td_ucred,
+                td,
+            )
+        })?;
+        let so = NonNull::new(so).ok_or(Errno::ENOBUFS)?;
+        Ok(KernelSocket { so, ty })
+    }
+
+    pub fn as_ptr(&self) -> *mut socket {
+        self.so.as_ptr()
+    }
+
+    pub fn socket_type(&self) -> SocketType {
+        self.ty
+    }
+
+    /// Bind to the local address `addr`, i.e. `sobind()`
+    pub fn bind<C: Sleepable>(
+        &self,
+        _ctx: &C,
+        addr: &SocketAddr,
+    ) -> Result<(), Errno> {
+        let mut ss = addr.to_raw()?;
+        let sa = &mut ss as *mut _ as *mut sockaddr;
+        Errno::result(unsafe {
+            kernel_sys::sobind(self.as_ptr(), sa, curthread())
+        })
+    }
+
+    /// Connect to `addr`, i.e. `soconnect()`, waiting until a stream
+    /// connection is established or fails. Returns `EALREADY` if a
+    /// connection attempt that was interrupted is still in progress.
+    pub fn connect<C: Sleepable>(
+        &self,
+        _ctx: &C,
+        addr: &SocketAddr,
+    ) -> Result<(), Errno> {
+        let mut ss = addr.to_raw()?;
+        let sa = &mut ss as *mut _ as *mut sockaddr;
+        let so = self.as_ptr();
+        // As in kern_connectat()
+        let connecting = kernel_sys::SS_ISCONNECTING as i16;
+        if unsafe { (*so).so_state } & connecting != 0 {
+            return Err(Errno::EALREADY);
+        }
+        let mut error = unsafe { kernel_sys::soconnect(so, sa, curthread()) };
+        let mut interrupted = false;
+        unsafe {
+            mtx_lock(&mut (*so).so_lock); // SOCK_LOCK()
+            if error == 0 {
+                while (*so).so_state & connecting != 0 && (*so).so_error == 0 {
+                    error = kernel_sys::_sleep(
+                        &mut (*so).so_timeo as *mut _ as *mut c_void,
+                        &mut (*so).so_lock.lock_object,
+                        (kernel_sys::PSOCK | kernel_sys::PCATCH) as c_int,
+                        "connec\0".as_ptr() as *const c_char,
+                        0,
+                        0,
+                        kernel_sys::C_HARDCLOCK as c_int,
+                    );
+                    if error != 0 {
+                        interrupted = error == libc::EINTR || error == ERESTART;
+                        break;
+                    }
+                }
+                if error == 0 {
+                    error = (*so).so_error as c_int;
+                    (*so).so_error = 0;
+                }
+            }
+            // An interrupted connection attempt carries on, anything else
+            // has finished
+            if !interrupted {
+                (*so).so_state &= !connecting;
+            }
+            mtx_unlock(&mut (*so).so_lock);
+        }
+        sleep_result(error)
+    }
+
+    /// Accept connections, with up to `backlog` of them waiting, i.e.
+    /// `solisten()`
+    pub fn listen(&self, backlog: c_int) -> Result<(), Errno> {
+        Errno::result(unsafe {
+            kernel_sys::solisten(self.as_ptr(), backlog, curthread())
+        })
+    }
+
+    /// Wait for a connection to a listening socket, returning its socket
+    /// and the address of the peer. Returns `EINVAL` if the socket is not
+    /// listening.
+    pub fn accept<C: Sleepable>(
+        &self,
+        _ctx: &C,
+    ) -> Result<(KernelSocket, Option<SocketAddr>), Errno> {
+        let head = self.as_ptr();
+        // SOLISTENING(), as in kern_accept4()
+        if unsafe { (*head).so_options } & kernel_sys::SO_ACCEPTCONN == 0 {
+            return Err(Errno::EINVAL);
+        }
+        let mut so = ptr::null_mut();
+        unsafe {
+            // SOLISTEN_LOCK(), which solisten_dequeue() always drops
+            mtx_lock(&mut (*head).so_lock);
+            sleep_result(kernel_sys::solisten_dequeue(head, &mut so, 0))?;
+        }
+        let so = KernelSocket {
+            so: NonNull::new(so).ok_or(Errno::ECONNABORTED)?,
+            ty: self.ty,
+        };
+        let mut sa = ptr::null_mut();
+        let error = unsafe { kernel_sys::soaccept(so.as_ptr(), &mut sa) };
+        let addr = unsafe { SocketAddr::from_soname(sa) };
+        Errno::result(error)?;
+        Ok((so, addr))
+    }
+
+    fn sosend(
+        &self,
+        buf: &[u8],
+        addr: Option<&SocketAddr>,
+    ) -> Result<usize, Errno> {
+        let mut ss = addr.map(SocketAddr::to_raw).transpose()?;
+        let sa = ss
+            .as_mut()
+            .map_or(ptr::null_mut(), |ss| ss as *mut _ as *mut sockaddr);
+        let mut iov = kernel_sys::iovec {
+            iov_base: buf.as_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+        let mut uio = sysspace_uio(&mut iov, kernel_sys::uio_rw_UIO_WRITE);
+        let error = unsafe {
+            kernel_sys::sosend(
+                self.as_ptr(),
+                sa,
+                &mut uio,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                0,
+                curthread(),
+            )
+        };
+        partial_result(error, buf.len(), &uio)
+    }
+
+    /// Send data, returning how much was sent, i.e. `sosend()`
+    pub fn send<C: Sleepable>(
+        &self,
+        _ctx: &C,
+        buf: &[u8],
+    ) -> Result<usize, Errno> {
+        self.sosend(buf, None)
+    }
+
+    /// Send a datagram to `addr`
+    pub fn send_to<C: Sleepable>(
+        &self,
+        _ctx: &C,
+        buf: &[u8],
+        addr: &SocketAddr,
+    ) -> Result<usize, Errno> {
+        self.sosend(buf, Some(addr))
+    }
+
+    fn soreceive(
+        &self,
+        buf: &mut [u8],
+        psa: *mut *mut sockaddr,
+    ) -> Result<usize, Errno> {
+        let mut iov = kernel_sys::iovec {
+            iov_base: buf.as_mut_ptr() as *mut c_void,
+            iov_len: buf.len(),
+        };
+        let mut uio = sysspace_uio(&mut iov, kernel_sys::uio_rw_UIO_READ);
+        let error = unsafe {
+            kernel_sys::soreceive(
+                self.as_ptr(),
+                psa,
+                &mut uio,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+        partial_result(error, buf.len(), &uio)
+    }
+
+    /// Wait for data, returning how much was received, i.e.
+    /// `soreceive()`. 0 means that a stream connection was closed.
+    pub fn recv<C: Sleepable>(
+        &self,
+        _ctx: &C,
+        buf: &mut [u8],
+    ) -> Result<usize, Errno> {
+        self.soreceive(buf, ptr::null_mut())
+    }
+
+    /// Wait for a datagram, returning its length and sender
+    pub fn recv_from<C: Sleepable>(
+        &self,
+        _ctx: &C,
+        buf: &mut [u8],
+    ) -> Result<(usize, Option<SocketAddr>), Errno> {
+        let mut sa = ptr::null_mut();
+        let ret = self.soreceive(buf, &mut sa);
+        let addr = unsafe { SocketAddr::from_soname(sa) };
+        Ok((ret?, addr))
+    }
+
+    /// Use a stream socket with `io::Read` and `io::Write`, which block in
+    /// the sleepable context `ctx`
+    pub fn stream<'a, C: Sleepable>(
+        &'a self,
+        ctx: &'a C,
+    ) -> SocketStream<'a, C> {
+        SocketStream { socket: self, ctx }
+    }
+}
+
+/// A socket borrowed together with a context that may sleep, returned by
+/// `KernelSocket::stream`
+#[derive(Debug)]
+pub struct SocketStream<'a, C: Sleepable> {
+    socket: &'a KernelSocket,
+    ctx: &'a C,
+}
+
+impl<'a, C: Sleepable> SocketStream<'a, C> {
+    fn check_stream(&self) -> io::Result<()> {
+        match self.socket.ty {
+            SocketType::Stream => Ok(()),
+            SocketType::Datagram => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "not a stream socket",
+            )),
+        }
+    }
+}
+
+/// A `uio` for a kernel buffer
+fn sysspace_uio(
+    iov: &mut kernel_sys::iovec,
+    rw: kernel_sys::uio_rw,
+) -> kernel_sys::uio {
+    kernel_sys::uio {
+        uio_iov: iov,
+        uio_iovcnt: 1,
+        uio_offset: 0,
+        uio_resid: iov.iov_len as isize,
+        uio_segflg: kernel_sys::uio_seg_UIO_SYSSPACE,
+        uio_rw: rw,
+        uio_td: curthread(),
+    }
+}
+
+/// The amount transferred, which is not an error even if the transfer
+/// was interrupted afterwards, as in the system calls
+fn partial_result(
+    error: c_int,
+    len: usize,
+    uio: &kernel_sys::uio,
+) -> Result<usize, Errno> {
+    let done = len - uio.uio_resid as usize;
+    match error {
+        0 => Ok(done),
+        ERESTART | libc::EINTR | libc::EWOULDBLOCK if done > 0 => Ok(done),
+        error => sleep_result(error).map(|()| done),
+    }
+}
+
+/// Reads from a stream socket
+impl<'a, C: Sleepable> Read for SocketStream<'a, C> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.check_stream()?;
+        Ok(self.socket.recv(self.ctx, buf)?)
+    }
+}
+
+/// Writes to a stream socket
+impl<'a, C: Sleepable> Write for SocketStream<'a, C> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check_stream()?;
+        Ok(self.socket.send(self.ctx, buf)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for KernelSocket {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::soclose(self.as_ptr()) };
+    }
+}
+
+unsafe impl Send for KernelSocket {}
+unsafe impl Sync for KernelSocket {}
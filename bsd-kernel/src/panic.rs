@@ -0,0 +1,266 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Rust panic handler that hands the panic over to the kernel
+//!
+//! Enabled with the `panic-handler` feature. A Rust panic normally ends in
+//! `panic(9)` with the panic message and location, so the usual crash
+//! dump, `savecore(8)` and `kgdb(1)` workflow applies.
+//!
+//! Code run through `catch` is treated differently: a panic is logged with
+//! `LOG_CRIT` and `catch` returns `Err(Panicked)`, so an entry point can
+//! fail with an error instead of taking the whole system down. Modules are
+//! built with `panic = "abort"`, so nothing is unwound: destructors between
+//! the panic and the `catch` do not run, memory is leaked and any lock
+//! held at that point stays locked. Only use `catch` around code that
+//! does not hold locks shared with other entry points, and consider the
+//! module broken after it returns `Err`.
+
+use crate::console::{self, Priority, Target};
+use crate::io::{Cursor, Write};
+use crate::{cstr, curthread};
+use core::mem::{self, ManuallyDrop};
+use core::panic::PanicInfo;
+use core::ptr;
+use libc::{c_char, c_int};
+use spin::Mutex;
+
+/// Size of the buffer the panic message is formatted into. Longer
+/// messages are truncated.
+const MESSAGE_SIZE: usize = 256;
+
+extern "C" {
+    // Declared here rather than taken from kernel_sys so that the compiler
+    // knows about the semantics that bindgen does not carry over
+    #[ffi_returns_twice]
+    fn setjmp(buf: *mut kernel_sys::_jmp_buf) -> c_int;
+    fn longjmp(buf: *mut kernel_sys::_jmp_buf, val: c_int) -> !;
+    fn panic(fmt: *const c_char, ...) -> !;
+}
+
+/// Error returned by `catch` when the closure panicked
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Panicked;
+
+struct Boundary {
+    td: *mut kernel_sys::thread,
+    buf: kernel_sys::_jmp_buf,
+    next: *mut Boundary,
+}
+
+struct Boundaries(*mut Boundary);
+
+unsafe impl Send for Boundaries {}
+
+/// Active `catch` boundaries of all threads, innermost first
+static BOUNDARIES: Mutex<Boundaries> = Mutex::new(Boundaries(ptr::null_mut()));
+
+struct Panicking {
+    td: *mut kernel_sys::thread,
+    next: *mut Panicking,
+}
+
+struct PanickingThreads(*mut Panicking);
+
+unsafe impl Send for PanickingThreads {}
+
+/// Threads currently in the panic handler. Each entry lives on the stack
+/// of the handler, which only returns by `longjmp` or `panic(9)`.
+static PANICKING: Mutex<PanickingThreads> =
+    Mutex::new(PanickingThreads(ptr::null_mut()));
+
+/// Run `f`, returning `Err(Panicked)` instead of panicking the kernel if
+/// `f` panics. See the module documentation for what is (not) cleaned up.
+///
+/// # Safety
+/// A panic skips over the frames inside `f` without dropping anything
+/// they own. No such frame may own a value with a `Drop` implementation
+/// or hold a lock, and `f` itself must not capture one by value.
+#[inline(never)]
+pub unsafe fn catch<F, R>(f: F) -> Result<R, Panicked>
+where
+    F: FnOnce() -> R,
+{
+    // `f` is never dropped after a panic, as it may have been partially
+    // moved out of by then
+    let mut f = ManuallyDrop::new(f);
+    let mut boundary = Boundary {
+        td: curthread(),
+        buf: mem::zeroed(),
+        next: ptr::null_mut(),
+    };
+
+    if setjmp(&mut boundary.buf) != 0 {
+        // Back from `longjmp` in the panic handler, which has already
+        // removed the boundary
+        return Err(Panicked);
+    }
+
+    {
+        let mut boundaries = BOUNDARIES.lock();
+        boundary.next = boundaries.0;
+        boundaries.0 = &mut boundary;
+    }
+
+    let result = ManuallyDrop::take(&mut f)();
+
+    remove(&mut boundary);
+    Ok(result)
+}
+
+fn remove(boundary: *mut Boundary) {
+    let mut boundaries = BOUNDARIES.lock();
+    let mut link: *mut *mut Boundary = &mut boundaries.0;
+    unsafe {
+        while !(*link).is_null() {
+            if *link == boundary {
+                *link = (*boundary).next;
+                return;
+            }
+            link = &mut (**link).next;
+        }
+    }
+}
+
+/// Find and unlink the innermost boundary of the current thread. Returns
+/// `None` as well if the boundaries are locked.
+fn take_boundary() -> Option<*mut Boundary> {
+    let td = curthread();
+    let mut boundaries = BOUNDARIES.try_lock()?;
+    let mut link: *mut *mut Boundary = &mut boundaries.0;
+    unsafe {
+        while !(*link).is_null() {
+            let boundary = *link;
+            if (*boundary).td == td {
+                *link = (*boundary).next;
+                return Some(boundary);
+            }
+            link = &mut (*boundary).next;
+        }
+    }
+    None
+}
+
+/// Record that `entry.td` is panicking. Returns `Some(false)` if it
+/// already was, and `None` if the list is locked.
+fn start_panicking(entry: &mut Panicking) -> Option<bool> {
+    let mut panicking = PANICKING.try_lock()?;
+    let mut next = panicking.0;
+    while !next.is_null() {
+        unsafe {
+            if (*next).td == entry.td {
+                return Some(false);
+            }
+            next = (*next).next;
+        }
+    }
+    entry.next = panicking.0;
+    panicking.0 = entry;
+    Some(true)
+}
+
+/// Remove `entry` again. Returns false if the list is locked.
+fn stop_panicking(entry: *mut Panicking) -> bool {
+    let mut panicking = match PANICKING.try_lock() {
+        Some(panicking) => panicking,
+        None => return false,
+    };
+    let mut link: *mut *mut Panicking = &mut panicking.0;
+    unsafe {
+        while !(*link).is_null() {
+            if *link == entry {
+                *link = (*entry).next;
+                break;
+            }
+            link = &mut (**link).next;
+        }
+    }
+    true
+}
+
+#[panic_handler]
+fn panic_handler(info: &PanicInfo) -> ! {
+    let mut entry = Panicking {
+        td: curthread(),
+        next: ptr::null_mut(),
+    };
+    // The locks are not taken unconditionally: the panic may have
+    // interrupted their holder on this CPU, which would spin forever.
+    // `panic(9)` is the fallback whenever one of them is busy.
+    match start_panicking(&mut entry) {
+        Some(true) => {}
+        // Formatting the first panic of this thread panicked, don't try
+        // again
+        Some(false) => unsafe {
+            panic(cstr!("Rust panic while panicking").as_ptr() as _)
+        },
+        // Without an entry a panic while formatting would recurse, so
+        // only report the location
+        None => unsafe {
+            let (file, line) = match info.location() {
+                Some(location) => (location.file(), location.line()),
+                None => ("unknown", 0),
+            };
+            panic(
+                cstr!("Rust panic at %.*s:%u").as_ptr() as *const c_char,
+                file.len() as c_int,
+                file.as_ptr() as *const c_char,
+                line,
+            )
+        },
+    }
+
+    let mut buf = [0u8; MESSAGE_SIZE];
+    let mut cursor = Cursor::new(&mut buf[..]);
+    // A full buffer is reported as an error, which leaves the message
+    // truncated but otherwise intact
+    let _ = write!(cursor, "{}", info);
+    let len = cursor.position() as usize;
+    let message = &buf[..len];
+
+    if let Some(boundary) = take_boundary() {
+        // The message may have been truncated in the middle of a
+        // character, keep what comes before it
+        let message = match core::str::from_utf8(message) {
+            Ok(message) => message,
+            Err(e) => unsafe {
+                core::str::from_utf8_unchecked(&message[..e.valid_up_to()])
+            },
+        };
+        console::print(
+            Target::Log(Priority::Crit),
+            format_args!("Rust {}\n", message),
+        );
+        if stop_panicking(&mut entry) {
+            unsafe { longjmp(&mut (*boundary).buf, 1) }
+        }
+    }
+
+    unsafe {
+        panic(
+            cstr!("Rust %.*s").as_ptr() as *const c_char,
+            len as c_int,
+            message.as_ptr() as *const c_char,
+        )
+    }
+}
@@ -0,0 +1,245 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Adding system calls from a module, i.e. `SYSCALL_MODULE()`
+//!
+//! `syscall_module!` registers a function in a free slot of the system
+//! call table when the module is loaded and removes it again on unload.
+//! The assigned number is stored in a `SyscallNumber`, and the kernel also
+//! reports it through `modstat(2)`, so user programs can look it up:
+//!
+//! ```c
+//! struct module_stat stat = { .version = sizeof(stat) };
+//! modstat(modfind("sys/hello"), &stat);
+//! syscall(stat.data.intval, 42);
+//! ```
+//!
+//! The module side of this looks like:
+//!
+//! ```rust,ignore
+//! use bsd_kernel::error::Errno;
+//! use bsd_kernel::syscall::{Arg, SyscallArgs, SyscallNumber};
+//!
+//! #[repr(C)]
+//! pub struct HelloArgs {
+//!     value: Arg<c_int>,
+//! }
+//! unsafe impl SyscallArgs for HelloArgs {}
+//!
+//! fn hello(_td: *mut thread, args: &HelloArgs) -> Result<register_t, Errno> {
+//!     Ok(args.value.get() as register_t + 1)
+//! }
+//!
+//! pub static HELLO_SYSCALL: SyscallNumber = SyscallNumber::new();
+//! bsd_kernel::syscall_module!(hello, HELLO_SYSCALL, HelloArgs, hello);
+//! ```
+
+use crate::error::Errno;
+use core::fmt;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicI32, Ordering};
+use kernel_sys::{register_t, sy_call_t, sysent, thread};
+use libc::{c_int, c_void};
+
+/// The most arguments a system call can take
+pub const MAX_ARGS: usize = 8;
+
+/// The system call handler type for `syscall_module!`. It receives the
+/// calling thread and its arguments and returns the value for `td_retval[0]`
+/// or an error number for `errno`.
+pub type SyscallHandler<A> = fn(*mut thread, &A) -> Result<register_t, Errno>;
+
+/// One argument of a system call
+///
+/// The kernel copies each argument into a `register_t` sized slot, like
+/// the padded members of the `*_args` structures generated in
+/// `sys/sysproto.h`. This assumes a little-endian machine, where the value
+/// is at the start of the slot.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub union Arg<T: Copy> {
+    value: T,
+    _slot: register_t,
+}
+
+impl<T: Copy> Arg<T> {
+    pub fn get(self) -> T {
+        unsafe { self.value }
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for Arg<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.get().fmt(f)
+    }
+}
+
+/// The arguments of a system call
+///
+/// # Safety
+/// Implementors must be `#[repr(C)]` structures containing only `Arg`
+/// fields, one per argument in order, so that they match the layout the
+/// kernel copies arguments in.
+pub unsafe trait SyscallArgs: Sized {
+    /// Number of arguments, for `sy_narg`
+    const COUNT: usize = {
+        let count = size_of::<Self>() / size_of::<register_t>();
+        assert!(count <= MAX_ARGS, "too many system call arguments");
+        count
+    };
+}
+
+unsafe impl SyscallArgs for () {}
+
+/// The system call number assigned to a `syscall_module!`
+///
+/// `new()` lets the kernel pick any free slot (`NO_SYSCALL`), while
+/// `fixed()` requests a specific one.
+pub struct SyscallNumber(AtomicI32);
+
+impl SyscallNumber {
+    pub const fn new() -> Self {
+        SyscallNumber(AtomicI32::new(kernel_sys::NO_SYSCALL))
+    }
+
+    pub const fn fixed(number: c_int) -> Self {
+        SyscallNumber(AtomicI32::new(number))
+    }
+
+    /// The number of the system call, or `None` if none has been
+    /// assigned yet
+    pub fn get(&self) -> Option<c_int> {
+        match self.0.load(Ordering::Acquire) {
+            kernel_sys::NO_SYSCALL => None,
+            number => Some(number),
+        }
+    }
+
+    /// Pointer for the `offset` of `struct syscall_module_data`, which the
+    /// kernel updates when the system call is registered
+    pub const fn as_ptr(&self) -> *mut c_int {
+        &self.0 as *const AtomicI32 as *mut c_int
+    }
+}
+
+impl Default for SyscallNumber {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SyscallNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SyscallNumber({:?})", self.get())
+    }
+}
+
+/// Build a system call table entry calling `call` with `narg` arguments
+pub const fn sysent(call: sy_call_t, narg: usize) -> sysent {
+    sysent {
+        sy_call: call,
+        sy_systrace_args_func: None,
+        sy_narg: narg as _,
+        sy_flags: 0,
+        sy_auevent: 0, // AUE_NULL
+        sy_entry: 0,
+        sy_return: 0,
+        sy_thrcnt: 0,
+    }
+}
+
+/// Call `func` with the arguments at `uap` and store its result for the
+/// calling thread. This is the body of the `sy_call` function generated by
+/// `syscall_module!`.
+///
+/// # Safety
+/// `td` must be the calling thread and `uap` must point to its arguments.
+pub unsafe fn dispatch_syscall<A: SyscallArgs>(
+    td: *mut thread,
+    uap: *mut c_void,
+    func: SyscallHandler<A>,
+) -> c_int {
+    let args = &*(uap as *const A);
+    match func(td, args) {
+        Ok(ret) => {
+            (*td).td_uretoff.tdu_retval[0] = ret;
+            0
+        }
+        Err(e) => e.raw(),
+    }
+}
+
+/// Add a system call, replacing `SYSCALL_MODULE()` and its `sysent`
+///
+/// This declares a module named `sys/$name` that registers `$func`, a
+/// `SyscallHandler<$args>`, on load and deregisters it on unload. `$number`
+/// is a `static` `SyscallNumber`, which receives the assigned number.
+#[macro_export]
+macro_rules! syscall_module {
+    ($name:ident, $number:expr, $args:ty, $func:expr) => {
+        const _: () = {
+            unsafe extern "C" fn sy_call(
+                td: *mut $crate::kernel_sys::thread,
+                uap: *mut $crate::libc::c_void,
+            ) -> $crate::libc::c_int {
+                $crate::syscall::dispatch_syscall::<$args>(td, uap, $func)
+            }
+
+            // The kernel writes to both of these while registering
+            static SYSENT: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::sysent,
+            > = $crate::linker_set::StaticMut::new($crate::syscall::sysent(
+                Some(sy_call),
+                <$args as $crate::syscall::SyscallArgs>::COUNT,
+            ));
+            static SYSCALL_DATA: $crate::linker_set::StaticMut<
+                $crate::kernel_sys::syscall_module_data,
+            > = $crate::linker_set::StaticMut::new(
+                $crate::kernel_sys::syscall_module_data {
+                    chainevh: None,
+                    chainarg: ::core::ptr::null_mut(),
+                    offset: $crate::syscall::SyscallNumber::as_ptr(&$number),
+                    new_sysent: SYSENT.get(),
+                    old_sysent: $crate::syscall::sysent(None, 0),
+                    flags: 0,
+                },
+            );
+
+            static MODULE_DATA: $crate::linker_set::Static<
+                $crate::kernel_sys::moduledata_t,
+            > = $crate::linker_set::Static($crate::kernel_sys::moduledata_t {
+                name: $crate::cstr!(concat!("sys/", stringify!($name))).as_ptr()
+                    as *const $crate::libc::c_char,
+                evhand: Some($crate::kernel_sys::syscall_module_handler),
+                priv_: SYSCALL_DATA.get() as *mut $crate::libc::c_void,
+            });
+
+            $crate::__declare_module!(
+                stringify!($name),
+                MODULE_DATA,
+                $crate::sysinit::Subsystem::Syscalls,
+                $crate::sysinit::Order::Middle
+            );
+        };
+    };
+}
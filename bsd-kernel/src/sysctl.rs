@@ -0,0 +1,214 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Dynamic sysctl(9) nodes and handlers
+//!
+//! Sysctl OIDs added through a `SysctlContext` are removed again when the
+//! context is dropped, so a module only needs to keep the context alive
+//! for as long as its sysctls should be visible.
+
+use crate::{cstr, cstr_ref};
+use alloc::boxed::Box;
+use core::{fmt, mem, ptr};
+use libc::{c_char, c_int, c_void};
+
+/// Signature of a sysctl handler, `SYSCTL_HANDLER_ARGS` in `sys/sysctl.h`
+pub type SysctlHandler = unsafe extern "C" fn(
+    oidp: *mut kernel_sys::sysctl_oid,
+    arg1: *mut c_void,
+    arg2: kernel_sys::intmax_t,
+    req: *mut kernel_sys::sysctl_req,
+) -> c_int;
+
+/// A node in the sysctl tree that other OIDs can be added beneath
+#[derive(Copy, Clone, Debug)]
+pub struct SysctlOid(ptr::NonNull<kernel_sys::sysctl_oid>);
+
+impl SysctlOid {
+    /// The `debug` top level node
+    pub fn debug() -> Self {
+        Self::from_static(unsafe {
+            ptr::addr_of_mut!(kernel_sys::sysctl___debug)
+        })
+    }
+
+    /// The `dev` top level node
+    pub fn dev() -> Self {
+        Self::from_static(unsafe {
+            ptr::addr_of_mut!(kernel_sys::sysctl___dev)
+        })
+    }
+
+    /// The `hw` top level node
+    pub fn hw() -> Self {
+        Self::from_static(unsafe { ptr::addr_of_mut!(kernel_sys::sysctl___hw) })
+    }
+
+    /// The `kern` top level node
+    pub fn kern() -> Self {
+        Self::from_static(unsafe {
+            ptr::addr_of_mut!(kernel_sys::sysctl___kern)
+        })
+    }
+
+    /// The `net` top level node
+    pub fn net() -> Self {
+        Self::from_static(unsafe {
+            ptr::addr_of_mut!(kernel_sys::sysctl___net)
+        })
+    }
+
+    fn from_static(oid: *mut kernel_sys::sysctl_oid) -> Self {
+        SysctlOid(ptr::NonNull::new(oid).unwrap())
+    }
+
+    pub fn as_ptr(&self) -> *mut kernel_sys::sysctl_oid {
+        self.0.as_ptr()
+    }
+
+    /// `SYSCTL_CHILDREN()`
+    fn children(&self) -> *mut kernel_sys::sysctl_oid_list {
+        unsafe { ptr::addr_of_mut!((*self.0.as_ptr()).oid_children) }
+    }
+}
+
+/// Owner of a set of dynamically created sysctl OIDs, see
+/// `sysctl_ctx_init(9)`
+pub struct SysctlContext {
+    // Boxed because the kernel keeps pointers into the list head
+    list: Box<kernel_sys::sysctl_ctx_list>,
+}
+
+impl SysctlContext {
+    pub fn new() -> Self {
+        let mut list: Box<kernel_sys::sysctl_ctx_list> =
+            Box::new(unsafe { mem::zeroed() });
+        unsafe { kernel_sys::sysctl_ctx_init(&mut *list) };
+        SysctlContext { list }
+    }
+
+    /// Add a node named `name` beneath `parent`. Returns `None` if the
+    /// kernel refused to create the node, e.g. because the name is taken.
+    pub fn add_node(
+        &mut self,
+        parent: SysctlOid,
+        name: &str,
+        descr: &str,
+    ) -> Option<SysctlOid> {
+        unsafe {
+            self.add_oid(
+                parent,
+                name,
+                kernel_sys::CTLTYPE_NODE as c_int
+                    | kernel_sys::CTLFLAG_RW as c_int
+                    | kernel_sys::CTLFLAG_MPSAFE as c_int,
+                ptr::null_mut(),
+                0,
+                None,
+                cstr!("N"),
+                descr,
+            )
+        }
+    }
+
+    /// Add an integer OID beneath `parent` whose reads and writes are
+    /// serviced by `handler`, i.e. `SYSCTL_ADD_PROC(9)`. `flags` are
+    /// `CTLFLAG_*` values, `CTLTYPE_INT` and `CTLFLAG_MPSAFE` are implied.
+    /// The handler receives `arg1` and an `arg2` of 0.
+    ///
+    /// # Safety
+    /// `handler` is called with `arg1` until the context is dropped, so
+    /// `arg1` must remain valid for at least that long.
+    pub unsafe fn add_int_proc(
+        &mut self,
+        parent: SysctlOid,
+        name: &str,
+        flags: c_int,
+        arg1: *mut c_void,
+        handler: SysctlHandler,
+        descr: &str,
+    ) -> Option<SysctlOid> {
+        self.add_oid(
+            parent,
+            name,
+            kernel_sys::CTLTYPE_INT as c_int
+                | kernel_sys::CTLFLAG_MPSAFE as c_int
+                | flags,
+            arg1,
+            0,
+            Some(handler),
+            cstr!("I"),
+            descr,
+        )
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    unsafe fn add_oid(
+        &mut self,
+        parent: SysctlOid,
+        name: &str,
+        kind: c_int,
+        arg1: *mut c_void,
+        arg2: kernel_sys::intmax_t,
+        handler: Option<SysctlHandler>,
+        format: &'static str,
+        descr: &str,
+    ) -> Option<SysctlOid> {
+        // The kernel takes its own copies of the strings. `format` is
+        // always one of our static, already terminated strings.
+        let oid = kernel_sys::sysctl_add_oid(
+            &mut *self.list,
+            parent.children(),
+            kernel_sys::OID_AUTO as c_int,
+            cstr_ref!(name).as_ptr() as *const c_char,
+            kind,
+            arg1,
+            arg2,
+            handler,
+            format.as_ptr() as *const c_char,
+            cstr_ref!(descr).as_ptr() as *const c_char,
+            ptr::null(),
+        );
+        ptr::NonNull::new(oid).map(SysctlOid)
+    }
+}
+
+impl Default for SysctlContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for SysctlContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SysctlContext {{ list: {:?} }}", &*self.list as *const _)
+    }
+}
+
+impl Drop for SysctlContext {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::sysctl_ctx_free(&mut *self.list) };
+    }
+}
+
+unsafe impl Send for SysctlContext {}
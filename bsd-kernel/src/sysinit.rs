@@ -0,0 +1,203 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Run functions at a given stage of kernel initialisation, i.e.
+//! `SYSINIT(9)` and `SYSUNINIT(9)`
+//!
+//! The functions are found through the `sysinit_set` and `sysuninit_set`
+//! linker sets, so this works both for code compiled into the kernel,
+//! where they run during boot in (subsystem, order) order, and for loaded
+//! modules, where the `sysinit!` functions run when the module is loaded
+//! and the `sysuninit!` functions when it is unloaded.
+//!
+//! ```rust,ignore
+//! use bsd_kernel::sysinit::{Order, Subsystem};
+//!
+//! bsd_kernel::sysinit!(Subsystem::Pseudo, Order::Any, || {
+//!     log::info!("pseudo devices can be created now");
+//! });
+//! ```
+
+use kernel_sys::*;
+
+/// Subsystems from `enum sysinit_sub_id` in `sys/kernel.h`, in the order
+/// in which they are initialised
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u32)]
+pub enum Subsystem {
+    /// Tunables are available
+    Tunables = sysinit_sub_id_SI_SUB_TUNABLES,
+    /// Kernel memory allocation works
+    Kmem = sysinit_sub_id_SI_SUB_KMEM,
+    /// Locks can be initialised
+    Lock = sysinit_sub_id_SI_SUB_LOCK,
+    /// `eventhandler(9)` lists are set up
+    Eventhandler = sysinit_sub_id_SI_SUB_EVENTHANDLER,
+    /// The kernel linker is set up
+    Kld = sysinit_sub_id_SI_SUB_KLD,
+    /// CPUs are identified
+    Cpu = sysinit_sub_id_SI_SUB_CPU,
+    /// Other `SYSINIT`s of the kernel proper
+    Intrinsic = sysinit_sub_id_SI_SUB_INTRINSIC,
+    /// mbufs are available
+    Mbuf = sysinit_sub_id_SI_SUB_MBUF,
+    /// Interrupt threads
+    Intr = sysinit_sub_id_SI_SUB_INTR,
+    /// `taskqueue(9)`s are available
+    Taskq = sysinit_sub_id_SI_SUB_TASKQ,
+    /// Application processors are started
+    Smp = sysinit_sub_id_SI_SUB_SMP,
+    /// Software interrupt threads
+    Softintr = sysinit_sub_id_SI_SUB_SOFTINTR,
+    /// `devfs` is ready for devices
+    Devfs = sysinit_sub_id_SI_SUB_DEVFS,
+    /// Interface lists are prepared
+    InitIf = sysinit_sub_id_SI_SUB_INIT_IF,
+    /// Netgraph
+    Netgraph = sysinit_sub_id_SI_SUB_NETGRAPH,
+    /// Device drivers, where `DECLARE_MODULE()` usually registers modules
+    Drivers = sysinit_sub_id_SI_SUB_DRIVERS,
+    /// Device configuration
+    Configure = sysinit_sub_id_SI_SUB_CONFIGURE,
+    /// Virtual file system
+    Vfs = sysinit_sub_id_SI_SUB_VFS,
+    /// Real time and statistics clocks
+    Clocks = sysinit_sub_id_SI_SUB_CLOCKS,
+    /// Pseudo devices
+    Pseudo = sysinit_sub_id_SI_SUB_PSEUDO,
+    /// Executable format handlers
+    Exec = sysinit_sub_id_SI_SUB_EXEC,
+    /// Start of network protocol initialisation
+    ProtoBegin = sysinit_sub_id_SI_SUB_PROTO_BEGIN,
+    /// `pfil(9)` packet filter hooks
+    ProtoPfil = sysinit_sub_id_SI_SUB_PROTO_PFIL,
+    /// Interfaces
+    ProtoIf = sysinit_sub_id_SI_SUB_PROTO_IF,
+    /// Protocol domains
+    ProtoDomain = sysinit_sub_id_SI_SUB_PROTO_DOMAIN,
+    /// Firewalls
+    ProtoFirewall = sysinit_sub_id_SI_SUB_PROTO_FIREWALL,
+    /// End of network protocol initialisation
+    ProtoEnd = sysinit_sub_id_SI_SUB_PROTO_END,
+    /// The scheduler is started
+    KickScheduler = sysinit_sub_id_SI_SUB_KICK_SCHEDULER,
+    /// Interrupt configuration hooks are run
+    IntConfigHooks = sysinit_sub_id_SI_SUB_INT_CONFIG_HOOKS,
+    /// The root file system is mounted
+    RootConf = sysinit_sub_id_SI_SUB_ROOT_CONF,
+    /// System calls are registered
+    Syscalls = sysinit_sub_id_SI_SUB_SYSCALLS,
+    /// Kernel threads are started
+    KthreadInit = sysinit_sub_id_SI_SUB_KTHREAD_INIT,
+    /// Last subsystem, after everything else
+    Last = sysinit_sub_id_SI_SUB_LAST,
+}
+
+/// Order within a subsystem from `enum sysinit_elem_order` in
+/// `sys/kernel.h`
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u32)]
+pub enum Order {
+    First = sysinit_elem_order_SI_ORDER_FIRST,
+    Second = sysinit_elem_order_SI_ORDER_SECOND,
+    Third = sysinit_elem_order_SI_ORDER_THIRD,
+    Fourth = sysinit_elem_order_SI_ORDER_FOURTH,
+    Fifth = sysinit_elem_order_SI_ORDER_FIFTH,
+    Sixth = sysinit_elem_order_SI_ORDER_SIXTH,
+    Seventh = sysinit_elem_order_SI_ORDER_SEVENTH,
+    Eighth = sysinit_elem_order_SI_ORDER_EIGHTH,
+    Middle = sysinit_elem_order_SI_ORDER_MIDDLE,
+    Any = sysinit_elem_order_SI_ORDER_ANY,
+}
+
+/// Call `$func`, a `fn()` or non-capturing closure, when `$subsystem`
+/// (a `Subsystem`) is initialised, ordered by `$order` (an `Order`)
+#[macro_export]
+macro_rules! sysinit {
+    ($subsystem:expr, $order:expr, $func:expr) => {
+        const _: () = {
+            unsafe extern "C" fn sysinit_func(
+                _udata: *const $crate::libc::c_void,
+            ) {
+                let func: fn() = $func;
+                func();
+            }
+            $crate::__sysinit!(
+                "set_sysinit_set",
+                $subsystem,
+                $order,
+                sysinit_func,
+                ::core::ptr::null::<$crate::libc::c_void>()
+            );
+        };
+    };
+}
+
+/// Call `$func`, a `fn()` or non-capturing closure, when the module is
+/// unloaded. `sysuninit!`s run in the reverse order of `sysinit!`s, so
+/// this undoes a `sysinit!` with the same subsystem and order.
+#[macro_export]
+macro_rules! sysuninit {
+    ($subsystem:expr, $order:expr, $func:expr) => {
+        const _: () = {
+            unsafe extern "C" fn sysuninit_func(
+                _udata: *const $crate::libc::c_void,
+            ) {
+                let func: fn() = $func;
+                func();
+            }
+            $crate::__sysinit!(
+                "set_sysuninit_set",
+                $subsystem,
+                $order,
+                sysuninit_func,
+                ::core::ptr::null::<$crate::libc::c_void>()
+            );
+        };
+    };
+}
+
+/// Add a `sysinit` record to the linker set in section `$section`
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __sysinit {
+    ($section:literal, $subsystem:expr, $order:expr, $func:expr, $udata:expr) => {
+        const _: () = {
+            const SUBSYSTEM: $crate::sysinit::Subsystem = $subsystem;
+            const ORDER: $crate::sysinit::Order = $order;
+            static SYSINIT: $crate::linker_set::Static<
+                $crate::kernel_sys::sysinit,
+            > = $crate::linker_set::Static($crate::kernel_sys::sysinit {
+                subsystem: SUBSYSTEM as _,
+                order: ORDER as _,
+                func: Some($func as _),
+                udata: $udata as *const _ as *const $crate::libc::c_void,
+            });
+            $crate::__linker_set_entry!(
+                $section,
+                $crate::kernel_sys::sysinit,
+                SYSINIT
+            );
+        };
+    };
+}
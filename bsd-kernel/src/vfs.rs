@@ -0,0 +1,1145 @@
+// Copyright (c) 2022 NCC Group
+//
+// Redistribution and use in source and binary forms, with or without
+// modification, are permitted provided that the following conditions are met:
+//
+// 1. Redistributions of source code must retain the above copyright notice, this
+//    list of conditions and the following disclaimer.
+//
+// 2. Redistributions in binary form must reproduce the above copyright notice,
+//    this list of conditions and the following disclaimer in the documentation
+//    and/or other materials provided with the distribution.
+//
+// THIS SOFTWARE IS PROVIDED BY THE COPYRIGHT HOLDERS AND CONTRIBUTORS "AS IS"
+// AND ANY EXPRESS OR IMPLIED WARRANTIES, INCLUDING, BUT NOT LIMITED TO, THE
+// IMPLIED WARRANTIES OF MERCHANTABILITY AND FITNESS FOR A PARTICULAR PURPOSE ARE
+// DISCLAIMED. IN NO EVENT SHALL THE COPYRIGHT HOLDER OR CONTRIBUTORS BE LIABLE
+// FOR ANY DIRECT, INDIRECT, INCIDENTAL, SPECIAL, EXEMPLARY, OR CONSEQUENTIAL
+// DAMAGES (INCLUDING, BUT NOT LIMITED TO, PROCUREMENT OF SUBSTITUTE GOODS OR
+// SERVICES; LOSS OF USE, DATA, OR PROFITS; OR BUSINESS INTERRUPTION) HOWEVER
+// CAUSED AND ON ANY THEORY OF LIABILITY, WHETHER IN CONTRACT, STRICT LIABILITY,
+// OR TORT (INCLUDING NEGLIGENCE OR OTHERWISE) ARISING IN ANY WAY OUT OF THE USE
+// OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF SUCH DAMAGE.
+
+//! Filesystems, see `vfs(9)`
+//!
+//! A filesystem type is a type implementing `Filesystem`, registered with
+//! `vfs_set!`. Each mount creates a value of the type with
+//! `Filesystem::mount`, which is dropped when it is unmounted.
+//!
+//! Files are named by inode numbers. The filesystem resolves names in
+//! directories to inode numbers with `lookup`, and `vget` creates the
+//! private data of the vnode of an inode, its `Node`, when there is none
+//! cached. The node is dropped when the vnode is reclaimed.
+//!
+//! Vnodes are given to the vnode operations locked. Elsewhere a reference
+//! to a vnode is a `VnodeRef`, which is locked with `VnodeRef::lock`.
+//!
+//! There are no operations that modify a filesystem, so mounts are always
+//! read-only.
+//!
+//! ```rust,ignore
+//! /// A directory with a single file, `hello`
+//! struct HelloFs;
+//!
+//! const ROOT: u64 = 2;
+//! const HELLO: u64 = 3;
+//! const TEXT: &[u8] = b"Hello, world!\n";
+//!
+//! impl Filesystem for HelloFs {
+//!     type Node = ();
+//!
+//!     fn mount(_mp: Mount<Self>) -> Result<Self, Errno> {
+//!         Ok(HelloFs)
+//!     }
+//!
+//!     fn root(&self) -> u64 {
+//!         ROOT
+//!     }
+//!
+//!     fn vget(&self, _mp: Mount<Self>, ino: u64) -> Result<(VType, ()), Errno> {
+//!         match ino {
+//!             ROOT => Ok((VType::Directory, ())),
+//!             HELLO => Ok((VType::Regular, ())),
+//!             _ => Err(Errno::ENOENT),
+//!         }
+//!     }
+//!
+//!     fn lookup(&self, _dir: &Vnode<Self>, name: &str) -> Result<u64, Errno> {
+//!         match name {
+//!             ".." => Ok(ROOT),
+//!             "hello" => Ok(HELLO),
+//!             _ => Err(Errno::ENOENT),
+//!         }
+//!     }
+//!
+//!     fn getattr(&self, vp: &Vnode<Self>) -> Result<VAttr, Errno> {
+//!         Ok(match vp.vtype() {
+//!             VType::Directory => VAttr {
+//!                 mode: 0o555,
+//!                 nlink: 2,
+//!                 ..Default::default()
+//!             },
+//!             _ => VAttr {
+//!                 mode: 0o444,
+//!                 nlink: 1,
+//!                 size: TEXT.len() as u64,
+//!                 ..Default::default()
+//!             },
+//!         })
+//!     }
+//!
+//!     fn read(
+//!         &self,
+//!         _vp: &Vnode<Self>,
+//!         offset: u64,
+//!         buf: &mut [u8],
+//!     ) -> Result<usize, Errno> {
+//!         let text = TEXT.get(offset as usize..).unwrap_or_default();
+//!         let len = cmp::min(buf.len(), text.len());
+//!         buf[..len].copy_from_slice(&text[..len]);
+//!         Ok(len)
+//!     }
+//!
+//!     fn readdir(
+//!         &self,
+//!         _dir: &Vnode<Self>,
+//!         entries: &mut DirEntries,
+//!     ) -> Result<(), Errno> {
+//!         let all = [
+//!             (ROOT, VType::Directory, "."),
+//!             (ROOT, VType::Directory, ".."),
+//!             (HELLO, VType::Regular, "hello"),
+//!         ];
+//!         let start = entries.offset() as usize;
+//!         for &(ino, vtype, name) in all.iter().skip(start) {
+//!             if !entries.push(ino, vtype, name) {
+//!                 break;
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//!
+//! bsd_kernel::vfs_set!(hellofs, HelloFs, VFCF_SYNTHETIC);
+//! ```
+
+use crate::error::Errno;
+use crate::{c_str, cstr_ref, curthread, mtx_lock, mtx_unlock, Module};
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::marker::PhantomData;
+use core::mem::{self, MaybeUninit};
+use core::ops::Deref;
+use core::ptr::{self, NonNull};
+use core::time::Duration;
+use core::{cmp, slice, str};
+use kernel_sys::{
+    componentname, dirent, mount, uio, vattr, vfsconf, vfsops, vnode,
+    vop_vector,
+};
+use libc::{c_char, c_int, c_void};
+
+/// The filesystem type only provides a namespace, e.g. `devfs`
+pub const VFCF_SYNTHETIC: c_int = kernel_sys::VFCF_SYNTHETIC as c_int;
+/// The filesystem type can be mounted in a jail
+pub const VFCF_JAIL: c_int = kernel_sys::VFCF_JAIL as c_int;
+
+/// The type of a file, i.e. `enum vtype`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VType {
+    Regular,
+    Directory,
+    Block,
+    Character,
+    Link,
+    Socket,
+    Fifo,
+}
+
+impl VType {
+    pub fn raw(self) -> kernel_sys::vtype {
+        match self {
+            VType::Regular => kernel_sys::vtype_VREG,
+            VType::Directory => kernel_sys::vtype_VDIR,
+            VType::Block => kernel_sys::vtype_VBLK,
+            VType::Character => kernel_sys::vtype_VCHR,
+            VType::Link => kernel_sys::vtype_VLNK,
+            VType::Socket => kernel_sys::vtype_VSOCK,
+            VType::Fifo => kernel_sys::vtype_VFIFO,
+        }
+    }
+
+    /// The `d_type` of a directory entry for a file of this type
+    fn dirent_type(self) -> u8 {
+        let dt = match self {
+            VType::Regular => kernel_sys::DT_REG,
+            VType::Directory => kernel_sys::DT_DIR,
+            VType::Block => kernel_sys::DT_BLK,
+            VType::Character => kernel_sys::DT_CHR,
+            VType::Link => kernel_sys::DT_LNK,
+            VType::Socket => kernel_sys::DT_SOCK,
+            VType::Fifo => kernel_sys::DT_FIFO,
+        };
+        dt as u8
+    }
+}
+
+/// How a vnode is locked
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LockType {
+    Shared,
+    Exclusive,
+}
+
+impl LockType {
+    fn flags(self) -> c_int {
+        let flags = match self {
+            LockType::Shared => kernel_sys::LK_SHARED,
+            LockType::Exclusive => kernel_sys::LK_EXCLUSIVE,
+        };
+        flags as c_int
+    }
+}
+
+/// The attributes of a file, i.e. `struct vattr`. The type, inode number
+/// and filesystem are filled in from the vnode.
+#[derive(Clone, Debug, Default)]
+pub struct VAttr {
+    /// The permission bits, e.g. `0o755`
+    pub mode: u16,
+    pub nlink: u64,
+    pub uid: u32,
+    pub gid: u32,
+    /// The size in bytes
+    pub size: u64,
+    /// The time of the last access, since the epoch
+    pub atime: Duration,
+    /// The time of the last modification, since the epoch
+    pub mtime: Duration,
+    /// The time of the last change of the attributes, since the epoch
+    pub ctime: Duration,
+}
+
+/// The statistics of a mounted filesystem, i.e. the sizes in
+/// `struct statfs`
+#[derive(Clone, Debug, Default)]
+pub struct StatFs {
+    /// The block size in bytes
+    pub bsize: u64,
+    /// The optimal transfer size in bytes
+    pub iosize: u64,
+    /// The total number of blocks
+    pub blocks: u64,
+    pub bfree: u64,
+    /// The number of free blocks available to unprivileged users
+    pub bavail: i64,
+    /// The total number of inodes
+    pub files: u64,
+    pub ffree: i64,
+}
+
+/// A filesystem type. The value of the type is the state of a mount.
+pub trait Filesystem: Send + Sync + Sized + 'static {
+    /// The private data of a vnode
+    type Node: Send + Sync + 'static;
+
+    /// Mount the filesystem on `mp`, i.e. `vfs_mount`. Options such as
+    /// `"from"` are read with `Mount::option`. Updating a mount is not
+    /// supported.
+    fn mount(mp: Mount<Self>) -> Result<Self, Errno>;
+
+    /// The filesystem is being unmounted, i.e. `vfs_unmount`. Its vnodes
+    /// are flushed afterwards, forcibly if `force` is set, and then the
+    /// state is dropped.
+    fn unmount(&self, _mp: Mount<Self>, _force: bool) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// The inode number of the root directory, which `vfs_root` looks up
+    fn root(&self) -> u64;
+
+    /// The statistics of the filesystem, i.e. `vfs_statfs`
+    fn statfs(&self, _mp: Mount<Self>) -> Result<StatFs, Errno> {
+        Ok(StatFs::default())
+    }
+
+    /// Write out cached data, waiting for it to complete if `wait` is
+    /// set, i.e. `vfs_sync`
+    fn sync(&self, _mp: Mount<Self>, _wait: bool) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// The type and node of the file `ino` for a new vnode, i.e.
+    /// `vfs_vget`. Vnodes are cached, so this is only called when there
+    /// is none for the file.
+    fn vget(
+        &self,
+        mp: Mount<Self>,
+        ino: u64,
+    ) -> Result<(VType, Self::Node), Errno>;
+
+    /// The inode number of the entry `name` of the directory `dir`, i.e.
+    /// `vop_lookup`. `name` may be `".."`, but `"."` is handled for the
+    /// filesystem.
+    fn lookup(&self, dir: &Vnode<Self>, name: &str) -> Result<u64, Errno>;
+
+    /// The attributes of `vp`, i.e. `vop_getattr`. Access to the file is
+    /// checked against them.
+    fn getattr(&self, vp: &Vnode<Self>) -> Result<VAttr, Errno>;
+
+    /// Read from the regular file `vp` at `offset` into `buf`, returning
+    /// the number of bytes read, i.e. `vop_read`. It is called until the
+    /// request is complete or it returns 0 at the end of the file.
+    fn read(
+        &self,
+        vp: &Vnode<Self>,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Errno>;
+
+    /// Add the entries of the directory `dir` to `entries`, starting at
+    /// the entry with the index `entries.offset()`, i.e. `vop_readdir`.
+    /// This includes `"."` and `".."`.
+    fn readdir(
+        &self,
+        dir: &Vnode<Self>,
+        entries: &mut DirEntries,
+    ) -> Result<(), Errno>;
+
+    /// `vp` is being opened with the `FREAD`/`FWRITE` flags `mode`, i.e.
+    /// `vop_open`
+    fn open(&self, _vp: &Vnode<Self>, _mode: c_int) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// A file opened with the flags `fflag` is being closed, i.e.
+    /// `vop_close`
+    fn close(&self, _vp: &Vnode<Self>, _fflag: c_int) -> Result<(), Errno> {
+        Ok(())
+    }
+
+    /// The last use of `vp` went away, i.e. `vop_inactive`
+    fn inactive(&self, _vp: &Vnode<Self>) {}
+
+    /// `vp` is being destroyed, i.e. `vop_reclaim`. Its node is dropped
+    /// afterwards.
+    fn reclaim(&self, _vp: &Vnode<Self>) {}
+}
+
+/// A mount of the filesystem `F`, i.e. `struct mount`
+pub struct Mount<F>(NonNull<mount>, PhantomData<F>);
+
+impl<F> Clone for Mount<F> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<F> Copy for Mount<F> {}
+
+unsafe impl<F: Sync> Send for Mount<F> {}
+unsafe impl<F: Sync> Sync for Mount<F> {}
+
+impl<F: Filesystem> Mount<F> {
+    unsafe fn from_ptr(mp: *mut mount) -> Mount<F> {
+        Mount(NonNull::new_unchecked(mp), PhantomData)
+    }
+
+    pub fn as_ptr(self) -> *mut mount {
+        self.0.as_ptr()
+    }
+
+    /// The value of the mount option `name`, i.e. `vfs_getopts()`
+    pub fn option(&self, name: &str) -> Option<&str> {
+        let name = cstr_ref!(name);
+        let mut error = 0;
+        unsafe {
+            let opts = (*self.as_ptr()).mnt_optnew;
+            let value = kernel_sys::vfs_getopts(
+                opts,
+                name.as_ptr() as *const c_char,
+                &mut error,
+            );
+            c_str(value)
+        }
+    }
+
+    /// The name of the filesystem type
+    pub fn fstype(&self) -> &str {
+        unsafe { c_str((*(*self.as_ptr()).mnt_vfc).vfc_name.as_ptr()) }
+            .unwrap_or("")
+    }
+
+    /// The vnode of the file `ino`, i.e. `VFS_VGET()`. This fails with
+    /// `ENXIO` while the filesystem is not mounted yet.
+    pub fn vget(
+        self,
+        ino: u64,
+        lock: LockType,
+    ) -> Result<LockedVnode<F>, Errno> {
+        let mp = self.as_ptr();
+        if unsafe { (*mp).mnt_data }.is_null() {
+            return Err(Errno::ENXIO);
+        }
+        let mut vp = ptr::null_mut();
+        unsafe {
+            vget::<F>(mp, ino, lock.flags(), &mut vp)?;
+            Ok(LockedVnode(Vnode::from_ptr(vp)))
+        }
+    }
+
+    /// The vnode of the root directory, i.e. `VFS_ROOT()`
+    pub fn root(self, lock: LockType) -> Result<LockedVnode<F>, Errno> {
+        match unsafe { state::<F>(self.as_ptr()) } {
+            Some(fs) => self.vget(fs.root(), lock),
+            None => Err(Errno::ENXIO),
+        }
+    }
+}
+
+/// The state of the filesystem mounted on `mp`
+unsafe fn state<'a, F>(mp: *mut mount) -> Option<&'a F> {
+    ((*mp).mnt_data as *const F).as_ref()
+}
+
+/// The private data of a vnode of the filesystem `F`
+struct NodeData<F: Filesystem> {
+    ino: u64,
+    vtype: VType,
+    node: F::Node,
+}
+
+/// A locked vnode of the filesystem `F`, i.e. `struct vnode`
+pub struct Vnode<F>(NonNull<vnode>, PhantomData<F>);
+
+impl<F: Filesystem> Vnode<F> {
+    unsafe fn from_ptr(vp: *mut vnode) -> Vnode<F> {
+        Vnode(NonNull::new_unchecked(vp), PhantomData)
+    }
+
+    pub fn as_ptr(&self) -> *mut vnode {
+        self.0.as_ptr()
+    }
+
+    fn data(&self) -> &NodeData<F> {
+        unsafe { &*((*self.as_ptr()).v_data as *const NodeData<F>) }
+    }
+
+    /// The inode number of the file
+    pub fn ino(&self) -> u64 {
+        self.data().ino
+    }
+
+    pub fn vtype(&self) -> VType {
+        self.data().vtype
+    }
+
+    /// The node created by `Filesystem::vget`
+    pub fn node(&self) -> &F::Node {
+        &self.data().node
+    }
+
+    pub fn mount(&self) -> Mount<F> {
+        unsafe { Mount::from_ptr((*self.as_ptr()).v_mount) }
+    }
+
+    /// Whether this is the root directory of the filesystem
+    pub fn is_root(&self) -> bool {
+        let flags = unsafe { (*self.as_ptr()).v_vflag };
+        flags & kernel_sys::VV_ROOT as u32 != 0
+    }
+
+    /// Take another reference to the vnode, i.e. `vref()`
+    pub fn vref(&self) -> VnodeRef<F> {
+        unsafe {
+            kernel_sys::vref(self.as_ptr());
+            VnodeRef(Vnode::from_ptr(self.as_ptr()))
+        }
+    }
+
+    fn state(&self) -> &F {
+        unsafe { &*((*(*self.as_ptr()).v_mount).mnt_data as *const F) }
+    }
+}
+
+/// A reference to an unlocked vnode, released with `vrele()` when
+/// dropped. The vnode can only be used once it is locked.
+pub struct VnodeRef<F: Filesystem>(Vnode<F>);
+
+unsafe impl<F: Filesystem> Send for VnodeRef<F> {}
+unsafe impl<F: Filesystem> Sync for VnodeRef<F> {}
+
+impl<F: Filesystem> VnodeRef<F> {
+    pub fn as_ptr(&self) -> *mut vnode {
+        self.0.as_ptr()
+    }
+
+    /// Lock the vnode, i.e. `vn_lock()`. This fails with `ENOENT` if the
+    /// vnode was reclaimed, e.g. by a forced unmount.
+    pub fn lock(self, lock: LockType) -> Result<LockedVnode<F>, Errno> {
+        Errno::result(unsafe { vn_lock(self.as_ptr(), lock.flags()) })?;
+        let vp = unsafe { Vnode::from_ptr(self.as_ptr()) };
+        mem::forget(self);
+        Ok(LockedVnode(vp))
+    }
+}
+
+impl<F: Filesystem> Drop for VnodeRef<F> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::vrele(self.as_ptr()) };
+    }
+}
+
+/// A reference to a locked vnode, which is unlocked and released with
+/// `vput()` when dropped
+pub struct LockedVnode<F: Filesystem>(Vnode<F>);
+
+impl<F: Filesystem> LockedVnode<F> {
+    /// Unlock the vnode, keeping the reference, i.e. `VOP_UNLOCK()`
+    pub fn unlock(self) -> VnodeRef<F> {
+        let vp = self.as_ptr();
+        mem::forget(self);
+        unsafe {
+            let mut args = kernel_sys::vop_unlock_args {
+                a_gen: kernel_sys::vop_generic_args {
+                    a_desc: ptr::addr_of_mut!(kernel_sys::vop_unlock_desc),
+                },
+                a_vp: vp,
+            };
+            kernel_sys::VOP_UNLOCK_APV((*vp).v_op, &mut args);
+            VnodeRef(Vnode::from_ptr(vp))
+        }
+    }
+}
+
+impl<F: Filesystem> Deref for LockedVnode<F> {
+    type Target = Vnode<F>;
+
+    fn deref(&self) -> &Vnode<F> {
+        &self.0
+    }
+}
+
+impl<F: Filesystem> Drop for LockedVnode<F> {
+    fn drop(&mut self) {
+        unsafe { kernel_sys::vput(self.as_ptr()) };
+    }
+}
+
+/// The entries of a directory being read, which are written to the
+/// `uio` of `vop_readdir` as `struct dirent`. Offsets in directories are
+/// indices of entries.
+pub struct DirEntries {
+    uio: NonNull<uio>,
+    index: u64,
+    full: bool,
+    error: Option<Errno>,
+}
+
+impl DirEntries {
+    /// The index of the next entry to add
+    pub fn offset(&self) -> u64 {
+        self.index
+    }
+
+    /// Add the entry `name` for the file `ino` of type `vtype`. This
+    /// returns `false` once there is no space left for more entries.
+    /// Entries with names longer than `MAXNAMLEN` are skipped.
+    pub fn push(&mut self, ino: u64, vtype: VType, name: &str) -> bool {
+        if self.full || self.error.is_some() {
+            return false;
+        }
+        let mut dp: dirent = unsafe { mem::zeroed() };
+        if name.len() >= dp.d_name.len() {
+            self.index += 1;
+            return true;
+        }
+        // GENERIC_DIRSIZ(), the size of the entry rounded up to 8 bytes
+        let base = &dp as *const dirent as usize;
+        let name_offset = dp.d_name.as_ptr() as usize - base;
+        let reclen = (name_offset + name.len() + 1 + 7) & !7;
+        if reclen > unsafe { self.uio.as_ref().uio_resid } as usize {
+            self.full = true;
+            return false;
+        }
+        dp.d_fileno = ino;
+        dp.d_off = (self.index + 1) as i64;
+        dp.d_reclen = reclen as u16;
+        dp.d_type = vtype.dirent_type();
+        dp.d_namlen = name.len() as u16;
+        for (dst, &src) in dp.d_name.iter_mut().zip(name.as_bytes()) {
+            *dst = src as c_char;
+        }
+        let ret = unsafe {
+            kernel_sys::uiomove(
+                &mut dp as *mut dirent as *mut c_void,
+                reclen as c_int,
+                self.uio.as_ptr(),
+            )
+        };
+        match Errno::result(ret) {
+            Ok(()) => {
+                self.index += 1;
+                true
+            }
+            Err(e) => {
+                self.error = Some(e);
+                false
+            }
+        }
+    }
+}
+
+/// `vn_lock()`
+unsafe fn vn_lock(vp: *mut vnode, flags: c_int) -> c_int {
+    let file = concat!(file!(), "\0").as_ptr() as *const c_char;
+    kernel_sys::_vn_lock(vp, flags, file, line!() as _)
+}
+
+/// The operations of a filesystem type, which are filled in when its
+/// module is loaded. This is declared by `vfs_set!`.
+#[doc(hidden)]
+#[repr(C)]
+pub struct FsType {
+    // First, so that the vfsconf of a mount leads back here
+    conf: UnsafeCell<MaybeUninit<vfsconf>>,
+    ops: UnsafeCell<MaybeUninit<vfsops>>,
+    vops: UnsafeCell<MaybeUninit<vop_vector>>,
+    name: &'static str,
+    flags: c_int,
+}
+
+unsafe impl Sync for FsType {}
+
+impl FsType {
+    pub const fn new(name: &'static str, flags: c_int) -> FsType {
+        FsType {
+            conf: UnsafeCell::new(MaybeUninit::uninit()),
+            ops: UnsafeCell::new(MaybeUninit::uninit()),
+            vops: UnsafeCell::new(MaybeUninit::uninit()),
+            name,
+            flags,
+        }
+    }
+
+    /// The `FsType` containing `vfc`
+    unsafe fn from_conf<'a>(vfc: *mut vfsconf) -> &'a FsType {
+        &*(vfc as *const FsType)
+    }
+
+    fn vops(&self) -> *mut vop_vector {
+        self.vops.get() as *mut vop_vector
+    }
+
+    /// Fill in the operations of `F`, and register the vnode operations,
+    /// i.e. `VFS_VOP_VECTOR_REGISTER()`
+    unsafe fn init<F: Filesystem>(&self) {
+        let mut ops: vfsops = mem::zeroed();
+        ops.vfs_mount = Some(vfs_mount::<F>);
+        ops.vfs_unmount = Some(vfs_unmount::<F>);
+        ops.vfs_root = Some(vfs_root::<F>);
+        ops.vfs_statfs = Some(vfs_statfs::<F>);
+        ops.vfs_sync = Some(vfs_sync::<F>);
+        ops.vfs_vget = Some(vfs_vget::<F>);
+        (*self.ops.get()).write(ops);
+
+        let mut vops: vop_vector = mem::zeroed();
+        vops.vop_default = ptr::addr_of_mut!(kernel_sys::default_vnodeops);
+        vops.vop_lookup = Some(vop_lookup::<F>);
+        vops.vop_access = Some(vop_access::<F>);
+        vops.vop_getattr = Some(vop_getattr::<F>);
+        vops.vop_read = Some(vop_read::<F>);
+        vops.vop_readdir = Some(vop_readdir::<F>);
+        vops.vop_open = Some(vop_open::<F>);
+        vops.vop_close = Some(vop_close::<F>);
+        vops.vop_inactive = Some(vop_inactive::<F>);
+        vops.vop_reclaim = Some(vop_reclaim::<F>);
+        (*self.vops.get()).write(vops);
+        kernel_sys::vfs_vector_op_register(self.vops());
+
+        let mut conf: vfsconf = mem::zeroed();
+        conf.vfc_version = kernel_sys::VFS_VERSION as u32;
+        let len = cmp::min(self.name.len(), conf.vfc_name.len() - 1);
+        for (dst, &src) in
+            conf.vfc_name.iter_mut().zip(&self.name.as_bytes()[..len])
+        {
+            *dst = src as c_char;
+        }
+        conf.vfc_vfsops = self.ops.get() as *mut vfsops;
+        conf.vfc_typenum = -1;
+        conf.vfc_flags = self.flags;
+        (*self.conf.get()).write(conf);
+    }
+}
+
+/// The event handler of the module declaring a filesystem type, which
+/// fills in the operations of `F` before passing the event on to
+/// `vfs_modevent()`
+///
+/// # Safety
+/// `arg` must point to an `FsType` that is only used for `F`.
+pub unsafe extern "C" fn vfs_module_handler<F: Filesystem>(
+    module: Module,
+    event: c_int,
+    arg: *mut c_void,
+) -> c_int {
+    let fstype = &*(arg as *const FsType);
+    if event as u32 == kernel_sys::modeventtype_MOD_LOAD {
+        fstype.init::<F>();
+    }
+    kernel_sys::vfs_modevent(module, event, fstype.conf.get() as *mut c_void)
+}
+
+unsafe extern "C" fn vfs_mount<F: Filesystem>(mp: *mut mount) -> c_int {
+    if (*mp).mnt_flag & kernel_sys::MNT_UPDATE as u64 != 0 {
+        return Errno::EOPNOTSUPP.raw();
+    }
+    let mount = Mount::<F>::from_ptr(mp);
+    let fs = match F::mount(mount) {
+        Ok(fs) => fs,
+        Err(e) => return e.raw(),
+    };
+    (*mp).mnt_data = Box::into_raw(Box::new(fs)) as *mut c_void;
+    mtx_lock(&mut (*mp).mnt_mtx);
+    (*mp).mnt_flag |= (kernel_sys::MNT_LOCAL | kernel_sys::MNT_RDONLY) as u64;
+    mtx_unlock(&mut (*mp).mnt_mtx);
+    kernel_sys::vfs_getnewfsid(mp);
+    let from =
+        cstr_ref!(mount.option("from").unwrap_or_else(|| mount.fstype()));
+    kernel_sys::vfs_mountedfrom(mp, from.as_ptr() as *const c_char);
+    0
+}
+
+unsafe extern "C" fn vfs_unmount<F: Filesystem>(
+    mp: *mut mount,
+    mntflags: c_int,
+) -> c_int {
+    let force = mntflags & kernel_sys::MNT_FORCE as c_int != 0;
+    let fs = (*mp).mnt_data as *mut F;
+    if let Err(e) = (*fs).unmount(Mount::from_ptr(mp), force) {
+        return e.raw();
+    }
+    let flags = if force {
+        kernel_sys::FORCECLOSE as c_int
+    } else {
+        0
+    };
+    let ret = kernel_sys::vflush(mp, 0, flags, curthread());
+    if ret != 0 {
+        return ret;
+    }
+    drop(Box::from_raw(fs));
+    (*mp).mnt_data = ptr::null_mut();
+    mtx_lock(&mut (*mp).mnt_mtx);
+    (*mp).mnt_flag &= !(kernel_sys::MNT_LOCAL as u64);
+    mtx_unlock(&mut (*mp).mnt_mtx);
+    0
+}
+
+unsafe extern "C" fn vfs_root<F: Filesystem>(
+    mp: *mut mount,
+    flags: c_int,
+    vpp: *mut *mut vnode,
+) -> c_int {
+    let fs = &*((*mp).mnt_data as *const F);
+    Errno::to_ret(vget::<F>(mp, fs.root(), flags, vpp))
+}
+
+unsafe extern "C" fn vfs_statfs<F: Filesystem>(
+    mp: *mut mount,
+    sbp: *mut kernel_sys::statfs,
+) -> c_int {
+    let fs = &*((*mp).mnt_data as *const F);
+    match fs.statfs(Mount::from_ptr(mp)) {
+        Ok(st) => {
+            (*sbp).f_bsize = st.bsize;
+            (*sbp).f_iosize = st.iosize;
+            (*sbp).f_blocks = st.blocks;
+            (*sbp).f_bfree = st.bfree;
+            (*sbp).f_bavail = st.bavail;
+            (*sbp).f_files = st.files;
+            (*sbp).f_ffree = st.ffree;
+            0
+        }
+        Err(e) => e.raw(),
+    }
+}
+
+unsafe extern "C" fn vfs_sync<F: Filesystem>(
+    mp: *mut mount,
+    waitfor: c_int,
+) -> c_int {
+    let fs = &*((*mp).mnt_data as *const F);
+    let wait = waitfor == kernel_sys::MNT_WAIT as c_int;
+    Errno::to_ret(fs.sync(Mount::from_ptr(mp), wait))
+}
+
+unsafe extern "C" fn vfs_vget<F: Filesystem>(
+    mp: *mut mount,
+    ino: kernel_sys::ino_t,
+    flags: c_int,
+    vpp: *mut *mut vnode,
+) -> c_int {
+    Errno::to_ret(vget::<F>(mp, ino, flags, vpp))
+}
+
+/// Find the vnode of the file `ino` in the vnode hash, or create it
+unsafe fn vget<F: Filesystem>(
+    mp: *mut mount,
+    ino: u64,
+    flags: c_int,
+    vpp: *mut *mut vnode,
+) -> Result<(), Errno> {
+    let td = curthread();
+    let mut key = ino;
+    let arg = &mut key as *mut u64 as *mut c_void;
+    let cmp: Option<unsafe extern "C" fn(*mut vnode, *mut c_void) -> c_int> =
+        Some(vfs_hash_cmp::<F>);
+    Errno::result(kernel_sys::vfs_hash_get(
+        mp, ino as u32, flags, td, vpp, cmp, arg,
+    ))?;
+    if !(*vpp).is_null() {
+        return Ok(());
+    }
+
+    let fs = &*((*mp).mnt_data as *const F);
+    let (vtype, node) = fs.vget(Mount::from_ptr(mp), ino)?;
+    let fstype = FsType::from_conf((*mp).mnt_vfc);
+    let mut vp = ptr::null_mut();
+    Errno::result(kernel_sys::getnewvnode(
+        (*(*mp).mnt_vfc).vfc_name.as_ptr(),
+        mp,
+        fstype.vops(),
+        &mut vp,
+    ))?;
+    let data = Box::into_raw(Box::new(NodeData::<F> { ino, vtype, node }));
+    (*vp).v_data = data as *mut c_void;
+    (*vp).v_type = vtype.raw();
+    if ino == fs.root() {
+        (*vp).v_vflag |= kernel_sys::VV_ROOT as u32;
+    }
+    // A new vnode cannot be doomed, so this cannot fail
+    vn_lock(
+        vp,
+        (kernel_sys::LK_EXCLUSIVE | kernel_sys::LK_RETRY) as c_int,
+    );
+    // On failure the vnode is destroyed by insmntque_dtr
+    Errno::result(kernel_sys::insmntque1(
+        vp,
+        mp,
+        Some(insmntque_dtr::<F>),
+        data as *mut c_void,
+    ))?;
+    Errno::result(kernel_sys::vfs_hash_insert(
+        vp, ino as u32, flags, td, vpp, cmp, arg,
+    ))?;
+    // Unless another thread created a vnode for the file first
+    if (*vpp).is_null() {
+        *vpp = vp;
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn vfs_hash_cmp<F: Filesystem>(
+    vp: *mut vnode,
+    arg: *mut c_void,
+) -> c_int {
+    let data = &*((*vp).v_data as *const NodeData<F>);
+    (data.ino != *(arg as *const u64)) as c_int
+}
+
+unsafe extern "C" fn insmntque_dtr<F: Filesystem>(
+    vp: *mut vnode,
+    arg: *mut c_void,
+) {
+    drop(Box::from_raw(arg as *mut NodeData<F>));
+    kernel_sys::insmntque_stddtr(vp, ptr::null_mut());
+}
+
+/// Check that `accmode` is allowed on `vp` for `cred`, i.e. `vaccess()`
+/// with the attributes from `getattr`
+unsafe fn access<F: Filesystem>(
+    vp: &Vnode<F>,
+    accmode: kernel_sys::accmode_t,
+    cred: *mut kernel_sys::ucred,
+) -> Result<(), Errno> {
+    let vtype = vp.vtype();
+    if accmode & kernel_sys::VWRITE as kernel_sys::accmode_t != 0
+        && matches!(vtype, VType::Regular | VType::Directory | VType::Link)
+    {
+        return Err(Errno::EROFS);
+    }
+    let attr = vp.state().getattr(vp)?;
+    Errno::result(kernel_sys::vaccess(
+        vtype.raw(),
+        attr.mode,
+        attr.uid,
+        attr.gid,
+        accmode,
+        cred,
+    ))
+}
+
+unsafe extern "C" fn vop_access<F: Filesystem>(
+    ap: *mut kernel_sys::vop_access_args,
+) -> c_int {
+    let vp = Vnode::<F>::from_ptr((*ap).a_vp);
+    Errno::to_ret(access(&vp, (*ap).a_accmode, (*ap).a_cred))
+}
+
+unsafe extern "C" fn vop_lookup<F: Filesystem>(
+    ap: *mut kernel_sys::vop_lookup_args,
+) -> c_int {
+    *(*ap).a_vpp = ptr::null_mut();
+    Errno::to_ret(lookup::<F>((*ap).a_dvp, (*ap).a_vpp, (*ap).a_cnp))
+}
+
+unsafe fn lookup<F: Filesystem>(
+    dvp: *mut vnode,
+    vpp: *mut *mut vnode,
+    cnp: *mut componentname,
+) -> Result<(), Errno> {
+    let dir = Vnode::<F>::from_ptr(dvp);
+    if dir.vtype() != VType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    access(&dir, kernel_sys::VEXEC as _, (*cnp).cn_cred)?;
+    let flags = (*cnp).cn_flags;
+    if flags & kernel_sys::ISLASTCN as u64 != 0
+        && (*cnp).cn_nameiop != kernel_sys::LOOKUP as _
+    {
+        return Err(Errno::EROFS);
+    }
+
+    let name = slice::from_raw_parts(
+        (*cnp).cn_nameptr as *const u8,
+        (*cnp).cn_namelen as usize,
+    );
+    if name == b"." {
+        kernel_sys::vref(dvp);
+        *vpp = dvp;
+        return Ok(());
+    }
+    let name = str::from_utf8(name).map_err(|_| Errno::ENOENT)?;
+    let ino = dir.state().lookup(&dir, name)?;
+    if ino == dir.ino() {
+        // E.g. ".." of the root, which is locked already
+        kernel_sys::vref(dvp);
+        *vpp = dvp;
+        return Ok(());
+    }
+    let lkflags = (*cnp).cn_lkflags;
+    if flags & kernel_sys::ISDOTDOT as u64 != 0 {
+        // This unlocks the directory while locking its parent
+        Errno::result(kernel_sys::vn_vget_ino(dvp, ino, lkflags, vpp))
+    } else {
+        vget::<F>((*dvp).v_mount, ino, lkflags, vpp)
+    }
+}
+
+/// `struct timespec` of a time since the epoch
+fn timespec(time: Duration) -> kernel_sys::timespec {
+    kernel_sys::timespec {
+        tv_sec: time.as_secs() as _,
+        tv_nsec: time.subsec_nanos() as _,
+    }
+}
+
+unsafe extern "C" fn vop_getattr<F: Filesystem>(
+    ap: *mut kernel_sys::vop_getattr_args,
+) -> c_int {
+    let vp = Vnode::<F>::from_ptr((*ap).a_vp);
+    let attr = match vp.state().getattr(&vp) {
+        Ok(attr) => attr,
+        Err(e) => return e.raw(),
+    };
+    let vap: *mut vattr = (*ap).a_vap;
+    ptr::write_bytes(vap, 0, 1);
+    (*vap).va_type = vp.vtype().raw();
+    (*vap).va_mode = attr.mode;
+    (*vap).va_nlink = attr.nlink;
+    (*vap).va_uid = attr.uid;
+    (*vap).va_gid = attr.gid;
+    let fsid = (*vp.mount().as_ptr()).mnt_stat.f_fsid.val[0];
+    (*vap).va_fsid = fsid as u32 as u64;
+    (*vap).va_fileid = vp.ino();
+    (*vap).va_size = attr.size;
+    (*vap).va_bytes = attr.size;
+    (*vap).va_blocksize = kernel_sys::PAGE_SIZE as _;
+    (*vap).va_atime = timespec(attr.atime);
+    (*vap).va_mtime = timespec(attr.mtime);
+    (*vap).va_ctime = timespec(attr.ctime);
+    (*vap).va_birthtime = timespec(attr.ctime);
+    // NODEV
+    (*vap).va_rdev = u64::MAX;
+    0
+}
+
+unsafe extern "C" fn vop_read<F: Filesystem>(
+    ap: *mut kernel_sys::vop_read_args,
+) -> c_int {
+    let vp = Vnode::<F>::from_ptr((*ap).a_vp);
+    Errno::to_ret(read(&vp, (*ap).a_uio))
+}
+
+unsafe fn read<F: Filesystem>(
+    vp: &Vnode<F>,
+    uio: *mut uio,
+) -> Result<(), Errno> {
+    match vp.vtype() {
+        VType::Regular => {}
+        VType::Directory => return Err(Errno::EISDIR),
+        _ => return Err(Errno::EOPNOTSUPP),
+    }
+    if (*uio).uio_offset < 0 {
+        return Err(Errno::EINVAL);
+    }
+    let resid = (*uio).uio_resid as usize;
+    let len = cmp::min(resid, kernel_sys::MAXBSIZE as usize);
+    let mut buf = Vec::new();
+    buf.try_reserve_exact(len).map_err(|_| Errno::ENOMEM)?;
+    buf.resize(len, 0);
+    // uiomove() advances the offset and the residual count
+    loop {
+        let len = cmp::min((*uio).uio_resid as usize, buf.len());
+        if len == 0 {
+            break;
+        }
+        let offset = (*uio).uio_offset as u64;
+        let n = vp.state().read(vp, offset, &mut buf[..len])?;
+        if n == 0 {
+            break;
+        }
+        Errno::result(kernel_sys::uiomove(
+            buf.as_mut_ptr() as *mut c_void,
+            cmp::min(n, len) as c_int,
+            uio,
+        ))?;
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn vop_readdir<F: Filesystem>(
+    ap: *mut kernel_sys::vop_readdir_args,
+) -> c_int {
+    let vp = Vnode::<F>::from_ptr((*ap).a_vp);
+    // The cookies are only used by the NFS server
+    if !(*ap).a_ncookies.is_null() {
+        return Errno::EOPNOTSUPP.raw();
+    }
+    match readdir(&vp, (*ap).a_uio) {
+        Ok(eof) => {
+            if !(*ap).a_eofflag.is_null() {
+                *(*ap).a_eofflag = eof as c_int;
+            }
+            0
+        }
+        Err(e) => e.raw(),
+    }
+}
+
+/// Read the entries of `vp` into `uio`, returning whether all were read
+unsafe fn readdir<F: Filesystem>(
+    vp: &Vnode<F>,
+    uio: *mut uio,
+) -> Result<bool, Errno> {
+    if vp.vtype() != VType::Directory {
+        return Err(Errno::ENOTDIR);
+    }
+    if (*uio).uio_offset < 0 {
+        return Err(Errno::EINVAL);
+    }
+    let start = (*uio).uio_offset as u64;
+    let mut entries = DirEntries {
+        uio: NonNull::new_unchecked(uio),
+        index: start,
+        full: false,
+        error: None,
+    };
+    let ret = vp.state().readdir(vp, &mut entries);
+    // uiomove() counts the offset in bytes
+    (*uio).uio_offset = entries.index as i64;
+    ret?;
+    if let Some(e) = entries.error {
+        return Err(e);
+    }
+    if entries.full && entries.index == start {
+        // Not even one entry fits
+        return Err(Errno::EINVAL);
+    }
+    Ok(!entries.full)
+}
+
+unsafe extern "C" fn vop_open<F: Filesystem>(
+    ap: *mut kernel_sys::vop_open_args,
+) -> c_int {
+    let vp = Vnode::<F>::from_ptr((*ap).a_vp);
+    Errno::to_ret(vp.state().open(&vp, (*ap).a_mode))
+}
+
+unsafe extern "C" fn vop_close<F: Filesystem>(
+    ap: *mut kernel_sys::vop_close_args,
+) -> c_int {
+    let vp = Vnode::<F>::from_ptr((*ap).a_vp);
+    Errno::to_ret(vp.state().close(&vp, (*ap).a_fflag))
+}
+
+unsafe extern "C" fn vop_inactive<F: Filesystem>(
+    ap: *mut kernel_sys::vop_inactive_args,
+) -> c_int {
+    let vp = Vnode::<F>::from_ptr((*ap).a_vp);
+    vp.state().inactive(&vp);
+    0
+}
+
+unsafe extern "C" fn vop_reclaim<F: Filesystem>(
+    ap: *mut kernel_sys::vop_reclaim_args,
+) -> c_int {
+    let vp = (*ap).a_vp;
+    let vnode = Vnode::<F>::from_ptr(vp);
+    vnode.state().reclaim(&vnode);
+    kernel_sys::vfs_hash_remove(vp);
+    drop(Box::from_raw((*vp).v_data as *mut NodeData<F>));
+    (*vp).v_data = ptr::null_mut();
+    0
+}
+
+/// Register `$fs`, a `Filesystem`, as the filesystem type `$name`, i.e.
+/// `VFS_SET()`. `$flags` are `VFCF_*` flags. This also declares a module
+/// named `$name`, which cannot be unloaded while the type is mounted.
+///
+/// ```rust,ignore
+/// bsd_kernel::vfs_set!(hellofs, HelloFs, VFCF_SYNTHETIC);
+/// bsd_kernel::module_version!(hellofs, 1);
+/// ```
+#[macro_export]
+macro_rules! vfs_set {
+    ($name:ident, $fs:ty) => {
+        $crate::vfs_set!($name, $fs, 0);
+    };
+    ($name:ident, $fs:ty, $flags:expr) => {
+        const _: () = {
+            static FSTYPE: $crate::vfs::FsType =
+                $crate::vfs::FsType::new(stringify!($name), $flags);
+
+            static MODULE_DATA: $crate::linker_set::Static<
+                $crate::kernel_sys::moduledata_t,
+            > = $crate::linker_set::Static($crate::kernel_sys::moduledata_t {
+                name: $crate::cstr!(stringify!($name)).as_ptr()
+                    as *const $crate::libc::c_char,
+                evhand: Some($crate::vfs::vfs_module_handler::<$fs>),
+                priv_: &FSTYPE as *const $crate::vfs::FsType
+                    as *mut $crate::libc::c_void,
+            });
+
+            $crate::__declare_module!(
+                stringify!($name),
+                MODULE_DATA,
+                $crate::sysinit::Subsystem::Vfs,
+                $crate::sysinit::Order::Middle
+            );
+        };
+    };
+}
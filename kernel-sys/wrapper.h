@@ -35,3 +35,42 @@
 #include <sys/unistd.h>
 #include <sys/lock.h>
 #include <sys/mutex.h>
+#include <sys/syslog.h>  /* log(9) priorities */
+#include <sys/eventhandler.h>
+#include <sys/proc.h>
+#include <sys/ucred.h>
+#include <sys/sysctl.h>
+#include <sys/sysent.h>  /* SYSCALL_MODULE */
+#include <sys/bus.h>     /* newbus */
+#include <sys/rman.h>
+#include <machine/bus.h>
+#include <machine/resource.h>
+#include <sys/bus_dma.h>
+#include <x86/busdma_impl.h>  /* inline bus_dma functions */
+#include <sys/kobj.h>
+#include <sys/mbuf.h>
+#include <net/if.h>
+#include <net/if_var.h>
+#include <net/if_types.h>
+#include <net/ethernet.h>
+#include <net/bpf.h>
+#include <sys/epoch.h>
+#include <net/pfil.h>
+#include <sys/socket.h>
+#include <sys/socketvar.h>
+#include <sys/un.h>
+#include <netinet/in.h>
+#include <sys/domain.h>
+#include <sys/protosw.h>
+#include <sys/sockbuf.h>
+#include <netgraph/ng_message.h>
+#include <netgraph/netgraph.h>
+#include <netgraph/ng_parse.h>
+#include <sys/sx.h>
+#include <sys/bio.h>
+#include <geom/geom.h>
+#include <geom/geom_disk.h>
+#include <sys/mount.h>
+#include <sys/vnode.h>  /* includes the generated vnode_if.h */
+#include <sys/namei.h>
+#include <sys/dirent.h>
@@ -39,10 +39,7 @@
 //! ```
 
 use bsd_kernel::allocator::KernelAllocator;
-use bsd_kernel::module::{ModuleEventType, ModuleEvents};
-use bsd_kernel::{debugln, println};
-use core::panic::PanicInfo;
-use libc::{c_int, c_void};
+use bsd_kernel::logger::KernelLogger;
 use module::MODULE;
 
 mod module;
@@ -52,58 +49,7 @@ extern crate alloc;
 #[global_allocator]
 static ALLOCATOR: KernelAllocator = KernelAllocator;
 
-#[panic_handler]
-fn panic_handler(info: &PanicInfo) -> ! {
-    if let Some(s) = info.payload().downcast_ref::<&str>() {
-        println!("Panic occurred: {}", s);
-    } else {
-        println!("Panic occurred");
-    }
+static LOGGER: KernelLogger = KernelLogger::new("hello");
 
-    if let Some(loc) = info.location() {
-        println!("Panic at line `{}` of file `{}`", loc.line(), loc.file());
-    }
-
-    loop {}
-}
-
-/// Main event handler for module events
-#[no_mangle]
-pub extern "C" fn module_event(
-    _module: bsd_kernel::Module,
-    event: c_int,
-    _arg: *mut c_void,
-) -> c_int {
-    // debugln!("[interface.rs] Got event {}", event);
-
-    if let Some(ev) = ModuleEventType::from_i32(event) {
-        use ModuleEventType::*;
-        match ev {
-            Load => {
-                // debugln!("[interface.rs] MOD_LOAD");
-
-                if let Some(mut m) = MODULE.lock() {
-                    m.load();
-                }
-            }
-            Unload => {
-                // debugln!("[interface.rs] MOD_UNLOAD");
-
-                if let Some(mut m) = MODULE.lock() {
-                    m.unload();
-                }
-
-                MODULE.cleanup();
-            }
-            Quiesce => {
-                // debugln!("[interface.rs] MOD_QUIESCE");
-            }
-            Shutdown => {
-                // debugln!("[interface.rs] MOD_SHUTDOWN");
-            }
-        }
-    } else {
-        debugln!("[interface.rs] Undefined event");
-    }
-    0
-}
+bsd_kernel::declare_module!(hello, MODULE);
+bsd_kernel::module_version!(hello, 1);
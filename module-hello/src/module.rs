@@ -23,12 +23,15 @@
 //
 // Based on public domain code by Johannes Lundberg
 
+use crate::LOGGER;
 use alloc::boxed::Box;
 use alloc::string::{String, ToString};
 use bsd_kernel::character_device::{CDev, CharacterDevice};
-use bsd_kernel::debugln;
+use bsd_kernel::error::Errno;
 use bsd_kernel::io::{Read, Write};
+use bsd_kernel::log::{debug, error, info, LevelFilter};
 use bsd_kernel::module::{ModuleEvents, SharedModule};
+use bsd_kernel::sysctl::{SysctlContext, SysctlOid};
 use bsd_kernel::uio::{UioReader, UioWriter};
 use lazy_static::lazy_static;
 
@@ -42,6 +45,7 @@ lazy_static! {
 pub struct HelloInner {
     data: String,
     _cdev: Box<CDev<Hello>>,
+    _sysctl: SysctlContext,
 }
 
 #[derive(Default, Debug)]
@@ -51,53 +55,97 @@ pub struct Hello {
     // callback. (we can't for example clone MODULE while in
     // Hello::new() because of order of initialisation)
     inner: Option<HelloInner>,
+    // Whether /dev/rustmodule is open, d_close is only called on the
+    // last close so this does not need to be a count
+    open: bool,
 }
 impl Hello {
     fn new() -> Self {
         // We can't access MODULE here because it is not initialised yet!
-        Hello { inner: None }
+        Hello {
+            inner: None,
+            open: false,
+        }
     }
 }
 
 impl ModuleEvents for Hello {
-    fn load(&mut self) {
-        debugln!("[module.rs] Hello::load");
+    fn load(&mut self) -> Result<(), Errno> {
+        let level = if cfg!(debug_assertions) {
+            LevelFilter::Debug
+        } else {
+            LevelFilter::Info
+        };
+        let _ = LOGGER.init(level);
+
+        debug!("[module.rs] Hello::load");
 
         // MODULE has been fully initialised here
         // so we can clone it safely
         let m = MODULE.clone();
 
-        if let Some(cdev) = CDev::new_with_delegate("rustmodule", m) {
-            self.inner = Some(HelloInner {
-                data: "Default hello message\n".to_string(),
-                _cdev: cdev,
-            });
-        } else {
-            debugln!(
-                "[module.rs] Hello::load: Failed to create character device"
-            );
+        // debug.hello.log_level
+        let mut sysctl = SysctlContext::new();
+        if let Some(node) =
+            sysctl.add_node(SysctlOid::debug(), "hello", "Rust hello module")
+        {
+            LOGGER.add_level_sysctl(&mut sysctl, node);
+        }
+
+        // Returning early drops `sysctl`, removing the node again
+        let cdev = match CDev::new_with_delegate("rustmodule", m) {
+            Some(cdev) => cdev,
+            None => {
+                error!(
+                    "[module.rs] Hello::load: Failed to create character device"
+                );
+                return Err(Errno::ENXIO);
+            }
+        };
+
+        self.inner = Some(HelloInner {
+            data: "Default hello message\n".to_string(),
+            _cdev: cdev,
+            _sysctl: sysctl,
+        });
+        Ok(())
+    }
+
+    fn unload(&mut self) -> Result<(), Errno> {
+        debug!("[module.rs] Hello::unload");
+        Ok(())
+    }
+
+    fn quiesce(&mut self) -> Result<(), Errno> {
+        if self.open {
+            info!("/dev/rustmodule is open, refusing to unload");
+            return Err(Errno::EBUSY);
         }
+        Ok(())
     }
 
-    fn unload(&mut self) {
-        debugln!("[module.rs] Hello::unload");
+    fn shutdown(&mut self) -> Result<(), Errno> {
+        debug!("[module.rs] Hello::shutdown");
+        Ok(())
     }
 }
 
 impl CharacterDevice for Hello {
     fn open(&mut self) {
         // debugln!("[module.rs] Hello::open");
+        self.open = true;
     }
     fn close(&mut self) {
         // debugln!("[module.rs] Hello::close");
+        self.open = false;
     }
     fn read(&mut self, uio: &mut UioWriter) {
         // debugln!("[module.rs] Hello::read");
 
         if let Some(ref h) = self.inner {
-            match uio.write_all(&h.data.as_bytes()) {
+            match uio.write_all(h.data.as_bytes()) {
                 Ok(()) => (),
-                Err(e) => debugln!("{}", e),
+                Err(e) => error!("{}", e),
             }
         }
     }
@@ -107,13 +155,12 @@ impl CharacterDevice for Hello {
             inner.data.clear();
             match uio.read_to_string(&mut inner.data) {
                 Ok(x) => {
-                    debugln!(
+                    debug!(
                         "Read {} bytes. Setting new message to `{}`",
-                        x,
-                        inner.data
+                        x, inner.data
                     )
                 }
-                Err(e) => debugln!("{:?}", e),
+                Err(e) => error!("{:?}", e),
             }
         }
     }
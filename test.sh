@@ -0,0 +1,13 @@
+#!/usr/bin/env sh
+
+# Run the unit tests of bsd-kernel on the host. .cargo/config.toml builds
+# for the kernel target with build-std, which cannot be turned off from the
+# command line, so cargo is run from outside the repository where it does
+# not pick up that file.
+
+CURDIR=`pwd`
+TOOLCHAIN=`sed -n 's/^channel = "\(.*\)"/\1/p' "${CURDIR}/rust-toolchain.toml"`
+
+cd / && \
+	cargo "+${TOOLCHAIN}" test \
+		--manifest-path "${CURDIR}/bsd-kernel/Cargo.toml" "$@"